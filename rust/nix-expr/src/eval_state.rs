@@ -10,8 +10,12 @@ use nix_store::store::{Store, StoreWeak};
 use nix_util::context::Context;
 use nix_util::string_return::{callback_get_result_string, callback_get_result_string_data};
 use nix_util::{check_call, check_call_opt_key, result_string_init};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::{c_char, CString};
+use std::fmt::Write as _;
 use std::os::raw::c_uint;
+use std::path::{Path, PathBuf};
 use std::ptr::{null, null_mut, NonNull};
 use std::sync::{Arc, Weak};
 
@@ -79,6 +83,112 @@ impl Drop for EvalStateRef {
     }
 }
 
+/// Configures and creates an [EvalState].
+pub struct EvalStateBuilder {
+    store: Store,
+    lookup_path: Vec<String>,
+    inherit_nix_path: bool,
+    pure: bool,
+}
+impl EvalStateBuilder {
+    pub fn new(store: Store) -> Self {
+        EvalStateBuilder {
+            store,
+            lookup_path: Vec::new(),
+            inherit_nix_path: false,
+            pure: false,
+        }
+    }
+
+    /// Add lookup path entries, such as `nixpkgs=/path/to/nixpkgs` or `/path/to/dir`.
+    pub fn lookup_path<'a>(mut self, entries: impl IntoIterator<Item = &'a str>) -> Self {
+        self.lookup_path
+            .extend(entries.into_iter().map(|s| s.to_string()));
+        self
+    }
+
+    /// Whether to append the entries from the `NIX_PATH` environment variable, like the Nix CLI does.
+    ///
+    /// Explicit [`lookup_path`][`EvalStateBuilder::lookup_path`] entries come first, so they take precedence.
+    pub fn inherit_nix_path(mut self, enabled: bool) -> Self {
+        self.inherit_nix_path = enabled;
+        self
+    }
+
+    /// Whether to forbid impure evaluation, for evaluating expressions that are not trusted to be reproducible.
+    ///
+    /// This enables Nix's `pure-eval` and `restrict-eval` settings and disables `allow-import-from-derivation`.
+    /// Impure builtins such as `builtins.currentTime` are then not available, `builtins.getEnv` returns an empty string, and only store paths and fetched sources can be read.
+    /// `builtins.getFlake` requires a locked flake reference.
+    ///
+    /// These are global Nix settings, so they also apply to `EvalState`s that are built afterwards, and disabling this does not reset them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use nix_expr::eval_state::EvalStateBuilder;
+    /// use nix_store::store::Store;
+    ///
+    /// # fn main() -> anyhow::Result<()> {
+    /// let mut es = EvalStateBuilder::new(Store::open("auto", [])?)
+    ///     .pure(true)
+    ///     .build()?;
+    /// let v = es.eval_from_string(r#"builtins.getEnv "HOME""#, ".")?;
+    /// assert_eq!(es.require_string(&v)?, "");
+    /// assert!(es.eval_from_string("builtins.currentTime", ".").is_err());
+    /// let v = es.eval_from_string("1 + 1", ".")?;
+    /// assert_eq!(es.require_int(&v)?, 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pure(mut self, enabled: bool) -> Self {
+        self.pure = enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<EvalState> {
+        let nix_path = std::env::var("NIX_PATH").ok();
+        self.build_with_nix_path(nix_path.as_deref())
+    }
+
+    /// Like [`build`][`EvalStateBuilder::build`], with `nix_path` in place of the `NIX_PATH` environment variable.
+    fn build_with_nix_path(self, nix_path: Option<&str>) -> Result<EvalState> {
+        let mut lookup_path = self.lookup_path;
+        if self.inherit_nix_path {
+            if let Some(nix_path) = nix_path {
+                lookup_path.extend(parse_nix_path(nix_path));
+            }
+        }
+        if self.pure {
+            // Nix decides which builtins exist when the EvalState is created,
+            // so these must be set first.
+            init()?;
+            nix_util::settings::set("pure-eval", "true")?;
+            nix_util::settings::set("restrict-eval", "true")?;
+            nix_util::settings::set("allow-import-from-derivation", "false")?;
+        }
+        EvalState::new(self.store, lookup_path.iter().map(|s| s.as_str()))
+    }
+}
+
+/// Split a `NIX_PATH` value into lookup path entries.
+///
+/// Entries are separated by `:`, except where the `:` is part of a URL, as in `nixpkgs=https://example.com/nixpkgs.tar.gz`.
+fn parse_nix_path(nix_path: &str) -> Vec<String> {
+    let mut entries: Vec<String> = Vec::new();
+    for part in nix_path.split(':') {
+        match entries.last_mut() {
+            Some(last) if part.starts_with("//") => {
+                last.push(':');
+                last.push_str(part);
+            }
+            _ => entries.push(part.to_string()),
+        }
+    }
+    entries.retain(|e| !e.is_empty());
+    entries
+}
+
 pub struct EvalState {
     eval_state: Arc<EvalStateRef>,
     store: Store,
@@ -178,6 +288,45 @@ impl EvalState {
             Ok(value)
         }
     }
+
+    /// Parses and evaluates a Nix expression `expr`, and forces the result to weak head normal form.
+    ///
+    /// Like [`eval_from_string`][`EvalState::eval_from_string`], but the returned value is guaranteed not to be a thunk, so that [`value_type_unforced`][`EvalState::value_type_unforced`] returns `Some`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use nix_expr::eval_state::EvalState;
+    /// use nix_store::store::Store;
+    /// use nix_expr::value::{Value, ValueType};
+    ///
+    /// # fn main() -> anyhow::Result<()> {
+    /// # let mut es = EvalState::new(Store::open("auto", [])?, [])?;
+    /// let v: Value = es.eval_from_string_strict("{ a = 1; }", ".")?;
+    /// assert_eq!(es.value_type_unforced(&v), Some(ValueType::AttrSet));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn eval_from_string_strict(&mut self, expr: &str, path: &str) -> Result<Value> {
+        let value = self.eval_from_string(expr, path)?;
+        self.force(&value)?;
+        Ok(value)
+    }
+
+    /// Evaluates the Nix file at `path`, as `import path` would.
+    ///
+    /// Unlike reading the file and passing it to [`eval_from_string`][`EvalState::eval_from_string`], errors and traces refer to the file.
+    pub fn eval_file(&mut self, path: &Path) -> Result<Value> {
+        let path = std::fs::canonicalize(path)
+            .with_context(|| format!("eval_file: could not resolve path {}", path.display()))?;
+        let path_str = path
+            .to_str()
+            .ok_or_else(|| anyhow::format_err!("eval_file: path is not valid UTF-8: {:?}", path))?;
+        let import = self.eval_from_string("path: import (/. + path)", "<nix-expr eval_file>")?;
+        let path_value = self.new_value_str(path_str)?;
+        self.call(import, path_value)
+    }
+
     /// Try turn any Value into a Value that isn't a Thunk.
     pub fn force(&mut self, v: &Value) -> Result<()> {
         unsafe {
@@ -211,10 +360,76 @@ impl EvalState {
     pub fn require_int(&mut self, v: &Value) -> Result<Int> {
         let t = self.value_type(v)?;
         if t != ValueType::Int {
-            bail!("expected an int, but got a {:?}", t);
+            return Err(self.unexpected_type_error("an int", v, t));
         }
         unsafe { check_call!(raw::get_int(&mut self.context, v.raw_ptr())) }
     }
+    /// Like [`require_int`][`EvalState::require_int`], but fails if the integer is not in the inclusive range `min..=max`.
+    ///
+    /// Use this rather than casting, to avoid silently truncating e.g. a port number to `u16`.
+    pub fn require_int_in_range(&mut self, v: &Value, min: Int, max: Int) -> Result<Int> {
+        let i = self.require_int(v)?;
+        if i < min || i > max {
+            bail!("integer {} out of range [{}, {}]", i, min, max);
+        }
+        Ok(i)
+    }
+    pub fn require_bool(&mut self, v: &Value) -> Result<bool> {
+        let t = self.value_type(v)?;
+        if t != ValueType::Bool {
+            return Err(self.unexpected_type_error("a bool", v, t));
+        }
+        unsafe { check_call!(raw::get_bool(&mut self.context, v.raw_ptr())) }
+    }
+    pub fn require_float(&mut self, v: &Value) -> Result<f64> {
+        let t = self.value_type(v)?;
+        if t != ValueType::Float {
+            return Err(self.unexpected_type_error("a float", v, t));
+        }
+        unsafe { check_call!(raw::get_float(&mut self.context, v.raw_ptr())) }
+    }
+
+    /// Evaluate, and require that the value is a list.
+    /// Returns the number of elements, without evaluating them.
+    pub fn require_list_size(&mut self, v: &Value) -> Result<usize> {
+        let t = self.value_type(v)?;
+        if t != ValueType::List {
+            return Err(self.unexpected_type_error("a list", v, t));
+        }
+        let n = unsafe { check_call!(raw::get_list_size(&mut self.context, v.raw_ptr())) }?;
+        Ok(n as usize)
+    }
+
+    /// Evaluate, and require that the value is a list.
+    /// Returns the elements, each of which is evaluated to weak head normal form.
+    pub fn require_list_strict(&mut self, v: &Value) -> Result<Vec<Value>> {
+        let n = self.require_list_size(v)?;
+        let mut elems = Vec::with_capacity(n);
+        for i in 0..n {
+            let elem = unsafe {
+                check_call!(raw::get_list_byidx(
+                    &mut self.context,
+                    v.raw_ptr(),
+                    self.eval_state.as_ptr(),
+                    i as c_uint
+                ))
+            }?;
+            let elem = unsafe { Value::new(elem) };
+            self.force(&elem)?;
+            elems.push(elem);
+        }
+        Ok(elems)
+    }
+
+    /// Evaluate, and require that the value is an attrset.
+    /// Returns the number of attributes, without evaluating them.
+    pub fn require_attrs_size(&mut self, v: &Value) -> Result<u32> {
+        let t = self.value_type(v)?;
+        if t != ValueType::AttrSet {
+            return Err(self.unexpected_type_error("an attrset", v, t));
+        }
+        unsafe { check_call!(raw::get_attrs_size(&mut self.context, v.raw_ptr())) }
+    }
 
     /// Evaluate, and require that the value is an attrset.
     /// Returns a list of the keys in the attrset.
@@ -232,7 +447,7 @@ impl EvalState {
     pub fn require_attrs_names_unsorted(&mut self, v: &Value) -> Result<Vec<String>> {
         let t = self.value_type(v)?;
         if t != ValueType::AttrSet {
-            bail!("expected an attrset, but got a {:?}", t);
+            return Err(self.unexpected_type_error("an attrset", v, t));
         }
         let n = unsafe { check_call!(raw::get_attrs_size(&mut self.context, v.raw_ptr())) }?;
         let mut attrs = Vec::with_capacity(n as usize);
@@ -254,23 +469,76 @@ impl EvalState {
         Ok(attrs)
     }
 
+    /// Evaluate, and require that the value is an attrset.
+    /// Returns the attributes in the order in which Nix stores them, which is not necessarily sorted by name.
+    ///
+    /// The attribute values are not evaluated. The C API's `get_attr_byidx` forces the value it returns, so each value is returned as a thunk that selects the attribute instead.
+    pub fn require_attrs_entries(&mut self, v: &Value) -> Result<Vec<(String, Value)>> {
+        let names = self.require_attrs_names_unsorted(v)?;
+        let select = self.eval_from_string("attrs: name: attrs.${name}", "<nix-expr>")?;
+        let select = self.new_value_apply(&select, v)?;
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            let name_value = self.new_value_str(&name)?;
+            let value = self.new_value_apply(&select, &name_value)?;
+            entries.push((name, value));
+        }
+        Ok(entries)
+    }
+
     /// Evaluate, require that the value is an attrset, and select an attribute by name.
+    ///
+    /// If the attribute is missing, the error lists the available attributes, and suggests a similarly named one, if any.
     pub fn require_attrs_select(&mut self, v: &Value, attr_name: &str) -> Result<Value> {
-        let t = self.value_type(v)?;
-        if t != ValueType::AttrSet {
-            bail!("expected an attrset, but got a {:?}", t);
+        match self.require_attrs_select_opt(v, attr_name)? {
+            Some(v2) => Ok(v2),
+            None => {
+                let names = self.require_attrs_names(v)?;
+                Err(missing_attribute_error(attr_name, &names))
+            }
         }
-        let attr_name = CString::new(attr_name)
-            .with_context(|| "require_attrs_select: attrName contains null byte")?;
-        unsafe {
-            let v2 = check_call!(raw::get_attr_byname(
-                &mut self.context,
-                v.raw_ptr(),
-                self.eval_state.as_ptr(),
-                attr_name.as_ptr()
-            ))?;
-            Ok(Value::new(v2))
+    }
+
+    /// Select a nested attribute, such as `a.b.c` for `path` `["a", "b", "c"]`,
+    /// requiring that `v` and the intermediate values are attrsets.
+    ///
+    /// The error says where the path broke off. A missing attribute is
+    /// reported as a [`MissingAttributeError`] for the missing path element.
+    pub fn require_attrs_select_path(&mut self, v: &Value, path: &[&str]) -> Result<Value> {
+        let mut current = v.clone();
+        for (i, attr_name) in path.iter().enumerate() {
+            let t = self.value_type(&current)?;
+            if t != ValueType::AttrSet {
+                let shown = self.value_to_error_string(&current, t);
+                if i == 0 {
+                    bail!(
+                        "cannot select attribute `{}`: expected an attrset, but got {}",
+                        path.join("."),
+                        shown
+                    );
+                }
+                bail!(
+                    "cannot select attribute `{}`: `{}` is {}, not an attrset",
+                    path.join("."),
+                    path[..i].join("."),
+                    shown
+                );
+            }
+            current = match self.require_attrs_select_opt(&current, attr_name)? {
+                Some(next) => next,
+                None => {
+                    return Err(anyhow::Error::new(MissingAttributeError {
+                        attr_name: attr_name.to_string(),
+                        message: format!(
+                            "attribute `{}` not found (missing at `{}`)",
+                            path.join("."),
+                            attr_name
+                        ),
+                    }))
+                }
+            };
         }
+        Ok(current)
     }
 
     /// Evaluate, require that the value is an attrset, and select an attribute by name.
@@ -287,7 +555,7 @@ impl EvalState {
     ) -> Result<Option<Value>> {
         let t = self.value_type(v)?;
         if t != ValueType::AttrSet {
-            bail!("expected an attrset, but got a {:?}", t);
+            return Err(self.unexpected_type_error("an attrset", v, t));
         }
         let attr_name = CString::new(attr_name)
             .with_context(|| "require_attrs_select_opt: attrName contains null byte")?;
@@ -302,6 +570,22 @@ impl EvalState {
         Ok(v2.map(|x| unsafe { Value::new(x) }))
     }
 
+    /// Evaluate, require that the value is an attrset, and return its type tag: the string in attribute `attr`, conventionally `_type`.
+    ///
+    /// Fails if the tag is missing or is not a string.
+    pub fn require_type_tag(&mut self, v: &Value, attr: &str) -> Result<String> {
+        let tag = match self.require_attrs_select_opt(v, attr)? {
+            Some(tag) => tag,
+            None => bail!("value is not tagged with `{}`", attr),
+        };
+        let t = self.value_type(&tag)?;
+        if t != ValueType::String {
+            let shown = self.value_to_error_string(&tag, t);
+            bail!("expected `{}` to be a string, but got {}", attr, shown);
+        }
+        self.get_string(&tag)
+    }
+
     /// Create a new value containing the passed string.
     /// Returns a string value without any string context.
     pub fn new_value_str(&mut self, s: &str) -> Result<Value> {
@@ -327,6 +611,53 @@ impl EvalState {
         Ok(v)
     }
 
+    pub fn new_value_bool(&mut self, b: bool) -> Result<Value> {
+        let v = unsafe {
+            let value = self.new_value_uninitialized()?;
+            check_call!(raw::init_bool(&mut self.context, value.raw_ptr(), b))?;
+            value
+        };
+        Ok(v)
+    }
+
+    /// Create a new float value. Like Nix, this does not reject NaN or infinities.
+    pub fn new_value_float(&mut self, f: f64) -> Result<Value> {
+        let v = unsafe {
+            let value = self.new_value_uninitialized()?;
+            check_call!(raw::init_float(&mut self.context, value.raw_ptr(), f))?;
+            value
+        };
+        Ok(v)
+    }
+
+    /// Create a new path value.
+    ///
+    /// A relative path is interpreted relative to the evaluator's base directory, which is the current working directory, like a relative path literal passed on the command line.
+    pub fn new_value_path(&mut self, path: &Path) -> Result<Value> {
+        let path = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            std::env::current_dir()
+                .context("new_value_path: could not determine the current directory")?
+                .join(path)
+        };
+        let s = path.to_str().ok_or_else(|| {
+            anyhow::format_err!("new_value_path: path is not valid UTF-8: {:?}", path)
+        })?;
+        let s = CString::new(s).with_context(|| "new_value_path: contains null byte")?;
+        let v = unsafe {
+            let value = self.new_value_uninitialized()?;
+            check_call!(raw::init_path_string(
+                &mut self.context,
+                self.eval_state.as_ptr(),
+                value.raw_ptr(),
+                s.as_ptr()
+            ))?;
+            value
+        };
+        Ok(v)
+    }
+
     /// Create a new thunk that will evaluate to the result of the given function.
     /// The function will be called with the current EvalState.
     /// The function must not return a thunk.
@@ -357,6 +688,136 @@ impl EvalState {
         self.new_value_apply(&p, &p)
     }
 
+    /// Render a value for use in messages, much like Nix prints values in its errors.
+    ///
+    /// Attrsets and lists nested more than `max_depth` levels deep are abbreviated to `{ ... }` and `[ ... ]`.
+    /// Strings are truncated to `max_len` characters, and at most `max_len` attributes or list elements are shown.
+    ///
+    /// Nothing is evaluated, except the `type` attribute of an attrset, to show derivations as `«derivation»`.
+    /// Unevaluated values are shown as `«thunk»`. The C API only returns attribute values and list elements after evaluating them, so these are always shown as `«thunk»`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use nix_expr::eval_state::EvalState;
+    /// use nix_store::store::Store;
+    ///
+    /// # fn main() -> anyhow::Result<()> {
+    /// # let mut es = EvalState::new(Store::open("auto", [])?, [])?;
+    /// let v = es.eval_from_string(r#"{ a = [ 1 "two" ]; b.c = null; }"#, ".")?;
+    /// assert_eq!(
+    ///     es.value_to_display_string(&v, 1, 10)?,
+    ///     "{ a = «thunk»; b = «thunk»; }"
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn value_to_display_string(
+        &mut self,
+        v: &Value,
+        max_depth: usize,
+        max_len: usize,
+    ) -> Result<String> {
+        let mut out = String::new();
+        self.write_value(&mut out, v, max_depth, max_len)?;
+        Ok(out)
+    }
+
+    fn write_value(
+        &mut self,
+        out: &mut String,
+        v: &Value,
+        max_depth: usize,
+        max_len: usize,
+    ) -> Result<()> {
+        let Some(t) = self.value_type_unforced(v) else {
+            out.push_str("«thunk»");
+            return Ok(());
+        };
+        match t {
+            ValueType::Int => write!(out, "{}", self.require_int(v)?)?,
+            ValueType::Bool => write!(out, "{}", self.require_bool(v)?)?,
+            ValueType::Float => write!(out, "{}", self.require_float(v)?)?,
+            ValueType::Null => out.push_str("null"),
+            ValueType::String => {
+                let s = self.get_string(v)?;
+                write_nix_string(out, &s, max_len);
+            }
+            ValueType::Path => write!(out, "{}", self.require_path(v)?.display())?,
+            ValueType::Function => out.push_str("«lambda»"),
+            ValueType::External => out.push_str("«external»"),
+            ValueType::Unknown => out.push_str("«unknown»"),
+            ValueType::List => {
+                let n = self.require_list_size(v)?;
+                if n == 0 {
+                    out.push_str("[ ]");
+                } else if max_depth == 0 {
+                    out.push_str("[ ... ]");
+                } else {
+                    out.push('[');
+                    // The C API forces the elements it returns
+                    for _ in 0..std::cmp::min(n, max_len) {
+                        out.push_str(" «thunk»");
+                    }
+                    if n > max_len {
+                        write_elided(out, n - max_len, "item");
+                    }
+                    out.push_str(" ]");
+                }
+            }
+            ValueType::AttrSet => {
+                let names = self.require_attrs_names(v)?;
+                if names.is_empty() {
+                    out.push_str("{ }");
+                } else if self.is_derivation(v) {
+                    out.push_str("«derivation»");
+                } else if max_depth == 0 {
+                    out.push_str("{ ... }");
+                } else {
+                    out.push('{');
+                    // The C API forces the attribute values it returns
+                    for name in names.iter().take(max_len) {
+                        out.push(' ');
+                        write_nix_attr_name(out, name);
+                        out.push_str(" = «thunk»;");
+                    }
+                    if names.len() > max_len {
+                        write_elided(out, names.len() - max_len, "attribute");
+                    }
+                    out.push_str(" }");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether an attrset is a derivation, i.e. has `type = "derivation"`, like Nix checks it.
+    ///
+    /// Only the `type` attribute is evaluated.
+    fn is_derivation(&mut self, v: &Value) -> bool {
+        match self.require_attrs_select_opt(v, "type") {
+            Ok(Some(t)) => {
+                self.value_type_unforced(&t) == Some(ValueType::String)
+                    && self.get_string(&t).is_ok_and(|s| s == "derivation")
+            }
+            _ => false,
+        }
+    }
+
+    /// Render a value of type `t` for an error message, falling back to its type if it can not be rendered.
+    fn value_to_error_string(&mut self, v: &Value, t: ValueType) -> String {
+        match self.value_to_display_string(v, ERROR_PRINT_MAX_DEPTH, ERROR_PRINT_MAX_LEN) {
+            Ok(s) => format!("`{}`", s),
+            Err(_) => format!("a {:?}", t),
+        }
+    }
+
+    /// The error for when `expected` was required, but `v` has type `t`.
+    fn unexpected_type_error(&mut self, expected: &str, v: &Value, t: ValueType) -> anyhow::Error {
+        let shown = self.value_to_error_string(v, t);
+        anyhow::format_err!("expected {}, but got {}", expected, shown)
+    }
+
     /// Not exposed, because the caller must always explicitly handle the context or not accept one at all.
     fn get_string(&mut self, value: &Value) -> Result<String> {
         let mut r = result_string_init!();
@@ -374,10 +835,24 @@ impl EvalState {
     pub fn require_string(&mut self, value: &Value) -> Result<String> {
         let t = self.value_type(value)?;
         if t != ValueType::String {
-            bail!("expected a string, but got a {:?}", t);
+            return Err(self.unexpected_type_error("a string", value, t));
         }
         self.get_string(value)
     }
+    /// Evaluate, and require that the value is a path.
+    pub fn require_path(&mut self, value: &Value) -> Result<PathBuf> {
+        let t = self.value_type(value)?;
+        if t != ValueType::Path {
+            return Err(self.unexpected_type_error("a path", value, t));
+        }
+        let cstr_ptr =
+            unsafe { check_call!(raw::get_path_string(&mut self.context, value.raw_ptr())) }?;
+        let cstr = unsafe { std::ffi::CStr::from_ptr(cstr_ptr) };
+        let s = cstr
+            .to_str()
+            .map_err(|e| anyhow::format_err!("Nix path is not valid UTF-8: {}", e))?;
+        Ok(PathBuf::from(s))
+    }
     pub fn realise_string(
         &mut self,
         value: &Value,
@@ -385,9 +860,11 @@ impl EvalState {
     ) -> Result<RealisedString> {
         let t = self.value_type(value)?;
         if t != ValueType::String {
-            bail!("expected a string, but got a {:?}", t);
+            return Err(self.unexpected_type_error("a string", value, t));
         }
 
+        // Nix does not report the individual builds, but we can at least show that we're waiting for them.
+        let span = tracing::info_span!("realising store paths");
         let rs = unsafe {
             check_call!(raw::string_realise(
                 &mut self.context,
@@ -395,7 +872,9 @@ impl EvalState {
                 value.raw_ptr(),
                 is_import_from_derivation
             ))
-        }?;
+        };
+        drop(span);
+        let rs = rs?;
 
         let s = unsafe {
             let start = raw::realised_string_get_buffer_start(rs) as *const u8;
@@ -428,6 +907,80 @@ impl EvalState {
         Ok(RealisedString { s, paths })
     }
 
+    /// Deeply evaluate a value and convert it to JSON, as `builtins.toJSON` does.
+    ///
+    /// String context is discarded; no store paths are realised.
+    pub fn eval_to_json(&mut self, v: &Value) -> Result<serde_json::Value> {
+        let to_json = self.eval_from_string("builtins.toJSON", "<nix-expr>")?;
+        let json_str = self.call(to_json, v.clone())?;
+        let json_str = self.get_string(&json_str)?;
+        serde_json::from_str(&json_str).with_context(|| "builtins.toJSON produced invalid JSON")
+    }
+
+    /// Deserialize a Nix value, typically an attrset, into a Rust type.
+    ///
+    /// The value is converted with [`eval_to_json`][`EvalState::eval_to_json`] first.
+    /// Errors name the path of the field that could not be deserialized.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use nix_expr::eval_state::EvalState;
+    /// use nix_store::store::Store;
+    ///
+    /// #[derive(serde::Deserialize)]
+    /// struct Config {
+    ///     name: String,
+    ///     port: u16,
+    /// }
+    ///
+    /// # fn main() -> anyhow::Result<()> {
+    /// # let mut es = EvalState::new(Store::open("auto", [])?, [])?;
+    /// let v = es.eval_from_string(r#"{ name = "web"; port = 80; }"#, ".")?;
+    /// let config: Config = es.extract_config(&v)?;
+    /// assert_eq!(config.name, "web");
+    /// assert_eq!(config.port, 80);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extract_config<T: DeserializeOwned>(&mut self, v: &Value) -> Result<T> {
+        let json = self.eval_to_json(v)?;
+        serde_path_to_error::deserialize(json).map_err(|e| {
+            let path = e.path().to_string();
+            if path == "." {
+                anyhow::format_err!("{}", e.inner())
+            } else {
+                anyhow::format_err!("in attribute `{}`: {}", path, e.inner())
+            }
+        })
+    }
+
+    /// Evaluate, and return whether the value is a function.
+    pub fn is_function(&mut self, v: &Value) -> Result<bool> {
+        Ok(self.value_type(v)? == ValueType::Function)
+    }
+
+    /// Evaluate, require that the value is a function, and return the named arguments it takes, as `builtins.functionArgs` does.
+    ///
+    /// Each argument name maps to whether it has a default value.
+    /// The map is empty when the function takes a single positional argument, as in `x: x`, or when it is a primop.
+    /// Nix does not distinguish those from `{ ... }: x`.
+    pub fn require_function_args(&mut self, v: &Value) -> Result<BTreeMap<String, bool>> {
+        let t = self.value_type(v)?;
+        if t != ValueType::Function {
+            return Err(self.unexpected_type_error("a function", v, t));
+        }
+        let function_args = self.eval_from_string("builtins.functionArgs", "<nix-expr>")?;
+        let args = self.call(function_args, v.clone())?;
+        let mut r = BTreeMap::new();
+        for name in self.require_attrs_names_unsorted(&args)? {
+            let has_default = self.require_attrs_select(&args, &name)?;
+            let has_default = self.require_bool(&has_default)?;
+            r.insert(name, has_default);
+        }
+        Ok(r)
+    }
+
     /// Eagerly apply a function to an argument.
     ///
     /// For a lazy version, see [`new_value_apply`][`EvalState::new_value_apply`].
@@ -535,20 +1088,189 @@ impl EvalState {
         }
         Ok(value)
     }
-}
 
-struct BindingsBuilder {
-    ptr: *mut raw::BindingsBuilder,
-}
-impl Drop for BindingsBuilder {
-    fn drop(&mut self) {
+    /// Create a new list value from the given elements, without evaluating them.
+    pub fn new_value_list<I>(&mut self, items: I) -> Result<Value>
+    where
+        I: IntoIterator<Item = Value>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let iter = items.into_iter();
+        let size = iter.len();
+        let list_builder = ListBuilder::new(self, size)?;
+        for (i, value) in iter.enumerate() {
+            unsafe {
+                check_call!(raw::list_builder_insert(
+                    &mut self.context,
+                    list_builder.ptr,
+                    i as c_uint,
+                    value.raw_ptr()
+                ))?;
+            }
+        }
+        let value = self.new_value_uninitialized()?;
         unsafe {
-            raw::bindings_builder_free(self.ptr);
+            check_call!(raw::make_list(
+                &mut self.context,
+                list_builder.ptr,
+                value.raw_ptr()
+            ))?;
+        }
+        Ok(value)
+    }
+
+    /// Like [`new_value_attrs`][`EvalState::new_value_attrs`], but returns an error if an attribute name occurs more than once.
+    ///
+    /// [`new_value_attrs`][`EvalState::new_value_attrs`] does not check for duplicates, and leaves it to Nix to pick one of the values.
+    pub fn new_value_attrs_checked<I>(&mut self, attrs: I) -> Result<Value>
+    where
+        I: IntoIterator<Item = (String, Value)>,
+    {
+        let attrs: Vec<(String, Value)> = attrs.into_iter().collect();
+        let mut seen = HashSet::with_capacity(attrs.len());
+        for (name, _) in attrs.iter() {
+            if !seen.insert(name.as_str()) {
+                bail!(
+                    "new_value_attrs_checked: duplicate attribute name `{}`",
+                    name
+                );
+            }
         }
+        self.new_value_attrs(attrs)
     }
 }
-impl BindingsBuilder {
-    fn new(eval_state: &mut EvalState, capacity: usize) -> Result<Self> {
+
+/// How deeply nested a value may be shown in an error message.
+const ERROR_PRINT_MAX_DEPTH: usize = 2;
+/// The maximum string length, and number of attributes or list elements, to show in an error message.
+const ERROR_PRINT_MAX_LEN: usize = 20;
+
+/// Write `s` as a Nix string literal, truncated to `max_len` characters.
+fn write_nix_string(out: &mut String, s: &str, max_len: usize) {
+    out.push('"');
+    let mut chars = s.chars().take(max_len).peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    let total = s.chars().count();
+    if total > max_len {
+        write_elided(out, total - max_len, "character");
+    }
+}
+
+fn write_elided(out: &mut String, n: usize, noun: &str) {
+    let plural = if n == 1 { "" } else { "s" };
+    out.push_str(&format!(" «{} {}{} elided»", n, noun, plural));
+}
+
+/// Write an attribute name, quoting it if it is not a valid identifier.
+fn write_nix_attr_name(out: &mut String, name: &str) {
+    let mut chars = name.chars();
+    let is_identifier = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '\'' || c == '-');
+    if is_identifier {
+        out.push_str(name);
+    } else {
+        write_nix_string(out, name, usize::MAX);
+    }
+}
+
+/// The maximum number of attribute names to list in a missing attribute error.
+const MISSING_ATTRIBUTE_MAX_NAMES: usize = 20;
+
+/// The error returned by [`require_attrs_select`][`EvalState::require_attrs_select`] when the attribute does not exist.
+///
+/// Callers can distinguish it from other errors with [`anyhow::Error::downcast_ref`].
+#[derive(Debug)]
+pub struct MissingAttributeError {
+    pub attr_name: String,
+    message: String,
+}
+impl std::fmt::Display for MissingAttributeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl std::error::Error for MissingAttributeError {}
+
+fn missing_attribute_error(attr_name: &str, names: &[String]) -> anyhow::Error {
+    let mut msg = format!("missing attribute `{}`", attr_name);
+    if let Some(suggestion) = suggest_similar(attr_name, names) {
+        msg.push_str(&format!(" (did you mean `{}`?)", suggestion));
+    }
+    if names.is_empty() {
+        msg.push_str("; the attribute set is empty");
+    } else {
+        let listed = names
+            .iter()
+            .take(MISSING_ATTRIBUTE_MAX_NAMES)
+            .map(|n| n.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        msg.push_str(&format!("; available attributes: {}", listed));
+        if names.len() > MISSING_ATTRIBUTE_MAX_NAMES {
+            msg.push_str(&format!(
+                ", ... ({} more)",
+                names.len() - MISSING_ATTRIBUTE_MAX_NAMES
+            ));
+        }
+    }
+    anyhow::Error::new(MissingAttributeError {
+        attr_name: attr_name.to_string(),
+        message: msg,
+    })
+}
+
+/// Find the name that is closest to `name`, if it is close enough to be a likely typo.
+fn suggest_similar<'a>(name: &str, candidates: &'a [String]) -> Option<&'a str> {
+    let max_distance = std::cmp::max(1, name.chars().count() / 3);
+    candidates
+        .iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(d, _)| *d <= max_distance)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c.as_str())
+}
+
+/// Levenshtein distance, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+struct BindingsBuilder {
+    ptr: *mut raw::BindingsBuilder,
+}
+impl Drop for BindingsBuilder {
+    fn drop(&mut self) {
+        unsafe {
+            raw::bindings_builder_free(self.ptr);
+        }
+    }
+}
+impl BindingsBuilder {
+    fn new(eval_state: &mut EvalState, capacity: usize) -> Result<Self> {
         let ptr = unsafe {
             check_call!(raw::make_bindings_builder(
                 &mut eval_state.context,
@@ -560,6 +1282,29 @@ impl BindingsBuilder {
     }
 }
 
+struct ListBuilder {
+    ptr: *mut raw::ListBuilder,
+}
+impl Drop for ListBuilder {
+    fn drop(&mut self) {
+        unsafe {
+            raw::list_builder_free(self.ptr);
+        }
+    }
+}
+impl ListBuilder {
+    fn new(eval_state: &mut EvalState, capacity: usize) -> Result<Self> {
+        let ptr = unsafe {
+            check_call!(raw::make_list_builder(
+                &mut eval_state.context,
+                eval_state.eval_state.as_ptr(),
+                capacity
+            ))
+        }?;
+        Ok(ListBuilder { ptr })
+    }
+}
+
 pub fn gc_now() {
     unsafe {
         raw::gc_now();
@@ -702,6 +1447,53 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn parse_nix_path_entries() {
+        assert_eq!(parse_nix_path(""), Vec::<String>::new());
+        assert_eq!(
+            parse_nix_path("nixpkgs=/a/b:/c/d::e=f"),
+            vec!["nixpkgs=/a/b", "/c/d", "e=f"]
+        );
+        assert_eq!(
+            parse_nix_path("nixpkgs=https://example.com/nixpkgs.tar.gz:/c"),
+            vec!["nixpkgs=https://example.com/nixpkgs.tar.gz", "/c"]
+        );
+    }
+
+    #[test]
+    fn eval_state_builder_inherit_nix_path() {
+        let mut explicit_file = tempfile::NamedTempFile::new().unwrap();
+        let mut env_file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(explicit_file, "1").unwrap();
+        writeln!(env_file, "2").unwrap();
+        let nix_path = format!(
+            "nixops4_test_a={}:nixops4_test_b={}",
+            env_file.path().to_str().unwrap(),
+            env_file.path().to_str().unwrap()
+        );
+        gc_registering_current_thread(|| {
+            let mut es = EvalStateBuilder::new(Store::open("auto", HashMap::new()).unwrap())
+                .lookup_path([
+                    format!("nixops4_test_a={}", explicit_file.path().to_str().unwrap()).as_str(),
+                ])
+                .inherit_nix_path(true)
+                .build_with_nix_path(Some(&nix_path))
+                .unwrap();
+            // explicit entries take precedence
+            let v = es
+                .eval_from_string("import <nixops4_test_a>", "<test>")
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 1);
+            let v = es
+                .eval_from_string("import <nixops4_test_b>", "<test>")
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 2);
+        })
+        .unwrap();
+        explicit_file.close().unwrap();
+        env_file.close().unwrap();
+    }
+
     #[test]
     fn eval_state_lookup_path() {
         let import_expression = "import <test_file0> + import <test_file1>";
@@ -749,6 +1541,200 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn eval_state_eval_from_string_strict() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string_strict("(x: x) { a = 1; }", "<test>")
+                .unwrap();
+            let t = es.value_type_unforced(&v);
+            assert!(t == Some(ValueType::AttrSet));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_strict_error() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let r = es.eval_from_string_strict("(x: x) (throw \"oh no the error\")", "<test>");
+            match r {
+                Ok(_) => panic!("expected an error"),
+                Err(e) => {
+                    if !e.to_string().contains("oh no the error") {
+                        eprintln!("unexpected error message: {}", e);
+                        assert!(false);
+                    }
+                }
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_error_info_msg() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let e = es
+                .eval_from_string_strict("(x: x) (throw \"oh no the error\")", "<test>")
+                .unwrap_err();
+            let nix_error = e
+                .chain()
+                .find_map(|e| e.downcast_ref::<nix_util::context::NixError>())
+                .expect("a NixError");
+            assert_eq!(nix_error.info_msg(), Some("oh no the error"));
+            assert!(nix_error.to_string().contains("oh no the error"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_extract_config() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Listen {
+            address: String,
+            port: u16,
+        }
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Config {
+            name: String,
+            listen: Vec<Listen>,
+            debug: Option<bool>,
+        }
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"{ name = "web"; listen = [ { address = "::"; port = 40 + 40; } ]; }"#,
+                    "<test>",
+                )
+                .unwrap();
+            let config: Config = es.extract_config(&v).unwrap();
+            assert_eq!(
+                config,
+                Config {
+                    name: "web".to_string(),
+                    listen: vec![Listen {
+                        address: "::".to_string(),
+                        port: 80,
+                    }],
+                    debug: None,
+                }
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_extract_config_field_error() {
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Listen {
+            port: u16,
+        }
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Config {
+            listen: Vec<Listen>,
+        }
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"{ listen = [ { port = 80; } { port = "http"; } ]; }"#,
+                    "<test>",
+                )
+                .unwrap();
+            let r: Result<Config> = es.extract_config(&v);
+            let e = r.unwrap_err().to_string();
+            assert!(
+                e.starts_with("in attribute `listen[1].port`: invalid type: string"),
+                "{}",
+                e
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_file() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let file = dir.path().join("example.nix");
+            std::fs::write(&file, "{ x = 1; }").unwrap();
+            let v = es.eval_file(&file).unwrap();
+            let x = es.require_attrs_select(&v, "x").unwrap();
+            assert_eq!(es.require_int(&x).unwrap(), 1);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_file_error_location() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let file = dir.path().join("broken.nix");
+            std::fs::write(&file, "{ x = ; }").unwrap();
+            let e = es.eval_file(&file).unwrap_err().to_string();
+            assert!(e.contains("broken.nix"), "unexpected error message: {}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_bool() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            for b in [true, false] {
+                let v = es.new_value_bool(b).unwrap();
+                assert_eq!(es.value_type_unforced(&v), Some(ValueType::Bool));
+                assert_eq!(es.require_bool(&v).unwrap(), b);
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_float() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            for f in [0.0, -1.5, f64::MAX, f64::MIN_POSITIVE, f64::INFINITY] {
+                let v = es.new_value_float(f).unwrap();
+                assert_eq!(es.value_type_unforced(&v), Some(ValueType::Float));
+                assert_eq!(es.require_float(&v).unwrap(), f);
+            }
+            let v = es.new_value_float(f64::NAN).unwrap();
+            assert!(es.require_float(&v).unwrap().is_nan());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_bool_float_type_error() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.new_value_int(1).unwrap();
+            let e = es.require_bool(&v).unwrap_err();
+            assert_eq!(e.to_string(), "expected a bool, but got `1`");
+            let e = es.require_float(&v).unwrap_err();
+            assert_eq!(e.to_string(), "expected a float, but got `1`");
+        })
+        .unwrap();
+    }
+
     #[test]
     fn eval_state_value_bool() {
         gc_registering_current_thread(|| {
@@ -793,6 +1779,26 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn eval_state_require_int_in_range() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = make_thunk(&mut es, "8080");
+            assert_eq!(es.require_int_in_range(&v, 0, 65535).unwrap(), 8080);
+            assert_eq!(es.require_int_in_range(&v, 8080, 8080).unwrap(), 8080);
+            let v = es.eval_from_string("-1", "<test>").unwrap();
+            let e = es.require_int_in_range(&v, 0, 65535).unwrap_err();
+            assert_eq!(e.to_string(), "integer -1 out of range [0, 65535]");
+            let v = es.eval_from_string("65536", "<test>").unwrap();
+            let e = es.require_int_in_range(&v, 0, 65535).unwrap_err();
+            assert_eq!(e.to_string(), "integer 65536 out of range [0, 65535]");
+            let v = es.eval_from_string("\"80\"", "<test>").unwrap();
+            assert!(es.require_int_in_range(&v, 0, 65535).is_err());
+        })
+        .unwrap();
+    }
+
     /// A helper that turns an expression into a thunk.
     fn make_thunk(es: &mut EvalState, expr: &str) -> Value {
         // This would be silly in real code, but it works for the current Nix implementation.
@@ -846,60 +1852,300 @@ mod tests {
     }
 
     #[test]
-    fn eval_state_require_attrs_names_unsorted_bad_type() {
+    fn eval_state_require_attrs_names_unsorted_bad_type() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("1", "<test>").unwrap();
+            es.force(&v).unwrap();
+            let r = es.require_attrs_names_unsorted(&v);
+            assert!(r.is_err());
+            assert_eq!(
+                r.unwrap_err().to_string(),
+                "expected an attrset, but got `1`"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_value_attrs_names_example() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let expr = r#"{ a = throw "nope a"; b = throw "nope b"; }"#;
+            let v = es.eval_from_string(expr, "<test>").unwrap();
+            let attrs = es.require_attrs_names(&v).unwrap();
+            assert_eq!(attrs.len(), 2);
+            assert_eq!(attrs[0], "a");
+            assert_eq!(attrs[1], "b");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let expr = r#"{ a = "aye"; b = "bee"; }"#;
+            let v = es.eval_from_string(expr, "<test>").unwrap();
+            let a = es.require_attrs_select(&v, "a").unwrap();
+            let b = es.require_attrs_select(&v, "b").unwrap();
+            assert_eq!(es.require_string(&a).unwrap(), "aye");
+            assert_eq!(es.require_string(&b).unwrap(), "bee");
+            let missing = es.require_attrs_select(&v, "c");
+            match missing {
+                Ok(_) => panic!("expected an error"),
+                Err(e) => {
+                    let s = format!("{e:#}");
+                    // TODO: bad error message from Nix
+                    if !s.contains("missing attribute") {
+                        eprintln!("unexpected error message: {}", s);
+                        assert!(false);
+                    }
+                }
+            }
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select_null_byte() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("{ a = 1; }", "<test>").unwrap();
+            let e = es.require_attrs_select_opt(&v, "a\0b").unwrap_err();
+            assert!(e.to_string().contains("contains null byte"));
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select_missing_suggestion() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let expr = r#"{ resources = 1; deploymentFunction = 2; }"#;
+            let v = es.eval_from_string(expr, "<test>").unwrap();
+            let r = es.require_attrs_select(&v, "resurces");
+            match r {
+                Ok(_) => panic!("expected an error"),
+                Err(e) => {
+                    assert_eq!(
+                        e.to_string(),
+                        "missing attribute `resurces` (did you mean `resources`?); available attributes: deploymentFunction, resources"
+                    );
+                }
+            }
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select_missing_no_suggestion() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("{ a = 1; }", "<test>").unwrap();
+            let r = es.require_attrs_select(&v, "something");
+            match r {
+                Ok(_) => panic!("expected an error"),
+                Err(e) => {
+                    assert_eq!(
+                        e.to_string(),
+                        "missing attribute `something`; available attributes: a"
+                    );
+                }
+            }
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select_path() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string(r#"{ a.b.c = "sea"; }"#, "<test>")
+                .unwrap();
+            let c = es.require_attrs_select_path(&v, &["a", "b", "c"]).unwrap();
+            assert_eq!(es.require_string(&c).unwrap(), "sea");
+            let b = es.require_attrs_select_path(&v, &["a", "b"]).unwrap();
+            assert_eq!(es.require_attrs_names(&b).unwrap(), vec!["c"]);
+            let same = es.require_attrs_select_path(&v, &[]).unwrap();
+            assert_eq!(es.require_attrs_names(&same).unwrap(), vec!["a"]);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select_path_missing() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("{ a.x.c = 1; }", "<test>").unwrap();
+            let e = es
+                .require_attrs_select_path(&v, &["a", "b", "c"])
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "attribute `a.b.c` not found (missing at `b`)"
+            );
+            assert_eq!(
+                e.downcast_ref::<MissingAttributeError>().unwrap().attr_name,
+                "b"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select_path_not_attrset() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("{ a.b = 1; }", "<test>").unwrap();
+            let e = es
+                .require_attrs_select_path(&v, &["a", "b", "c"])
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "cannot select attribute `a.b.c`: `a.b` is `1`, not an attrset"
+            );
+            let one = es.eval_from_string("1", "<test>").unwrap();
+            let e = es.require_attrs_select_path(&one, &["a"]).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "cannot select attribute `a`: expected an attrset, but got `1`"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn missing_attribute_error_truncates() {
+        let names: Vec<String> = (0..25).map(|i| format!("attr{:02}", i)).collect();
+        let e = missing_attribute_error("x", &names);
+        assert_eq!(
+            e.downcast_ref::<MissingAttributeError>().unwrap().attr_name,
+            "x"
+        );
+        let msg = e.to_string();
+        assert!(msg.contains("attr19, ... (5 more)"));
+        assert!(!msg.contains("attr20"));
+    }
+
+    #[test]
+    fn edit_distance_examples() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", "abc"), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("resurces", "resources"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn eval_state_require_function_args() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+
+            let positional = es.eval_from_string("x: x", "<test>").unwrap();
+            assert!(es.is_function(&positional).unwrap());
+            assert!(es.require_function_args(&positional).unwrap().is_empty());
+
+            let named = es.eval_from_string("{ a, b ? 1 }: a", "<test>").unwrap();
+            assert!(es.is_function(&named).unwrap());
+            let args = es.require_function_args(&named).unwrap();
+            assert_eq!(
+                args,
+                BTreeMap::from([("a".to_string(), false), ("b".to_string(), true)])
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_function_args_not_a_function() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("{ a = 1; }", "<test>").unwrap();
+            assert!(!es.is_function(&v).unwrap());
+            let e = es.require_function_args(&v).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "expected a function, but got `{ a = «thunk»; }`"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_attrs_size() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let expr = r#"{ a = throw "a"; b = throw "b"; c = throw "c"; }"#;
+            let v = make_thunk(&mut es, expr);
+            assert_eq!(es.require_attrs_size(&v).unwrap(), 3);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_attrs_size_type_error() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto", HashMap::new()).unwrap();
             let mut es = EvalState::new(store, []).unwrap();
-            let v = es.eval_from_string("1", "<test>").unwrap();
-            es.force(&v).unwrap();
-            let r = es.require_attrs_names_unsorted(&v);
-            assert!(r.is_err());
+            let v = es.eval_from_string("[ 1 2 3 ]", "<test>").unwrap();
+            let e = es.require_attrs_size(&v).unwrap_err();
             assert_eq!(
-                r.unwrap_err().to_string(),
-                "expected an attrset, but got a Int"
+                e.to_string(),
+                "expected an attrset, but got `[ «thunk» «thunk» «thunk» ]`"
             );
         })
         .unwrap()
     }
 
     #[test]
-    fn eval_state_value_attrs_names_example() {
+    fn eval_state_require_attrs_entries() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto", HashMap::new()).unwrap();
             let mut es = EvalState::new(store, []).unwrap();
-            let expr = r#"{ a = throw "nope a"; b = throw "nope b"; }"#;
-            let v = es.eval_from_string(expr, "<test>").unwrap();
-            let attrs = es.require_attrs_names(&v).unwrap();
-            assert_eq!(attrs.len(), 2);
-            assert_eq!(attrs[0], "a");
-            assert_eq!(attrs[1], "b");
+            let v = es.eval_from_string("{ a = 1; b = 2; }", "<test>").unwrap();
+            let entries = es.require_attrs_entries(&v).unwrap();
+            let mut names = entries.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(names, vec!["a", "b"]);
+            for (_, value) in entries.iter() {
+                assert!(es.value_type_unforced(value).is_none());
+            }
+            for (name, value) in entries.iter() {
+                let i = es.require_int(value).unwrap();
+                assert_eq!(i, if name == "a" { 1 } else { 2 });
+                assert_eq!(es.value_type_unforced(value), Some(ValueType::Int));
+            }
         })
-        .unwrap();
+        .unwrap()
     }
 
     #[test]
-    fn eval_state_require_attrs_select() {
+    fn eval_state_require_attrs_entries_lazy() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto", HashMap::new()).unwrap();
             let mut es = EvalState::new(store, []).unwrap();
-            let expr = r#"{ a = "aye"; b = "bee"; }"#;
-            let v = es.eval_from_string(expr, "<test>").unwrap();
-            let a = es.require_attrs_select(&v, "a").unwrap();
-            let b = es.require_attrs_select(&v, "b").unwrap();
-            assert_eq!(es.require_string(&a).unwrap(), "aye");
-            assert_eq!(es.require_string(&b).unwrap(), "bee");
-            let missing = es.require_attrs_select(&v, "c");
-            match missing {
-                Ok(_) => panic!("expected an error"),
-                Err(e) => {
-                    let s = format!("{e:#}");
-                    // TODO: bad error message from Nix
-                    if !s.contains("missing attribute") {
-                        eprintln!("unexpected error message: {}", s);
-                        assert!(false);
-                    }
-                }
-            }
+            let v = es
+                .eval_from_string(r#"{ ok = 1; bad = throw "not evaluated"; }"#, "<test>")
+                .unwrap();
+            let entries = es.require_attrs_entries(&v).unwrap();
+            assert_eq!(entries.len(), 2);
+            let (_, bad) = entries.iter().find(|(n, _)| n == "bad").unwrap();
+            let e = es.force(bad).unwrap_err();
+            assert!(e.to_string().contains("not evaluated"));
         })
         .unwrap()
     }
@@ -1028,10 +2274,9 @@ mod tests {
             es.force(&v).unwrap();
             let r = es.require_string(&v);
             assert!(r.is_err());
-            // TODO: safe print value (like Nix would)
             assert_eq!(
                 r.unwrap_err().to_string(),
-                "expected a string, but got a Bool"
+                "expected a string, but got `true`"
             );
         })
         .unwrap()
@@ -1048,7 +2293,149 @@ mod tests {
             assert!(r.is_err());
             assert_eq!(
                 r.unwrap_err().to_string(),
-                "expected a string, but got a Path"
+                "expected a string, but got `/foo`"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_type_tag() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string(r#"{ _type = "nixops4Deployment"; }"#, "<test>")
+                .unwrap();
+            assert_eq!(
+                es.require_type_tag(&v, "_type").unwrap(),
+                "nixops4Deployment"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_type_tag_missing() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("{ a = 1; }", "<test>").unwrap();
+            let e = es.require_type_tag(&v, "_type").unwrap_err();
+            assert_eq!(e.to_string(), "value is not tagged with `_type`");
+            let v = es.eval_from_string("1", "<test>").unwrap();
+            let e = es.require_type_tag(&v, "_type").unwrap_err();
+            assert_eq!(e.to_string(), "expected an attrset, but got `1`");
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_require_type_tag_not_a_string() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("{ _type = true; }", "<test>").unwrap();
+            let e = es.require_type_tag(&v, "_type").unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "expected `_type` to be a string, but got `true`"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_value_to_display_string_int() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("-42", "<test>").unwrap();
+            assert_eq!(es.value_to_display_string(&v, 3, 10).unwrap(), "-42");
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_value_to_display_string_string() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.new_value_str("say \"hi\"\n").unwrap();
+            assert_eq!(
+                es.value_to_display_string(&v, 3, 10).unwrap(),
+                r#""say \"hi\"\n""#
+            );
+            let v = es.new_value_str("hello, world").unwrap();
+            assert_eq!(
+                es.value_to_display_string(&v, 3, 5).unwrap(),
+                r#""hello" «7 characters elided»"#
+            );
+            let v = es.new_value_str("${x}").unwrap();
+            assert_eq!(es.value_to_display_string(&v, 3, 10).unwrap(), r#""\${x}""#);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_value_to_display_string_nested() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"{ a.b.c = 1; "x y" = [ true null { } ]; z = throw "nope"; }"#,
+                    "<test>",
+                )
+                .unwrap();
+            // Nested values are not evaluated, so `z` doesn't throw
+            assert_eq!(
+                es.value_to_display_string(&v, 2, 10).unwrap(),
+                r#"{ a = «thunk»; "x y" = «thunk»; z = «thunk»; }"#
+            );
+            assert_eq!(es.value_to_display_string(&v, 0, 10).unwrap(), "{ ... }");
+            assert_eq!(
+                es.value_to_display_string(&v, 1, 2).unwrap(),
+                r#"{ a = «thunk»; "x y" = «thunk»; «1 attribute elided» }"#
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_value_to_display_string_list_elided() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string("[ 1 2.5 (x: x) 4 5 ]", "<test>")
+                .unwrap();
+            assert_eq!(
+                es.value_to_display_string(&v, 1, 3).unwrap(),
+                "[ «thunk» «thunk» «thunk» «2 items elided» ]"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_value_to_display_string_unevaluated() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let f = es.eval_from_string("x: throw x", "<test>").unwrap();
+            let a = es.new_value_str("nope").unwrap();
+            let v = es.new_value_apply(&f, &a).unwrap();
+            assert_eq!(es.value_to_display_string(&v, 2, 10).unwrap(), "«thunk»");
+            let v = es
+                .eval_from_string(
+                    r#"{ type = "derivation"; name = throw "nope"; outPath = throw "nope"; }"#,
+                    "<test>",
+                )
+                .unwrap();
+            assert_eq!(
+                es.value_to_display_string(&v, 2, 10).unwrap(),
+                "«derivation»"
             );
         })
         .unwrap()
@@ -1184,6 +2571,103 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn eval_state_realised_path_is_valid() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string(r#"builtins.toFile "just-a-file" "ooh file good""#, "<test>")
+                .unwrap();
+            let rs = es.realise_string(&v, false).unwrap();
+            assert_eq!(rs.paths.len(), 1);
+            let mut store = es.store().clone();
+            assert!(store.is_valid_path(&rs.paths[0]).unwrap());
+        })
+        .unwrap();
+    }
+
+    /// Records the names of the spans that are created.
+    struct SpanRecorder {
+        names: Arc<Mutex<Vec<String>>>,
+    }
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut names = self.names.lock().unwrap();
+            names.push(span.metadata().name().to_string());
+            tracing::span::Id::from_u64(names.len() as u64)
+        }
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+        fn event(&self, _event: &tracing::Event<'_>) {}
+        fn enter(&self, _span: &tracing::span::Id) {}
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[test]
+    fn eval_state_build_reports_progress() {
+        gc_registering_current_thread(|| {
+            let names = Arc::new(Mutex::new(Vec::new()));
+            let recorder = SpanRecorder {
+                names: names.clone(),
+            };
+            tracing::subscriber::with_default(recorder, || {
+                let store = Store::open("auto", HashMap::new()).unwrap();
+                let mut es = EvalState::new(store, []).unwrap();
+                let expr = r#"
+                    builtins.unsafeDiscardOutputDependency
+                        (derivation {
+                            name = "progress";
+                            system = builtins.currentSystem;
+                            builder = "/bin/sh";
+                            args = [ "-c" "echo foo > $out" ];
+                        }).drvPath
+                "#;
+                let v = es.eval_from_string(expr, "<test>").unwrap();
+                let rs = es.realise_string(&v, false).unwrap();
+                let mut store = es.store().clone();
+                store.build(&rs.paths[0]).unwrap();
+            });
+            let names = names.lock().unwrap();
+            assert!(names.contains(&"realising store paths".to_string()));
+            assert!(names.contains(&"building".to_string()));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_store_build() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let expr = r#"
+                builtins.unsafeDiscardOutputDependency
+                    (derivation {
+                        name = "letsbuild";
+                        system = builtins.currentSystem;
+                        builder = "/bin/sh";
+                        args = [ "-c" "echo foo > $out" ];
+                    }).drvPath
+            "#;
+            let v = es.eval_from_string(expr, "<test>").unwrap();
+            let rs = es.realise_string(&v, false).unwrap();
+            assert_eq!(rs.paths.len(), 1);
+            let drv = &rs.paths[0];
+            assert_eq!(drv.name().unwrap(), "letsbuild.drv");
+
+            let mut store = es.store().clone();
+            let outputs = store.build(drv).unwrap();
+            assert_eq!(outputs.keys().collect::<Vec<_>>(), vec!["out"]);
+            let out = &outputs["out"];
+            assert_eq!(out.name().unwrap(), "letsbuild");
+            assert!(store.is_valid_path(out).unwrap());
+        })
+        .unwrap();
+    }
+
     #[test]
     fn eval_state_realise_string() {
         gc_registering_current_thread(|| {
@@ -1742,6 +3226,145 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    pub fn eval_state_new_value_attrs_checked() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let a = es.new_value_int(1).unwrap();
+            let b = es.new_value_int(2).unwrap();
+            let attrs = es
+                .new_value_attrs_checked(vec![("a".to_string(), a), ("b".to_string(), b)])
+                .unwrap();
+            let names = es.require_attrs_names(&attrs).unwrap();
+            assert_eq!(names, vec!["a".to_string(), "b".to_string()]);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    pub fn eval_state_new_value_attrs_checked_duplicate() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let a1 = es.new_value_int(1).unwrap();
+            let b = es.new_value_int(2).unwrap();
+            let a2 = es.new_value_int(3).unwrap();
+            let attrs = vec![
+                ("a".to_string(), a1),
+                ("b".to_string(), b),
+                ("a".to_string(), a2),
+            ];
+
+            let r = es.new_value_attrs_checked(attrs.clone());
+            assert_eq!(
+                r.unwrap_err().to_string(),
+                "new_value_attrs_checked: duplicate attribute name `a`"
+            );
+
+            // The unchecked version leaves it to Nix; one of the values wins
+            let v = es.new_value_attrs(attrs).unwrap();
+            let a = es.require_attrs_select(&v, "a").unwrap();
+            let i = es.require_int(&a).unwrap();
+            assert!(i == 1 || i == 3);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    pub fn eval_state_new_value_path() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .new_value_path(Path::new("/some/where/file.txt"))
+                .unwrap();
+            assert_eq!(es.value_type(&v).unwrap(), ValueType::Path);
+            assert_eq!(
+                es.require_path(&v).unwrap(),
+                PathBuf::from("/some/where/file.txt")
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    pub fn eval_state_new_value_path_relative() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.new_value_path(Path::new("file.txt")).unwrap();
+            assert_eq!(
+                es.require_path(&v).unwrap(),
+                std::env::current_dir().unwrap().join("file.txt")
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    pub fn eval_state_require_path_type_error() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.new_value_str("/not/a/path").unwrap();
+            let e = es.require_path(&v).unwrap_err();
+            assert!(e.to_string().contains("expected a path"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    pub fn eval_state_new_value_list() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let items = [1, 2, 3]
+                .into_iter()
+                .map(|i| es.new_value_int(i))
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            let list = es.new_value_list(items).unwrap();
+            assert_eq!(es.value_type(&list).unwrap(), ValueType::List);
+            assert_eq!(es.require_list_size(&list).unwrap(), 3);
+            let elems = es.require_list_strict(&list).unwrap();
+            let ints = elems
+                .iter()
+                .map(|v| es.require_int(v))
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(ints, vec![1, 2, 3]);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    pub fn eval_state_new_value_list_empty() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let list = es.new_value_list(Vec::<Value>::new()).unwrap();
+            assert_eq!(es.require_list_size(&list).unwrap(), 0);
+            assert!(es.require_list_strict(&list).unwrap().is_empty());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    pub fn eval_state_require_list_size_lazy() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto", []).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let list = es
+                .eval_from_string("[ (throw \"not evaluated\") 2 ]", "<test>")
+                .unwrap();
+            assert_eq!(es.require_list_size(&list).unwrap(), 2);
+            let e = es.require_list_strict(&list).unwrap_err();
+            assert!(e.to_string().contains("not evaluated"));
+        })
+        .unwrap();
+    }
+
     #[test]
     pub fn eval_state_new_value_attrs_from_hashmap() {
         gc_registering_current_thread(|| {
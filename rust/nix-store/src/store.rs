@@ -1,11 +1,11 @@
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, Context as _, Error, Result};
 use lazy_static::lazy_static;
 use nix_c_raw as raw;
 use nix_util::context::Context;
 use nix_util::string_return::{callback_get_result_string, callback_get_result_string_data};
 use nix_util::{check_call, result_string_init};
-use std::collections::HashMap;
-use std::ffi::{c_char, CString};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{c_char, c_void, CStr, CString};
 use std::ptr::null_mut;
 use std::ptr::NonNull;
 use std::sync::{Arc, Mutex, Weak};
@@ -210,6 +210,61 @@ impl Store {
         }
     }
 
+    /// Check whether the store path is present in the store, without substituting or building it.
+    #[doc(alias = "nix_store_is_valid_path")]
+    pub fn is_valid_path(&mut self, path: &StorePath) -> Result<bool> {
+        unsafe {
+            check_call!(raw::store_is_valid_path(
+                &mut self.context,
+                self.inner.ptr(),
+                path.as_ptr()
+            ))
+        }
+    }
+
+    /// Build the outputs of a derivation, or substitute them if possible.
+    ///
+    /// Returns a map from output name to output path.
+    ///
+    /// If the build fails, the error message includes Nix's suggestion for retrieving the build log.
+    ///
+    /// The build is reported as a `building` tracing span.
+    #[doc(alias = "nix_store_realise")]
+    pub fn build(&mut self, drv: &StorePath) -> Result<BTreeMap<String, StorePath>> {
+        unsafe extern "C" fn callback(
+            userdata: *mut c_void,
+            outname: *const c_char,
+            out: *const c_char,
+        ) {
+            let outputs = &mut *(userdata as *mut Vec<(String, String)>);
+            let outname = CStr::from_ptr(outname).to_string_lossy().into_owned();
+            let out = CStr::from_ptr(out).to_string_lossy().into_owned();
+            outputs.push((outname, out));
+        }
+
+        let drv_name = drv.name().unwrap_or_default();
+        let span = tracing::info_span!("building", derivation = %drv_name);
+        let mut outputs: Vec<(String, String)> = Vec::new();
+        let r = unsafe {
+            check_call!(raw::store_realise(
+                &mut self.context,
+                self.inner.ptr(),
+                drv.as_ptr(),
+                &mut outputs as *mut Vec<(String, String)> as *mut c_void,
+                Some(callback)
+            ))
+        };
+        drop(span);
+        r.with_context(|| format!("while building derivation {}", drv_name))?;
+
+        let mut r = BTreeMap::new();
+        for (outname, out) in outputs {
+            let path = self.parse_store_path(&out)?;
+            r.insert(outname, path);
+        }
+        Ok(r)
+    }
+
     pub fn weak_ref(&self) -> StoreWeak {
         StoreWeak {
             inner: Arc::downgrade(&self.inner),
@@ -287,6 +342,31 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(nix_at_least = "2.26" /* get_storedir */)]
+    fn is_valid_path_nonexistent() {
+        let mut store = Store::open("auto", HashMap::new()).unwrap();
+        let store_dir = store.get_storedir().unwrap();
+        let store_path_string =
+            format!("{store_dir}/00000000000000000000000000000000-nixops4-does-not-exist");
+        let store_path = store.parse_store_path(store_path_string.as_str()).unwrap();
+        assert!(!store.is_valid_path(&store_path).unwrap());
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.26" /* get_storedir */)]
+    fn build_nonexistent_derivation() {
+        let mut store = Store::open("auto", HashMap::new()).unwrap();
+        let store_dir = store.get_storedir().unwrap();
+        let store_path_string =
+            format!("{store_dir}/00000000000000000000000000000000-nixops4-does-not-exist.drv");
+        let store_path = store.parse_store_path(store_path_string.as_str()).unwrap();
+        let e = store.build(&store_path).unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("while building derivation nixops4-does-not-exist.drv"));
+    }
+
     #[test]
     fn weak_ref() {
         let mut store = Store::open("auto", HashMap::new()).unwrap();
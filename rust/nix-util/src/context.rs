@@ -1,5 +1,10 @@
 use anyhow::{bail, Result};
 use nix_c_raw as raw;
+
+use crate::{
+    result_string_init,
+    string_return::{callback_get_result_string, callback_get_result_string_data},
+};
 use std::ptr::null_mut;
 use std::ptr::NonNull;
 
@@ -40,11 +45,34 @@ impl Context {
             let msgp = unsafe { raw::err_msg(null_mut(), self.inner.as_ptr(), null_mut()) };
             // Turn the i8 pointer into a Rust string by copying
             let msg: &str = unsafe { core::ffi::CStr::from_ptr(msgp).to_str()? };
+            if err == raw::err_NIX_ERR_NIX_ERROR {
+                return Err(NixError {
+                    msg: msg.to_string(),
+                    info_msg: self.info_msg().ok(),
+                }
+                .into());
+            }
             bail!("{}", msg);
         }
         Ok(())
     }
 
+    /// The error message without the trace, for errors thrown by Nix.
+    fn info_msg(&self) -> Result<String> {
+        let mut ctx = Context::new();
+        let mut r: Result<String> = result_string_init!();
+        unsafe {
+            raw::err_info_msg(
+                ctx.ptr(),
+                self.inner.as_ptr(),
+                Some(callback_get_result_string),
+                callback_get_result_string_data(&mut r),
+            );
+        }
+        ctx.check_err()?;
+        r
+    }
+
     pub fn clear(&mut self) {
         unsafe {
             raw::set_err_msg(
@@ -77,6 +105,46 @@ impl Context {
     }
 }
 
+/// An error that was thrown by Nix, as opposed to an error in the use of the C API.
+///
+/// Whether the message includes the trace is determined by Nix's `show-trace` setting.
+#[derive(Debug)]
+pub struct NixError {
+    msg: String,
+    info_msg: Option<String>,
+}
+
+impl NixError {
+    /// The error message without the trace, if Nix provided it.
+    pub fn info_msg(&self) -> Option<&str> {
+        self.info_msg.as_deref()
+    }
+
+    /// The message without the error message from [`info_msg`](Self::info_msg),
+    /// i.e. the trace and the positions, if the two can be separated.
+    pub fn trace(&self) -> Option<String> {
+        let (before, after) = self.msg.rsplit_once(self.info_msg.as_deref()?)?;
+        // Nix introduces the trace and the message with `error:`
+        let before = before.trim();
+        let before = before.strip_prefix("error:").unwrap_or(before);
+        let before = before.strip_suffix("error:").unwrap_or(before);
+        let trace = [before.trim(), after.trim()]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        Some(trace).filter(|trace| !trace.is_empty())
+    }
+}
+
+impl std::fmt::Display for NixError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for NixError {}
+
 impl Drop for Context {
     fn drop(&mut self) {
         unsafe {
@@ -148,6 +216,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn nix_error_trace() {
+        let e = NixError {
+            msg: "error:\n       … while evaluating 'x'\n\n       error: oops".to_string(),
+            info_msg: Some("oops".to_string()),
+        };
+        assert_eq!(e.trace().as_deref(), Some("… while evaluating 'x'"));
+        let e = NixError {
+            msg: "error: oops".to_string(),
+            info_msg: Some("oops".to_string()),
+        };
+        assert_eq!(e.trace(), None);
+        let e = NixError {
+            msg: "error: oops".to_string(),
+            info_msg: None,
+        };
+        assert_eq!(e.trace(), None);
+    }
+
     #[test]
     fn check_call_dynamic_context() {
         let r = check_call!(set_dummy_err(&mut Context::new()));
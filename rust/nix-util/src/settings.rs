@@ -31,6 +31,31 @@ pub fn get(key: &str) -> Result<String> {
     r
 }
 
+/// Set a setting until the returned guard is dropped, which restores the previous value.
+pub fn set_temporarily(key: &str, value: &str) -> Result<TemporarySetting> {
+    let previous = get(key)?;
+    set(key, value)?;
+    Ok(TemporarySetting {
+        key: key.to_string(),
+        previous,
+    })
+}
+
+/// Restores a setting when dropped. See [`set_temporarily`].
+#[must_use = "the setting is restored when this is dropped"]
+pub struct TemporarySetting {
+    key: String,
+    previous: String,
+}
+
+impl Drop for TemporarySetting {
+    fn drop(&mut self) {
+        if let Err(e) = set(&self.key, &self.previous) {
+            eprintln!("could not restore setting {}: {}", self.key, e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::check_call;
@@ -68,4 +93,15 @@ mod tests {
 
         assert_eq!(res, new_value);
     }
+
+    #[test]
+    fn set_temporarily_restores() {
+        let key = "user-agent-suffix";
+        let old_value = get(key).unwrap();
+        {
+            let _guard = set_temporarily(key, "temporary").unwrap();
+            assert_eq!(get(key).unwrap(), "temporary");
+        }
+        assert_eq!(get(key).unwrap(), old_value);
+    }
 }
@@ -1,4 +1,7 @@
-use std::hash::{Hash, Hasher};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    hash::{Hash, Hasher},
+};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
@@ -91,6 +94,8 @@ pub struct ResourceType;
 pub enum EvalRequest {
     LoadFlake(AssignRequest<FlakeRequest>),
     ListDeployments(QueryRequest<Id<FlakeType>, (Id<FlakeType>, Vec<String>)>),
+    GetFlakeMetadata(QueryRequest<Id<FlakeType>, FlakeMetadata>),
+    GetDeploymentOptions(QueryRequest<DeploymentName, DeploymentOptions>),
     LoadDeployment(AssignRequest<DeploymentRequest>),
     ListResources(QueryRequest<Id<DeploymentType>, (Id<DeploymentType>, Vec<String>)>),
     LoadResource(AssignRequest<ResourceRequest>),
@@ -140,8 +145,11 @@ impl<P, R> RequestIdType for QueryRequest<P, R> {
 /// No promises are made about this interface.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EvalResponse {
-    Error(Id<AnyType>, String),
+    Error(Id<AnyType>, EvalError),
     QueryResponse(Id<MessageType>, QueryResponseValue),
+    /// The evaluator has recorded a `PutResourceOutput`, so that dependent
+    /// resource inputs can now be evaluated.
+    ResourceOutputAck(NamedProperty),
     TracingEvent(
         /// This is a tracing_tunnel::TracingEvent, but that type (rightfully)
         /// does not implement Eq, while we would like to have that on our other
@@ -150,9 +158,63 @@ pub enum EvalResponse {
     ),
 }
 
+/// Why a request failed, so that the client can decide how to present the error, or whether to retry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvalError {
+    /// The Nix expressions could not be evaluated, e.g. because of a `throw` or a type error.
+    EvaluationError {
+        message: String,
+        trace: Option<String>,
+    },
+    /// The flake could not be loaded, e.g. because it does not exist or its lock file is out of date.
+    FlakeError {
+        message: String,
+        trace: Option<String>,
+    },
+    /// An attribute that NixOps4 requires, such as a deployment or resource, does not exist.
+    MissingAttribute {
+        message: String,
+        trace: Option<String>,
+    },
+    /// The evaluator could not handle the request, e.g. because it refers to an unknown id. This is a bug in NixOps4.
+    Internal {
+        message: String,
+        trace: Option<String>,
+    },
+}
+impl EvalError {
+    pub fn message(&self) -> &str {
+        match self {
+            EvalError::EvaluationError { message, .. }
+            | EvalError::FlakeError { message, .. }
+            | EvalError::MissingAttribute { message, .. }
+            | EvalError::Internal { message, .. } => message,
+        }
+    }
+    pub fn trace(&self) -> Option<&str> {
+        match self {
+            EvalError::EvaluationError { trace, .. }
+            | EvalError::FlakeError { trace, .. }
+            | EvalError::MissingAttribute { trace, .. }
+            | EvalError::Internal { trace, .. } => trace.as_deref(),
+        }
+    }
+}
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message())?;
+        if let Some(trace) = self.trace() {
+            write!(f, "\n{}", trace)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum QueryResponseValue {
     ListDeployments((Id<FlakeType>, Vec<String>)),
+    FlakeMetadata(FlakeMetadata),
+    DeploymentOptions(DeploymentOptions),
     ListResources((Id<DeploymentType>, Vec<String>)),
     ResourceProviderInfo(ResourceProviderInfo),
     ListResourceInputs((Id<ResourceType>, Vec<String>)),
@@ -165,11 +227,35 @@ pub enum ResourceInputState {
     ResourceInputDependency(ResourceInputDependency),
 }
 
+/// Information about the source of a loaded flake, for provenance.
+///
+/// Fields are `None` when the fetcher does not provide them, e.g. `rev` for a
+/// `path:` flake or a dirty `git` working tree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlakeMetadata {
+    pub id: Id<FlakeType>,
+    /// The commit hash of the flake source.
+    pub rev: Option<String>,
+    /// The time of the last modification, in seconds since the epoch.
+    pub last_modified: Option<i64>,
+    /// The NAR hash of the flake source, in SRI format.
+    pub nar_hash: Option<String>,
+    /// The commit hash of a working tree with uncommitted changes, with a `-dirty` suffix.
+    pub dirty_rev: Option<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ResourceProviderInfo {
     pub id: Id<ResourceType>,
     pub provider: Value,
     pub resource_type: String,
+    /// The inputs and outputs whose values must not be shown, from the resource's `secretProperties`.
+    #[serde(default)]
+    pub secret_properties: BTreeSet<String>,
+    /// How many seconds applying the resource may take, from the resource's
+    /// `timeoutSeconds`. Overrides `nixops4 apply --resource-timeout`.
+    #[serde(default)]
+    pub timeout_seconds: Option<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -206,11 +292,41 @@ pub struct DeploymentRequest {
     pub flake: Id<FlakeType>,
     /// The name of the deployment to load.
     pub name: String,
+    /// Arguments to pass to the deployment, if it is a function.
+    pub args: BTreeMap<String, DeploymentArg>,
 }
 impl RequestIdType for DeploymentRequest {
     type IdType = DeploymentType;
 }
 
+/// An argument for a deployment function, as in Nix's `--arg` and `--argstr`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeploymentArg {
+    /// A Nix expression, to be evaluated
+    Expr(String),
+    /// A string value
+    Str(String),
+}
+
+/// A deployment in a flake, without loading it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeploymentName {
+    pub flake: Id<FlakeType>,
+    pub name: String,
+}
+
+/// The options of a deployment, other than its resources.
+///
+/// These are attributes of the `nixops4Deployments` entry, next to `_type`.
+/// A deployment that is a function has no options until it is called, so for
+/// such a deployment they are all unset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeploymentOptions {
+    pub deployment: DeploymentName,
+    /// A short description of the deployment, for `nixops4 deployments list`.
+    pub description: Option<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ResourceRequest {
     /// The deployment to load the resource from.
@@ -294,6 +410,96 @@ mod tests {
         assert_eq!(req, req2);
     }
 
+    #[test]
+    fn test_eval_response_flake_metadata() {
+        let resp = EvalResponse::QueryResponse(
+            Id::new(2),
+            QueryResponseValue::FlakeMetadata(FlakeMetadata {
+                id: Id::new(1),
+                rev: None,
+                last_modified: Some(1700000000),
+                nar_hash: Some("sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string()),
+                dirty_rev: Some("0123456789abcdef0123456789abcdef01234567-dirty".to_string()),
+            }),
+        );
+        let s = eval_response_to_json(&resp).unwrap();
+        let resp2 = eval_response_from_json(&s).unwrap();
+        assert_eq!(resp, resp2);
+    }
+
+    #[test]
+    fn test_eval_response_deployment_options() {
+        let resp = EvalResponse::QueryResponse(
+            Id::new(2),
+            QueryResponseValue::DeploymentOptions(DeploymentOptions {
+                deployment: DeploymentName {
+                    flake: Id::new(1),
+                    name: "prod".to_string(),
+                },
+                description: Some("The production environment".to_string()),
+            }),
+        );
+        let s = eval_response_to_json(&resp).unwrap();
+        let resp2 = eval_response_from_json(&s).unwrap();
+        assert_eq!(resp, resp2);
+    }
+
+    #[test]
+    fn test_eval_response_error() {
+        let errors = [
+            EvalError::EvaluationError {
+                message: "a".to_string(),
+                trace: Some("while evaluating a".to_string()),
+            },
+            EvalError::FlakeError {
+                message: "b".to_string(),
+                trace: None,
+            },
+            EvalError::MissingAttribute {
+                message: "c".to_string(),
+                trace: None,
+            },
+            EvalError::Internal {
+                message: "d".to_string(),
+                trace: None,
+            },
+        ];
+        for error in errors {
+            let resp = EvalResponse::Error(Id::new(1), error);
+            let s = eval_response_to_json(&resp).unwrap();
+            let resp2 = eval_response_from_json(&s).unwrap();
+            assert_eq!(resp, resp2);
+        }
+    }
+
+    #[test]
+    fn test_eval_error_message() {
+        let e = EvalError::MissingAttribute {
+            message: "missing attribute `x`".to_string(),
+            trace: Some("trace".to_string()),
+        };
+        assert_eq!(e.message(), "missing attribute `x`");
+        assert_eq!(e.to_string(), "missing attribute `x`\ntrace");
+        assert_eq!(e.trace(), Some("trace"));
+
+        let e = EvalError::EvaluationError {
+            message: "oops".to_string(),
+            trace: None,
+        };
+        assert_eq!(e.to_string(), "oops");
+    }
+
+    #[test]
+    fn test_eval_response_resource_output_ack() {
+        let resp = EvalResponse::ResourceOutputAck(NamedProperty {
+            resource: "a".to_string(),
+            name: "out".to_string(),
+        });
+        let s = eval_response_to_json(&resp).unwrap();
+        let resp2 = eval_response_from_json(&s).unwrap();
+        assert_eq!(resp, resp2);
+    }
+
     #[test]
     fn test_eval_request_list_deployments() {
         let req = EvalRequest::ListDeployments(QueryRequest {
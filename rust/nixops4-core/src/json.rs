@@ -0,0 +1,73 @@
+use serde_json::{Map, Number, Value};
+
+/// Normalize a JSON value so that semantically equal values compare equal.
+///
+/// Object keys are sorted recursively, and numbers that are integers are
+/// represented as integers, so that e.g. `1.0` and `1` are equal, and so are
+/// `-0.0` and `0`. The order of array elements is significant and kept.
+pub fn canonicalize_json(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by_key(|(k, _)| *k);
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), canonicalize_json(v)))
+                    .collect::<Map<String, Value>>(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.iter().map(canonicalize_json).collect()),
+        Value::Number(n) => Value::Number(canonicalize_number(n)),
+        Value::Null | Value::Bool(_) | Value::String(_) => value.clone(),
+    }
+}
+
+fn canonicalize_number(n: &Number) -> Number {
+    if n.is_i64() || n.is_u64() {
+        return n.clone();
+    }
+    match n.as_f64() {
+        Some(f) if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 => {
+            Number::from(f as i64)
+        }
+        _ => n.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_canonicalize_json_object_order() {
+        let a: Value = serde_json::from_str(r#"{"b": 1, "a": {"y": [1, 2], "x": null}}"#).unwrap();
+        let b: Value = serde_json::from_str(r#"{"a": {"x": null, "y": [1, 2]}, "b": 1}"#).unwrap();
+        assert_eq!(canonicalize_json(&a), canonicalize_json(&b));
+        assert_eq!(
+            serde_json::to_string(&canonicalize_json(&a)).unwrap(),
+            r#"{"a":{"x":null,"y":[1,2]},"b":1}"#
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_json_numbers() {
+        assert_eq!(canonicalize_json(&json!(1.0)), json!(1));
+        assert_eq!(canonicalize_json(&json!(-0.0)), json!(0));
+        assert_eq!(canonicalize_json(&json!(1.5)), json!(1.5));
+        assert_eq!(canonicalize_json(&json!(u64::MAX)), json!(u64::MAX));
+        assert_eq!(
+            canonicalize_json(&json!({"n": [2.0, 3]})),
+            canonicalize_json(&json!({"n": [2, 3.0]}))
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_json_array_order() {
+        assert_ne!(
+            canonicalize_json(&json!([1, 2])),
+            canonicalize_json(&json!([2, 1]))
+        );
+    }
+}
@@ -1 +1,2 @@
 pub mod eval_api;
+pub mod json;
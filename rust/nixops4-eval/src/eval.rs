@@ -1,16 +1,22 @@
-use std::{collections::HashMap, future::Future, pin::Pin};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    future::Future,
+    pin::Pin,
+};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
 use async_trait::async_trait;
 use base64::engine::Engine;
 use cstr::cstr;
 use nix_expr::{
-    eval_state::EvalState,
+    eval_state::{EvalState, MissingAttributeError},
     primop::{PrimOp, PrimOpMeta},
-    value::Value,
+    value::{Value, ValueType},
 };
+use nix_util::context::NixError;
 use nixops4_core::eval_api::{
-    AssignRequest, EvalRequest, EvalResponse, FlakeType, Id, IdNum, NamedProperty, QueryRequest,
+    AssignRequest, DeploymentArg, DeploymentName, DeploymentOptions, EvalError, EvalRequest,
+    EvalResponse, FlakeMetadata, FlakeType, Id, IdNum, NamedProperty, QueryRequest,
     QueryResponseValue, RequestIdType, ResourceInputDependency, ResourceInputState,
     ResourceProviderInfo, ResourceType,
 };
@@ -29,6 +35,13 @@ pub struct EvaluationDriver {
     respond: Box<dyn Respond>,
     known_outputs: Arc<Mutex<HashMap<NamedProperty, Value>>>,
     resource_names: HashMap<Id<ResourceType>, String>,
+    show_trace: bool,
+    pure_eval: bool,
+    /// Flakes that have been loaded, by flake reference, so that loading the
+    /// same flake again doesn't lock and fetch it again.
+    flakes: HashMap<String, Value>,
+    /// How many times a flake was actually loaded, rather than taken from `flakes`.
+    flake_loads: usize,
 }
 impl EvaluationDriver {
     pub fn new(eval_state: EvalState, respond: Box<dyn Respond>) -> EvaluationDriver {
@@ -38,9 +51,29 @@ impl EvaluationDriver {
             respond,
             known_outputs: Arc::new(Mutex::new(HashMap::new())),
             resource_names: HashMap::new(),
+            show_trace: false,
+            pure_eval: false,
+            flakes: HashMap::new(),
+            flake_loads: 0,
         }
     }
 
+    /// Whether to include the Nix trace in error responses.
+    ///
+    /// This also enables Nix's `show-trace` setting, which is global.
+    pub fn set_show_trace(&mut self, show_trace: bool) -> Result<()> {
+        nix_util::settings::set("show-trace", if show_trace { "true" } else { "false" })?;
+        self.show_trace = show_trace;
+        Ok(())
+    }
+
+    /// Whether the `EvalState` was built with [`EvalStateBuilder::pure`][nix_expr::eval_state::EvalStateBuilder::pure].
+    ///
+    /// The flake to load is then still locked impurely, as `nix` does for the flake it is asked to evaluate.
+    pub fn set_pure_eval(&mut self, pure_eval: bool) {
+        self.pure_eval = pure_eval;
+    }
+
     async fn respond(&mut self, response: EvalResponse) -> Result<()> {
         self.respond.call(response).await
     }
@@ -50,7 +83,10 @@ impl EvaluationDriver {
             return Box::pin(async move {
                 self.respond(EvalResponse::Error(
                     id.any(),
-                    "id already used: ".to_string() + &id.num().to_string(),
+                    EvalError::Internal {
+                        message: "id already used: ".to_string() + &id.num().to_string(),
+                        trace: None,
+                    },
                 ))
                 .await?;
                 Ok(())
@@ -62,11 +98,6 @@ impl EvaluationDriver {
 
     // https://github.com/NixOS/nix/issues/10435
     fn get_flake(&mut self, flakeref_str: &str) -> Result<Value> {
-        let get_flake = self
-            .eval_state
-            .eval_from_string("builtins.getFlake", "<nixops4-eval setup>")?;
-        // TODO: replace with native functionality through C API, see issue #10435, linked above
-
         // Avoid copying everything, including target/ and .git/ directories.
         // Check for a .git directory in the path.
         let flakeref_str = if std::path::Path::new(flakeref_str).join(".git").exists() {
@@ -75,8 +106,28 @@ impl EvaluationDriver {
             flakeref_str.to_string()
         };
 
+        if let Some(flake) = self.flakes.get(&flakeref_str) {
+            return Ok(flake.clone());
+        }
+
+        let get_flake = self
+            .eval_state
+            .eval_from_string("builtins.getFlake", "<nixops4-eval setup>")?;
+        // TODO: replace with native functionality through C API, see issue #10435, linked above
+
         let flakeref = self.eval_state.new_value_str(flakeref_str.as_str())?;
-        self.eval_state.call(get_flake, flakeref)
+        let flake = if self.pure_eval {
+            // `builtins.getFlake` refuses unlocked references, such as a
+            // local directory, in pure mode. Lock the flake impurely; its
+            // outputs are still evaluated purely, as they are lazy.
+            let _impure = nix_util::settings::set_temporarily("pure-eval", "false")?;
+            self.eval_state.call(get_flake, flakeref)?
+        } else {
+            self.eval_state.call(get_flake, flakeref)?
+        };
+        self.flake_loads += 1;
+        self.flakes.insert(flakeref_str, flake.clone());
+        Ok(flake)
     }
 
     /// Helper function that helps with error handling and saving the result.
@@ -96,8 +147,11 @@ impl EvaluationDriver {
         match r {
             Ok(a) => save(self, request.assign_to, a).await,
             Err(e) => {
-                self.respond(EvalResponse::Error(request.assign_to.any(), e.to_string()))
-                    .await
+                self.respond(EvalResponse::Error(
+                    request.assign_to.any(),
+                    classify_error(&e, self.show_trace),
+                ))
+                .await
             }
         }
     }
@@ -119,8 +173,11 @@ impl EvaluationDriver {
                     .await
             }
             Err(e) => {
-                self.respond(EvalResponse::Error(request.message_id.any(), e.to_string()))
-                    .await
+                self.respond(EvalResponse::Error(
+                    request.message_id.any(),
+                    classify_error(&e, self.show_trace),
+                ))
+                .await
             }
         }
     }
@@ -128,16 +185,13 @@ impl EvaluationDriver {
     fn get_value<T>(&self, id: Id<T>) -> Result<&Value> {
         self.values
             .get(&id.num())
-            .ok_or_else(|| anyhow::anyhow!("id not found: {}", id.num().to_string()))
+            .ok_or_else(|| anyhow::Error::new(UnknownIdError(id.num())))
     }
 
     fn get_flake_deployments_value(&mut self, flake: Id<FlakeType>) -> Result<Value> {
         let flake = self.get_value(flake)?.clone();
-        let outputs = self.eval_state.require_attrs_select(&flake, "outputs")?;
-        let deployments = self
-            .eval_state
-            .require_attrs_select(&outputs, "nixops4Deployments")?;
-        Ok(deployments.clone())
+        self.eval_state
+            .require_attrs_select_path(&flake, &["outputs", "nixops4Deployments"])
     }
 
     pub async fn perform_request(&mut self, request: &EvalRequest) -> Result<()> {
@@ -145,7 +199,10 @@ impl EvaluationDriver {
             EvalRequest::LoadFlake(req) => {
                 self.handle_assign_request(
                     req,
-                    |this, req| this.get_flake(req.abspath.as_str()),
+                    |this, req| {
+                        this.get_flake(req.abspath.as_str())
+                            .map_err(|e| anyhow::Error::new(FlakeLoadError(e)))
+                    },
                     EvaluationDriver::assign_value,
                 )
                 .await
@@ -163,6 +220,22 @@ impl EvaluationDriver {
                 })
                 .await
             }
+            EvalRequest::GetFlakeMetadata(req) => {
+                self.handle_simple_request(
+                    req,
+                    QueryResponseValue::FlakeMetadata,
+                    perform_get_flake_metadata,
+                )
+                .await
+            }
+            EvalRequest::GetDeploymentOptions(req) => {
+                self.handle_simple_request(
+                    req,
+                    QueryResponseValue::DeploymentOptions,
+                    perform_get_deployment_options,
+                )
+                .await
+            }
             EvalRequest::LoadDeployment(req) => {
                 let known_outputs = Arc::clone(&self.known_outputs);
                 self.handle_assign_request(
@@ -238,12 +311,92 @@ impl EvaluationDriver {
                         .unwrap()
                         .insert(named_prop.clone(), value);
                 }
-                Ok(())
+                self.respond(EvalResponse::ResourceOutputAck(named_prop.clone()))
+                    .await
             } // _ => unimplemented!(),
         }
     }
 }
 
+/// An error that occurred while loading a flake.
+#[derive(Debug)]
+struct FlakeLoadError(anyhow::Error);
+impl std::fmt::Display for FlakeLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+impl std::error::Error for FlakeLoadError {}
+
+/// A request referred to an id that was never assigned.
+#[derive(Debug)]
+struct UnknownIdError(IdNum);
+impl std::fmt::Display for UnknownIdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "id not found: {}", self.0)
+    }
+}
+impl std::error::Error for UnknownIdError {}
+
+/// Determine the kind of error to report to the client.
+///
+/// With `show_trace`, the message of an error thrown by Nix is reported
+/// without its trace, and the trace is reported separately, if Nix's
+/// message allows separating the two.
+fn classify_error(e: &anyhow::Error, show_trace: bool) -> EvalError {
+    let nix_error = e.chain().find_map(|e| e.downcast_ref::<NixError>());
+    let (message, trace) = match nix_error {
+        Some(nix_error) if show_trace => {
+            let message = e
+                .chain()
+                .map(|e| match e.downcast_ref::<NixError>() {
+                    Some(nix_error) => nix_error
+                        .info_msg()
+                        .map(|m| m.to_string())
+                        .unwrap_or_else(|| nix_error.to_string()),
+                    None => e.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(": ");
+            (message, nix_error.trace())
+        }
+        _ => (format!("{:#}", e), None),
+    };
+    if e.downcast_ref::<UnknownIdError>().is_some() {
+        EvalError::Internal { message, trace }
+    } else if e.downcast_ref::<FlakeLoadError>().is_some() {
+        EvalError::FlakeError { message, trace }
+    } else if e.downcast_ref::<MissingAttributeError>().is_some() {
+        EvalError::MissingAttribute { message, trace }
+    } else {
+        EvalError::EvaluationError { message, trace }
+    }
+}
+
+fn perform_get_deployment_options(
+    driver: &mut EvaluationDriver,
+    req: &DeploymentName,
+) -> Result<DeploymentOptions> {
+    let deployments = driver.get_flake_deployments_value(req.flake)?;
+    let es = &mut driver.eval_state;
+    let deployment = es.require_attrs_select(&deployments, &req.name)?;
+    let mut options = DeploymentOptions {
+        deployment: req.clone(),
+        description: None,
+    };
+    if es.value_type(&deployment)? == ValueType::Function {
+        return Ok(options);
+    }
+    check_deployment_tag(es, &req.name, &deployment)?;
+    if let Some(description) = es.require_attrs_select_opt(&deployment, "description")? {
+        let description = es
+            .require_string(&description)
+            .context("while evaluating the deployment's `description`")?;
+        options.description = Some(description);
+    }
+    Ok(options)
+}
+
 fn perform_load_deployment(
     driver: &mut EvaluationDriver,
     req: &nixops4_core::eval_api::DeploymentRequest,
@@ -252,13 +405,8 @@ fn perform_load_deployment(
     let deployments = { driver.get_flake_deployments_value(req.flake)? }.clone();
     let es = &mut driver.eval_state;
     let deployment = es.require_attrs_select(&deployments, &req.name)?;
-    {
-        let tag = es.require_attrs_select(&deployment, "_type")?;
-        let str = es.require_string(&tag)?;
-        if str != "nixops4Deployment" {
-            bail!("expected _type to be 'nixops4Deployment', got: {}", str);
-        }
-    }
+    let deployment = apply_deployment_args(es, &deployment, &req.args)?;
+    check_deployment_tag(es, &req.name, &deployment)?;
     let eval_expr = r#"
                         # primops
                         loadResourceAttr:
@@ -339,6 +487,82 @@ fn perform_load_deployment(
     Ok(fixpoint)
 }
 
+/// Check that a `nixops4Deployments` entry is a deployment, so that a mistake
+/// in the flake is reported as such, rather than as a confusing error later on.
+fn check_deployment_tag(es: &mut EvalState, name: &str, deployment: &Value) -> Result<()> {
+    let not_a_deployment = || {
+        format!(
+            "nixops4Deployments.{} is not a deployment; a deployment is an attrset with `_type = \"nixops4Deployment\"`",
+            name
+        )
+    };
+    let tag = es
+        .require_type_tag(deployment, "_type")
+        .with_context(not_a_deployment)?;
+    if tag != "nixops4Deployment" {
+        bail!("{}, not `_type = \"{}\"`", not_a_deployment(), tag);
+    }
+    Ok(())
+}
+
+/// If the deployment is a function, call it with the arguments that it accepts,
+/// like Nix does with `--arg` and `--argstr`.
+fn apply_deployment_args(
+    es: &mut EvalState,
+    deployment: &Value,
+    args: &BTreeMap<String, DeploymentArg>,
+) -> Result<Value> {
+    if es.value_type(deployment)? != ValueType::Function {
+        return Ok(deployment.clone());
+    }
+    let mut arg_values = Vec::with_capacity(args.len());
+    for (name, arg) in args {
+        let value = match arg {
+            DeploymentArg::Expr(expr) => {
+                let cwd = std::env::current_dir()?;
+                es.eval_from_string(expr, cwd.to_string_lossy().as_ref())
+                    .with_context(|| format!("while evaluating deployment argument {}", name))?
+            }
+            DeploymentArg::Str(s) => es.new_value_str(s)?,
+        };
+        arg_values.push((name.clone(), value));
+    }
+    let args_value = es.new_value_attrs(arg_values)?;
+    let auto_call = es.eval_from_string(
+        "f: args: f (builtins.intersectAttrs (builtins.functionArgs f) args)",
+        "<nixops4 internals>",
+    )?;
+    es.call_multi(&auto_call, &[deployment.clone(), args_value])
+}
+
+fn perform_get_flake_metadata(
+    this: &mut EvaluationDriver,
+    req: &Id<FlakeType>,
+) -> std::result::Result<FlakeMetadata, anyhow::Error> {
+    let flake = this.get_value(req.to_owned())?.clone();
+    let mut get_string = |name: &str| -> Result<Option<String>> {
+        this.eval_state
+            .require_attrs_select_opt(&flake, name)?
+            .map(|v| this.eval_state.require_string(&v))
+            .transpose()
+    };
+    let rev = get_string("rev")?;
+    let nar_hash = get_string("narHash")?;
+    let dirty_rev = get_string("dirtyRev")?;
+    let last_modified = this
+        .eval_state
+        .require_attrs_select_opt(&flake, "lastModified")?
+        .map(|v| this.eval_state.require_int(&v))
+        .transpose()?;
+    Ok(FlakeMetadata {
+        id: req.to_owned(),
+        rev,
+        last_modified,
+        nar_hash,
+        dirty_rev,
+    })
+}
+
 fn perform_get_resource(
     this: &mut EvaluationDriver,
     req: &Id<nixops4_core::eval_api::ResourceType>,
@@ -360,10 +584,34 @@ fn perform_get_resource(
     };
     let resource_type_value = this.eval_state.require_attrs_select(&resource, "type")?;
     let resource_type_str = this.eval_state.require_string(&resource_type_value)?;
+    let mut secret_properties = BTreeSet::new();
+    if let Some(secrets) = this
+        .eval_state
+        .require_attrs_select_opt(&resource, "secretProperties")?
+    {
+        for name in this.eval_state.require_list_strict(&secrets)? {
+            secret_properties.insert(this.eval_state.require_string(&name)?);
+        }
+    }
+    let timeout_seconds = match this
+        .eval_state
+        .require_attrs_select_opt(&resource, "timeoutSeconds")?
+    {
+        Some(timeout) => {
+            let timeout = this.eval_state.require_int(&timeout)?;
+            if timeout < 0 {
+                bail!("timeoutSeconds must not be negative, but is {}", timeout);
+            }
+            Some(timeout as u64)
+        }
+        None => None,
+    };
     Ok(ResourceProviderInfo {
         id: req.to_owned(),
         provider: provider_json,
         resource_type: resource_type_str,
+        secret_properties,
+        timeout_seconds,
     })
 }
 
@@ -375,7 +623,17 @@ fn perform_get_resource_input(
         let resource = this.get_value(req.resource.to_owned())?.clone();
         let inputs = this.eval_state.require_attrs_select(&resource, "inputs")?;
         let input = this.eval_state.require_attrs_select(&inputs, &req.name)?;
+        let span = tracing::info_span!(
+            "evaluating and realising resource input",
+            resource_name = this
+                .resource_names
+                .get(&req.resource)
+                .map(|s| s.as_str())
+                .unwrap_or("<unknown>"),
+            input_name = req.name.as_str()
+        );
         let json = value_to_json(&mut this.eval_state, &input)?;
+        drop(span);
         Ok(json)
     })();
     match attempt {
@@ -400,7 +658,15 @@ fn perform_get_resource_input(
                     },
                 ))
             } else {
-                Err(e)
+                let resource_name = this
+                    .resource_names
+                    .get(&req.resource)
+                    .map(|s| s.as_str())
+                    .unwrap_or("<unknown>");
+                Err(e.context(format!(
+                    "while evaluating resource `{}`.inputs.`{}`",
+                    resource_name, req.name
+                )))
             }
         }
     }
@@ -435,7 +701,8 @@ mod tests {
     use nix_expr::eval_state::{gc_register_my_thread, EvalState};
     use nix_store::store::Store;
     use nixops4_core::eval_api::{
-        AssignRequest, DeploymentRequest, FlakeRequest, Ids, QueryRequest,
+        AssignRequest, DeploymentRequest, DeploymentType, FlakeRequest, Ids, MessageType, Property,
+        QueryRequest, ResourceRequest,
     };
     use tempdir::TempDir;
     use tokio::runtime;
@@ -469,6 +736,72 @@ mod tests {
         nix_expr::eval_state::test_init();
     }
 
+    /// Load a flake with the given `flake.nix` into a new driver, and run `f`
+    /// with the driver, the ids for further requests, the id of the flake, and
+    /// the responses that the driver has sent.
+    fn with_test_driver(
+        flake_nix: &str,
+        f: impl FnOnce(
+            &mut EvaluationDriver,
+            &mut Ids,
+            Id<FlakeType>,
+            &Mutex<Vec<EvalResponse>>,
+        ) -> Result<()>,
+    ) {
+        let tmpdir = TempDir::new("test-nixops4-eval").unwrap();
+        std::fs::write(tmpdir.path().join("flake.nix"), flake_nix).unwrap();
+
+        let guard = gc_register_my_thread().unwrap();
+        let store = Store::open("auto", []).unwrap();
+        let eval_state = EvalState::new(store, []).unwrap();
+        let responses: Arc<Mutex<Vec<EvalResponse>>> = Default::default();
+        let respond = Box::new(TestRespond {
+            responses: responses.clone(),
+        });
+        let mut driver = EvaluationDriver::new(eval_state, respond);
+
+        let mut ids = Ids::new();
+        let flake_id = ids.next();
+        block_on(
+            driver.perform_request(&EvalRequest::LoadFlake(AssignRequest {
+                assign_to: flake_id,
+                payload: FlakeRequest {
+                    abspath: tmpdir.path().to_str().unwrap().to_string(),
+                },
+            })),
+        )
+        .unwrap();
+        {
+            let r = responses.lock().unwrap();
+            if !r.is_empty() {
+                panic!("expected 0 responses after loading the flake, got: {:?}", r);
+            }
+        }
+        f(&mut driver, &mut ids, flake_id, &responses).unwrap();
+        drop(guard);
+    }
+
+    /// Load deployment `name` of the flake, without arguments.
+    fn load_test_deployment(
+        driver: &mut EvaluationDriver,
+        ids: &mut Ids,
+        flake_id: Id<FlakeType>,
+        name: &str,
+    ) -> Result<Id<DeploymentType>> {
+        let deployment_id = ids.next();
+        block_on(
+            driver.perform_request(&EvalRequest::LoadDeployment(AssignRequest {
+                assign_to: deployment_id,
+                payload: DeploymentRequest {
+                    flake: flake_id,
+                    name: name.to_string(),
+                    args: BTreeMap::new(),
+                },
+            })),
+        )?;
+        Ok(deployment_id)
+    }
+
     #[test]
     fn test_eval_driver_invalid_flakeref() {
         (|| -> Result<()> {
@@ -498,7 +831,8 @@ mod tests {
                 match &r[0] {
                     EvalResponse::Error(id, msg) => {
                         assert_eq!(id, &flake_id.any());
-                        if msg.contains("/non-existent/path/to/flake") {
+                        assert!(matches!(msg, EvalError::FlakeError { .. }));
+                        if msg.message().contains("/non-existent/path/to/flake") {
                             drop(guard);
                             return Ok(());
                         } else {
@@ -609,6 +943,125 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn test_eval_driver_flake_metadata_path() {
+        let flake_nix = r#"
+        {
+          outputs = { ... }: { };
+        }
+        "#;
+        with_test_driver(flake_nix, |driver, ids, flake_id, responses| {
+            let metadata_id = ids.next();
+            block_on(
+                driver.perform_request(&EvalRequest::GetFlakeMetadata(QueryRequest::new(
+                    metadata_id,
+                    flake_id,
+                ))),
+            )?;
+            let r = responses.lock().unwrap();
+            if r.len() != 1 {
+                panic!("expected 1 response, got: {:?}", r);
+            }
+            match &r[0] {
+                EvalResponse::QueryResponse(id, QueryResponseValue::FlakeMetadata(metadata)) => {
+                    assert_eq!(id, &metadata_id);
+                    assert_eq!(metadata.id, flake_id);
+                    assert!(metadata.last_modified.is_some());
+                    assert!(metadata.nar_hash.is_some());
+                    // Not a version controlled flake
+                    assert_eq!(metadata.rev, None);
+                    assert_eq!(metadata.dirty_rev, None);
+                }
+                _ => panic!("expected EvalResponse::QueryResponse with FlakeMetadata"),
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_eval_driver_flake_loaded_once() {
+        let flake_nix = r#"
+        {
+          outputs = { ... }: {
+            nixops4Deployments.default = { };
+          };
+        }
+        "#;
+        let tmpdir = TempDir::new("test-nixops4-eval").unwrap();
+        std::fs::write(tmpdir.path().join("flake.nix"), flake_nix).unwrap();
+        let other_tmpdir = TempDir::new("test-nixops4-eval").unwrap();
+        std::fs::write(other_tmpdir.path().join("flake.nix"), flake_nix).unwrap();
+
+        (|| -> Result<()> {
+            let guard = gc_register_my_thread().unwrap();
+            let store = Store::open("auto", [])?;
+            let eval_state = EvalState::new(store, [])?;
+            let responses: Arc<Mutex<Vec<EvalResponse>>> = Default::default();
+            let respond = Box::new(TestRespond {
+                responses: responses.clone(),
+            });
+            let mut driver = EvaluationDriver::new(eval_state, respond);
+
+            let mut ids = Ids::new();
+            let flake_ids: Vec<Id<FlakeType>> = vec![ids.next(), ids.next()];
+            let deployments_ids: Vec<Id<MessageType>> = vec![ids.next(), ids.next()];
+            block_on(async {
+                for (flake_id, deployments_id) in flake_ids.iter().zip(deployments_ids.iter()) {
+                    driver
+                        .perform_request(&EvalRequest::LoadFlake(AssignRequest {
+                            assign_to: *flake_id,
+                            payload: FlakeRequest {
+                                abspath: tmpdir.path().to_str().unwrap().to_string(),
+                            },
+                        }))
+                        .await?;
+                    driver
+                        .perform_request(&EvalRequest::ListDeployments(QueryRequest::new(
+                            *deployments_id,
+                            *flake_id,
+                        )))
+                        .await?;
+                }
+                Ok::<(), anyhow::Error>(())
+            })
+            .unwrap();
+            assert_eq!(driver.flake_loads, 1);
+            {
+                let r = responses.lock().unwrap();
+                if r.len() != 2 {
+                    panic!("expected 2 responses, got: {:?}", r);
+                }
+                for (response, flake_id) in r.iter().zip(flake_ids.iter()) {
+                    match response {
+                        EvalResponse::QueryResponse(
+                            _,
+                            QueryResponseValue::ListDeployments((id, deployments)),
+                        ) => {
+                            assert_eq!(id, flake_id);
+                            assert_eq!(deployments, &vec!["default".to_string()]);
+                        }
+                        _ => panic!("expected EvalResponse::QueryResponse with ListDeployments"),
+                    }
+                }
+            }
+
+            // A different flake is loaded separately
+            block_on(
+                driver.perform_request(&EvalRequest::LoadFlake(AssignRequest {
+                    assign_to: ids.next(),
+                    payload: FlakeRequest {
+                        abspath: other_tmpdir.path().to_str().unwrap().to_string(),
+                    },
+                })),
+            )?;
+            assert_eq!(driver.flake_loads, 2);
+
+            drop(guard);
+            Ok(())
+        })()
+        .unwrap();
+    }
+
     #[test]
     fn test_eval_driver_flake_deployments_throw() {
         let flake_nix = r#"
@@ -659,7 +1112,7 @@ mod tests {
                 match &r[0] {
                     EvalResponse::Error(id, msg) => {
                         assert_eq!(id, &deployments_id.any());
-                        if !msg.contains("so this is the error message from the nixops4Deployments attribute value") {
+                        if !msg.message().contains("so this is the error message from the nixops4Deployments attribute value") {
                             panic!("unexpected error message: {}", msg);
                         }
                     }
@@ -744,6 +1197,103 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_eval_driver_flake_deployment_not_tagged() {
+        let flake_nix = r#"
+            {
+                outputs = { ... }: {
+                    nixops4Deployments = {
+                        untagged = {
+                            deploymentFunction = { ... }: { resources = { }; };
+                        };
+                        mistagged = {
+                            _type = "nixops4Resource";
+                        };
+                    };
+                };
+            }
+        "#;
+
+        with_test_driver(flake_nix, |driver, ids, flake_id, responses| {
+            for (name, expected) in [
+                (
+                    "untagged",
+                    "nixops4Deployments.untagged is not a deployment; a deployment is an attrset with `_type = \"nixops4Deployment\"`: value is not tagged with `_type`",
+                ),
+                (
+                    "mistagged",
+                    "nixops4Deployments.mistagged is not a deployment; a deployment is an attrset with `_type = \"nixops4Deployment\"`, not `_type = \"nixops4Resource\"`",
+                ),
+            ] {
+                let deployment_id = load_test_deployment(driver, ids, flake_id, name)?;
+                let r = responses.lock().unwrap().pop();
+                match r {
+                    Some(EvalResponse::Error(id, e)) => {
+                        assert_eq!(id, deployment_id.any());
+                        assert_eq!(e.message(), expected);
+                    }
+                    _ => panic!("expected EvalResponse::Error, got: {:?}", r),
+                }
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_eval_driver_flake_deployment_options() {
+        let flake_nix = r#"
+            {
+                outputs = { ... }: {
+                    nixops4Deployments = {
+                        described = {
+                            _type = "nixops4Deployment";
+                            description = "The production environment";
+                            deploymentFunction = { ... }: { resources = { }; };
+                        };
+                        plain = {
+                            _type = "nixops4Deployment";
+                            deploymentFunction = { ... }: { resources = { }; };
+                        };
+                    };
+                };
+            }
+        "#;
+
+        with_test_driver(flake_nix, |driver, ids, flake_id, responses| {
+            for (name, expected) in [
+                ("described", Some("The production environment")),
+                ("plain", None),
+            ] {
+                let options_id = ids.next();
+                let deployment = DeploymentName {
+                    flake: flake_id,
+                    name: name.to_string(),
+                };
+                block_on(driver.perform_request(&EvalRequest::GetDeploymentOptions(
+                    QueryRequest::new(options_id, deployment.clone()),
+                )))?;
+                let r = responses.lock().unwrap().pop();
+                match r {
+                    Some(EvalResponse::QueryResponse(
+                        id,
+                        QueryResponseValue::DeploymentOptions(options),
+                    )) => {
+                        assert_eq!(id, options_id);
+                        assert_eq!(
+                            options,
+                            DeploymentOptions {
+                                deployment,
+                                description: expected.map(|s| s.to_string()),
+                            }
+                        );
+                    }
+                    _ => panic!("expected EvalResponse::QueryResponse, got: {:?}", r),
+                }
+            }
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_eval_driver_flake_example() {
         let flake_nix = r#"
@@ -815,6 +1365,7 @@ mod tests {
                     payload: DeploymentRequest {
                         flake: flake_id,
                         name: "example".to_string(),
+                        args: BTreeMap::new(),
                     },
                 })),
             )
@@ -828,4 +1379,256 @@ mod tests {
             drop(guard);
         }
     }
+
+    #[test]
+    fn test_eval_driver_flake_resource_input_error_path() {
+        let flake_nix = r#"
+            {
+                outputs = { self, ... }: {
+                    nixops4Deployments = {
+                        example = {
+                            _type = "nixops4Deployment";
+                            deploymentFunction = { resources, resourceProviderSystem }: {
+                                resources = {
+                                    db = {
+                                        _type = "nixops4SimpleResource";
+                                        exe = "__test:dummy";
+                                        inputs = {
+                                            password = { secret = throw "no password configured"; };
+                                        };
+                                    };
+                                };
+                            };
+                        };
+                    };
+                };
+            }
+            "#;
+
+        with_test_driver(flake_nix, |driver, ids, flake_id, responses| {
+            let deployment_id = load_test_deployment(driver, ids, flake_id, "example")?;
+            let resource_id = ids.next();
+            let input_msg_id = ids.next();
+            block_on(
+                driver.perform_request(&EvalRequest::LoadResource(AssignRequest {
+                    assign_to: resource_id,
+                    payload: ResourceRequest {
+                        deployment: deployment_id,
+                        name: "db".to_string(),
+                    },
+                })),
+            )?;
+            block_on(
+                driver.perform_request(&EvalRequest::GetResourceInput(QueryRequest::new(
+                    input_msg_id,
+                    Property {
+                        resource: resource_id,
+                        name: "password".to_string(),
+                    },
+                ))),
+            )?;
+            let r = responses.lock().unwrap();
+            if r.len() != 1 {
+                panic!("expected 1 response, got: {:?}", r);
+            }
+            match &r[0] {
+                EvalResponse::Error(id, msg) => {
+                    assert_eq!(id, &input_msg_id.any());
+                    if !msg
+                        .message()
+                        .starts_with("while evaluating resource `db`.inputs.`password`: ")
+                        || !msg.message().contains("no password configured")
+                    {
+                        panic!("unexpected error message: {}", msg);
+                    }
+                }
+                _ => panic!("expected EvalResponse::Error"),
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_eval_driver_put_resource_output_ack() {
+        let flake_nix = r#"
+            {
+                outputs = { self, ... }: {
+                    nixops4Deployments = {
+                        example = {
+                            _type = "nixops4Deployment";
+                            deploymentFunction = { resources, resourceProviderSystem }: {
+                                resources = {
+                                    a = {
+                                        type = "t";
+                                        provider.types.t.outputs.out = null;
+                                        inputs = { };
+                                    };
+                                    b = {
+                                        type = "t";
+                                        provider.types.t.outputs.out = null;
+                                        inputs = {
+                                            fromA = resources.a.out;
+                                        };
+                                    };
+                                };
+                            };
+                        };
+                    };
+                };
+            }
+            "#;
+
+        with_test_driver(flake_nix, |driver, ids, flake_id, responses| {
+            let deployment_id = load_test_deployment(driver, ids, flake_id, "example")?;
+            let resource_id = ids.next();
+            block_on(
+                driver.perform_request(&EvalRequest::LoadResource(AssignRequest {
+                    assign_to: resource_id,
+                    payload: ResourceRequest {
+                        deployment: deployment_id,
+                        name: "b".to_string(),
+                    },
+                })),
+            )?;
+            let input = Property {
+                resource: resource_id,
+                name: "fromA".to_string(),
+            };
+            let output = NamedProperty {
+                resource: "a".to_string(),
+                name: "out".to_string(),
+            };
+
+            // Before the output is known, the input is a dependency
+            block_on(
+                driver.perform_request(&EvalRequest::GetResourceInput(QueryRequest::new(
+                    ids.next(),
+                    input.clone(),
+                ))),
+            )?;
+            {
+                let mut r = responses.lock().unwrap();
+                match r.as_slice() {
+                    [EvalResponse::QueryResponse(
+                        _,
+                        QueryResponseValue::ResourceInputState((
+                            _,
+                            ResourceInputState::ResourceInputDependency(dep),
+                        )),
+                    )] => {
+                        assert_eq!(dep.dependency, output);
+                    }
+                    _ => panic!("expected a ResourceInputDependency, got: {:?}", r),
+                }
+                r.clear();
+            }
+
+            // The output is acknowledged
+            block_on(driver.perform_request(&EvalRequest::PutResourceOutput(
+                output.clone(),
+                serde_json::json!("hello"),
+            )))?;
+            {
+                let mut r = responses.lock().unwrap();
+                assert_eq!(
+                    r.as_slice(),
+                    &[EvalResponse::ResourceOutputAck(output.clone())]
+                );
+                r.clear();
+            }
+
+            // After the acknowledgement, the input has a value
+            block_on(
+                driver.perform_request(&EvalRequest::GetResourceInput(QueryRequest::new(
+                    ids.next(),
+                    input.clone(),
+                ))),
+            )?;
+            {
+                let r = responses.lock().unwrap();
+                match r.as_slice() {
+                    [EvalResponse::QueryResponse(
+                        _,
+                        QueryResponseValue::ResourceInputState((
+                            _,
+                            ResourceInputState::ResourceInputValue((_, value)),
+                        )),
+                    )] => {
+                        assert_eq!(value, &serde_json::json!("hello"));
+                    }
+                    _ => panic!("expected a ResourceInputValue, got: {:?}", r),
+                }
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_eval_driver_flake_deployment_function_argstr() {
+        let flake_nix = r#"
+            {
+                outputs = { self, ... }: {
+                    nixops4Deployments = {
+                        example = { resourceName, unused ? "default" }: {
+                            _type = "nixops4Deployment";
+                            deploymentFunction = { resources, resourceProviderSystem }: {
+                                resources = {
+                                    ${resourceName} = {
+                                        _type = "nixops4SimpleResource";
+                                        exe = "__test:dummy";
+                                        inputs = { };
+                                    };
+                                };
+                            };
+                        };
+                    };
+                };
+            }
+            "#;
+
+        with_test_driver(flake_nix, |driver, ids, flake_id, responses| {
+            let deployment_id = ids.next();
+            let resources_msg_id = ids.next();
+            block_on(
+                driver.perform_request(&EvalRequest::LoadDeployment(AssignRequest {
+                    assign_to: deployment_id,
+                    payload: DeploymentRequest {
+                        flake: flake_id,
+                        name: "example".to_string(),
+                        args: BTreeMap::from([
+                            (
+                                "resourceName".to_string(),
+                                DeploymentArg::Str("from-argstr".to_string()),
+                            ),
+                            (
+                                "notAnArgument".to_string(),
+                                DeploymentArg::Expr("1 + 1".to_string()),
+                            ),
+                        ]),
+                    },
+                })),
+            )?;
+            block_on(
+                driver.perform_request(&EvalRequest::ListResources(QueryRequest::new(
+                    resources_msg_id,
+                    deployment_id,
+                ))),
+            )?;
+            let r = responses.lock().unwrap();
+            if r.len() != 1 {
+                panic!("expected 1 response, got: {:?}", r);
+            }
+            match &r[0] {
+                EvalResponse::QueryResponse(
+                    _id,
+                    QueryResponseValue::ListResources((id, names)),
+                ) => {
+                    assert_eq!(id, &deployment_id);
+                    assert_eq!(names, &vec!["from-argstr".to_string()]);
+                }
+                _ => panic!("expected EvalResponse::ListResources"),
+            }
+            Ok(())
+        });
+    }
 }
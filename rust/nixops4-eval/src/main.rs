@@ -1,5 +1,5 @@
 use anyhow::Result;
-use nix_expr::eval_state::{self, gc_register_my_thread, EvalState};
+use nix_expr::eval_state::{self, gc_register_my_thread, EvalStateBuilder};
 use nix_store::store::Store;
 use std::process::exit;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
@@ -12,10 +12,22 @@ mod eval;
 fn main() {
     // Be friendly to the user if they try to run this.
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 || args[1] != "<subprocess>" {
+    if args.len() < 2 || args[1] != "<subprocess>" {
         eprintln!("nixops4-eval is not for direct use");
         exit(1);
     }
+    let mut show_trace = false;
+    let mut pure_eval = false;
+    for arg in &args[2..] {
+        match arg.as_str() {
+            "--show-trace" => show_trace = true,
+            "--pure-eval" => pure_eval = true,
+            _ => {
+                eprintln!("nixops4-eval: unknown argument: {}", arg);
+                exit(1);
+            }
+        }
+    }
     handle_err((|| {
         // Ctrl+C in the terminal is sent to the whole process tree.
         // Interruption is handled by the parent process. We will be shut down
@@ -27,7 +39,7 @@ fn main() {
             .enable_all()
             .thread_name("no4-e-tokio")
             .build()?;
-        runtime.block_on(async_main())?;
+        runtime.block_on(async_main(show_trace, pure_eval))?;
         Ok(())
     })())
 }
@@ -42,7 +54,7 @@ fn handle_err(r: Result<()>) {
     }
 }
 
-async fn async_main() -> Result<()> {
+async fn async_main(show_trace: bool, pure_eval: bool) -> Result<()> {
     // Session output handle
     struct Session {
         sender: Sender<nixops4_core::eval_api::EvalResponse>,
@@ -111,6 +123,12 @@ async fn async_main() -> Result<()> {
         Ok(())
     });
 
+    // All evaluation happens on this one thread, with one EvalState.
+    // An EvalState must not be used from multiple threads at once, and
+    // separate EvalStates would not share their evaluation caches, so that
+    // deployments in the same flake would be evaluated redundantly.
+    // Clients should therefore send independent requests without waiting
+    // for the responses, so that the queue never runs dry.
     let local: tokio::task::LocalSet = tokio::task::LocalSet::new();
 
     nix_flake::FlakeSettings::new()?.init_globally()?;
@@ -120,9 +138,11 @@ async fn async_main() -> Result<()> {
         eval_state::init()?;
         let gc_guard = gc_register_my_thread()?;
         let store = Store::open("auto", [])?;
-        let eval_state = EvalState::new(store, [])?;
+        let eval_state = EvalStateBuilder::new(store).pure(pure_eval).build()?;
 
         let mut driver = eval::EvaluationDriver::new(eval_state, Box::new(session));
+        driver.set_show_trace(show_trace)?;
+        driver.set_pure_eval(pure_eval);
         loop {
             while let Ok(request) = high_prio_rx.try_recv() {
                 let ed = span.enter();
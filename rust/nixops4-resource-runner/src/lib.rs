@@ -1,46 +1,272 @@
 use std::{
-    collections::BTreeMap,
-    io::{BufRead, Write},
+    collections::{BTreeMap, VecDeque},
+    io::{BufRead, BufReader, Write},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ExitStatus},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use nixops4_resource::schema::v0::{CreateResourceRequest, CreateResourceResponse};
+use anyhow::{bail, Context, Result};
+use nixops4_resource::framework;
+use nixops4_resource::schema::v0::{
+    CancelNotification, CapabilitiesRequest, CapabilitiesResponse, CreateResourceRequest,
+    CreateResourceResponse, Request, Response, SchemaRequest, SchemaResponse,
+    ValidateResourceRequest, ValidateResourceResponse, ValidationProblem,
+};
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ResourceProviderConfig {
     pub provider_executable: String,
     pub provider_args: Vec<String>,
+    /// Environment variables to set for the provider, over the inherited environment.
+    pub env: BTreeMap<String, String>,
+    /// Start the provider with only the variables in `env`, instead of inheriting
+    /// the environment of nixops4.
+    pub clear_env: bool,
+    /// How long to wait for a newly started provider to respond to its first
+    /// request. `None` waits indefinitely.
+    ///
+    /// With a startup timeout, the first request to a new provider is a
+    /// capabilities request, so that the timeout does not include the work of
+    /// the operation that caused the provider to be started.
+    pub startup_timeout: Option<Duration>,
+    /// Ask the provider to log more details; see [`framework::VERBOSE_ENV`].
+    pub verbose: bool,
+    /// Path prefixes, such as `/nix/store`, that the provider executable must
+    /// be under. `None` allows any executable.
+    pub allowed_executables: Option<Vec<String>>,
+    /// How long to wait for the response to each request. If the provider
+    /// does not respond in time, it is killed. `None` waits indefinitely.
+    pub request_timeout: Option<Duration>,
+}
+
+/// A provider did not respond within the
+/// [`request_timeout`][ResourceProviderConfig::request_timeout].
+#[derive(Debug)]
+pub struct RequestTimeoutError {
+    pub executable: String,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for RequestTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Provider {} did not respond within {} seconds",
+            self.executable,
+            self.timeout.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for RequestTimeoutError {}
+
+/// The wait for a response was interrupted; see
+/// [`ResourceProviderClient::set_interrupt_check`].
+///
+/// The provider process was stopped, so the operation it was performing may
+/// have been left unfinished; for instance, a resource may be partially created.
+#[derive(Debug)]
+pub struct RequestInterruptedError {
+    pub executable: String,
+}
+
+impl std::fmt::Display for RequestInterruptedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Interrupted while waiting for provider {}; it was stopped, \
+             possibly leaving the resource partially created",
+            self.executable
+        )
+    }
+}
+
+impl std::error::Error for RequestInterruptedError {}
+
+/// A provider exited without responding to a request.
+#[derive(Debug)]
+pub struct ProviderExitedError {
+    pub executable: String,
+    pub status: ExitStatus,
+    /// Whether the provider had responded to an earlier request. If not, it
+    /// failed to start.
+    pub started: bool,
+    /// The last lines that the provider wrote to stderr, if it failed to start.
+    pub stderr_tail: Vec<String>,
+}
+
+impl std::fmt::Display for ProviderExitedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.started {
+            write!(
+                f,
+                "Provider {} exited without responding ({})",
+                self.executable, self.status
+            )
+        } else {
+            f.write_str(&startup_message(
+                &self.executable,
+                format!("it exited without responding ({})", self.status),
+                &self.stderr_tail,
+            ))
+        }
+    }
+}
+
+impl std::error::Error for ProviderExitedError {}
+
+/// How often to check for interruption while waiting for a response.
+const INTERRUPT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many lines of a provider's stderr to include in an error when it fails
+/// to start.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// How long an interrupted provider that supports `cancel` may take to stop by
+/// itself, before it is killed.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// A running provider process and its communication channels.
+struct ProviderProcess {
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines read from the provider's stdout by a separate thread, so that we
+    /// can stop waiting for them.
+    responses: Receiver<std::io::Result<String>>,
+    /// Whether the provider has responded to a request yet.
+    started: bool,
+    /// The provider's stderr is relayed to ours by a separate thread, which
+    /// keeps the last [`STDERR_TAIL_LINES`] lines for error messages.
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    stderr_thread: JoinHandle<()>,
+}
+
+impl ProviderProcess {
+    /// The last lines that the provider wrote to stderr, after waiting briefly
+    /// for it to finish writing.
+    fn stderr_tail(&self) -> Vec<String> {
+        let start = Instant::now();
+        while !self.stderr_thread.is_finished() && start.elapsed() < Duration::from_secs(1) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        self.stderr_tail.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Describe why a provider failed to start, including what it wrote to stderr.
+fn startup_message(executable: &str, reason: String, stderr_tail: &[String]) -> String {
+    let mut message = format!("Provider {} failed to start: {}", executable, reason);
+    if !stderr_tail.is_empty() {
+        message.push_str("\nIts last output was:");
+        for line in stderr_tail {
+            message.push_str("\n  ");
+            message.push_str(line);
+        }
+    }
+    message
+}
+
+/// Write a message to the provider, terminated by a newline.
+fn write_line(stdin: &mut ChildStdin, line: &str) -> std::io::Result<()> {
+    stdin.write_all(line.as_bytes())?;
+    stdin.write_all(b"\n")?;
+    stdin.flush()
+}
+
+/// The capabilities of a provider that predates the capabilities operation.
+fn create_only_capabilities() -> CapabilitiesResponse {
+    CapabilitiesResponse {
+        protocol_version: 0,
+        operations: vec!["create".to_string()],
+        resource_types: vec![],
+    }
 }
 
 pub struct ResourceProviderClient {
     provider_config: ResourceProviderConfig,
-    // TODO: maintain a long-lived process
+    /// Started on first use, and kept alive until [`ResourceProviderClient::close`].
+    process: Option<ProviderProcess>,
+    /// Retrieved on first use; see [`ResourceProviderClient::capabilities`].
+    capabilities: Option<CapabilitiesResponse>,
+    /// See [`ResourceProviderClient::set_interrupt_check`].
+    interrupt_check: Option<Box<dyn Fn() -> bool + Send>>,
+    /// See [`ResourceProviderClient::set_deadline`].
+    deadline: Option<Instant>,
 }
 
 impl ResourceProviderClient {
     pub fn new(provider_config: ResourceProviderConfig) -> Self {
-        ResourceProviderClient { provider_config }
+        ResourceProviderClient {
+            provider_config,
+            process: None,
+            capabilities: None,
+            interrupt_check: None,
+            deadline: None,
+        }
     }
 
-    pub fn create(
-        &self,
-        type_: &str,
-        inputs: &BTreeMap<String, Value>,
-    ) -> Result<BTreeMap<String, Value>> {
-        let stdin_str = {
-            let req = CreateResourceRequest {
-                input_properties: inputs.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
-                type_: type_.to_string(),
-            };
-            serde_json::to_string(&req).unwrap()
-        };
+    pub fn config(&self) -> &ResourceProviderConfig {
+        &self.provider_config
+    }
+
+    /// Stop waiting for a response when `check` returns true, such as after
+    /// the user pressed Ctrl+C.
+    ///
+    /// The provider process is then killed, and the request fails with a
+    /// [`RequestInterruptedError`].
+    pub fn set_interrupt_check(&mut self, check: impl Fn() -> bool + Send + 'static) {
+        self.interrupt_check = Some(Box::new(check));
+    }
+
+    /// Fail requests that are not answered by `deadline`, in the same way as
+    /// the [`request_timeout`][ResourceProviderConfig::request_timeout].
+    ///
+    /// Unlike the request timeout, this limits a sequence of requests, such as
+    /// those for applying one resource. `None` removes the deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
 
-        let mut process =
-            std::process::Command::new(self.provider_config.provider_executable.clone())
+    fn process(&mut self) -> Result<&mut ProviderProcess> {
+        if self.process.is_none() {
+            let executable = &self.provider_config.provider_executable;
+            let mut command = match &self.provider_config.allowed_executables {
+                Some(allowed) => {
+                    // Run the file that was checked, even if a symlink on the
+                    // way is changed in the meantime
+                    let mut command =
+                        std::process::Command::new(check_executable_allowed(executable, allowed)?);
+                    command.arg0(executable);
+                    command
+                }
+                None => std::process::Command::new(executable),
+            };
+            if self.provider_config.clear_env {
+                command.env_clear();
+            }
+            let mut child = command
                 .args(self.provider_config.provider_args.clone())
+                .envs(&self.provider_config.env)
                 .stdin(std::process::Stdio::piped())
                 .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::inherit())
+                .stderr(std::process::Stdio::piped())
+                .env(
+                    framework::VERBOSE_ENV,
+                    if self.provider_config.verbose {
+                        "1"
+                    } else {
+                        "0"
+                    },
+                )
                 .spawn()
                 .with_context(|| {
                     format!(
@@ -48,30 +274,211 @@ impl ResourceProviderClient {
                         self.provider_config.provider_executable
                     )
                 })?;
+            let stdin = child.stdin.take().unwrap();
+            let mut stdout = BufReader::new(child.stdout.take().unwrap());
+            let (sender, responses) = mpsc::channel();
+            std::thread::spawn(move || loop {
+                let mut line = String::new();
+                match stdout.read_line(&mut line) {
+                    // EOF; dropping the sender lets the receiver know
+                    Ok(0) => break,
+                    Ok(_) => {
+                        if sender.send(Ok(line)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        break;
+                    }
+                }
+            });
+            let mut stderr = BufReader::new(child.stderr.take().unwrap());
+            let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+            let stderr_thread = {
+                let stderr_tail = stderr_tail.clone();
+                // Drain until EOF, so that the provider doesn't get EPIPE when
+                // it writes something that isn't UTF-8.
+                std::thread::spawn(move || loop {
+                    let mut bytes = Vec::new();
+                    match stderr.read_until(b'\n', &mut bytes) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {}
+                    }
+                    let line = String::from_utf8_lossy(&bytes);
+                    let line = line.trim_end_matches(['\n', '\r']).to_string();
+                    eprintln!("{}", line);
+                    let mut tail = stderr_tail.lock().unwrap();
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                })
+            };
+            self.process = Some(ProviderProcess {
+                child,
+                stdin,
+                responses,
+                started: false,
+                stderr_tail,
+                stderr_thread,
+            });
+        }
+        Ok(self.process.as_mut().unwrap())
+    }
+
+    /// Send a request and wait for its response.
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        self.send(request, request.capabilities.is_some())
+    }
+
+    /// Send a message and wait for the provider's reply.
+    ///
+    /// Set `handshake` for the capabilities request, which must not wait for
+    /// capabilities itself.
+    fn send<R: DeserializeOwned>(
+        &mut self,
+        message: &impl Serialize,
+        handshake: bool,
+    ) -> Result<R> {
+        let stdin_str = serde_json::to_string(message).unwrap();
+
+        let executable = self.provider_config.provider_executable.clone();
+        let startup_timeout = self.provider_config.startup_timeout;
+        let request_timeout = self.provider_config.request_timeout;
+        let is_started = self.process.as_ref().is_some_and(|p| p.started);
+        if startup_timeout.is_some() && !is_started && self.capabilities.is_none() && !handshake {
+            self.capabilities()?;
+        }
+        let process = self.process()?;
 
-        // Get the handles
-        let (response, mut process) = {
-            let child_in = process.stdin.as_mut().unwrap();
-            let child_out = process.stdout.as_mut().unwrap();
-            let mut child_reader = std::io::BufReader::new(child_out);
-
-            // Write the request
-            child_in.write_all(stdin_str.as_bytes()).unwrap();
-            child_in.write_all(b"\n").unwrap();
-            child_in.flush().unwrap();
-
-            // Read the response
-            let response: CreateResourceResponse = {
-                let mut response = String::new();
-                child_reader.read_line(&mut response).unwrap();
-                serde_json::from_str(&response)?
+        // Write the request
+        let written = write_line(&mut process.stdin, &stdin_str);
+        let process = match written {
+            Err(_) if process.started => {
+                // The provider exited after its previous response, as providers
+                // that handle a single request do. Start a fresh one.
+                let mut process = self.process.take().unwrap();
+                let _ = process.child.wait();
+                let process = self.process()?;
+                write_line(&mut process.stdin, &stdin_str).map(|_| process)
+            }
+            written => written.map(|_| process),
+        }
+        .with_context(|| format!("Could not write request to provider {}", executable))?;
+
+        // Read the response
+        let start = Instant::now();
+        let startup_deadline = startup_timeout
+            .filter(|_| !process.started)
+            .map(|timeout| start + timeout);
+        let deadline = [
+            startup_deadline,
+            request_timeout.map(|timeout| start + timeout),
+            self.deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let received = loop {
+            let responses = &self.process.as_ref().unwrap().responses;
+            if self.interrupt_check.is_none() && deadline.is_none() {
+                break responses.recv().ok();
+            }
+            let mut wait = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => INTERRUPT_CHECK_INTERVAL,
             };
-            (response, process)
-            // This closes stdin
+            if self.interrupt_check.is_some() {
+                wait = wait.min(INTERRUPT_CHECK_INTERVAL);
+            }
+            match responses.recv_timeout(wait) {
+                Ok(r) => break Some(r),
+                Err(RecvTimeoutError::Disconnected) => break None,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            if self.interrupt_check.as_ref().is_some_and(|check| check()) {
+                if self.cancel().unwrap_or(false) {
+                    // Give the provider a chance to stop cleanly. Whatever it
+                    // responds, the request has been interrupted.
+                    let _ = self
+                        .process
+                        .as_ref()
+                        .unwrap()
+                        .responses
+                        .recv_timeout(CANCEL_GRACE_PERIOD);
+                }
+                let mut process = self.process.take().unwrap();
+                let _ = process.child.kill();
+                let _ = process.child.wait();
+                return Err(RequestInterruptedError { executable }.into());
+            }
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    let mut process = self.process.take().unwrap();
+                    let _ = process.child.kill();
+                    let _ = process.child.wait();
+                    if startup_deadline == Some(deadline) {
+                        bail!(startup_message(
+                            &executable,
+                            format!(
+                                "it did not respond within {} seconds; \
+                                 consider increasing the provider startup timeout",
+                                (deadline - start).as_secs_f64()
+                            ),
+                            &process.stderr_tail(),
+                        ));
+                    }
+                    return Err(RequestTimeoutError {
+                        executable,
+                        timeout: deadline.saturating_duration_since(start),
+                    }
+                    .into());
+                }
+            }
+        };
+        let response = match received {
+            Some(r) => {
+                r.with_context(|| format!("Could not read response from provider {}", executable))?
+            }
+            None => {
+                // The provider has exited, presumably after reporting an error on stderr.
+                // Reap it, so that a next request starts a fresh process.
+                let mut process = self.process.take().unwrap();
+                let status = process.child.wait()?;
+                let stderr_tail = if process.started {
+                    vec![]
+                } else {
+                    process.stderr_tail()
+                };
+                return Err(ProviderExitedError {
+                    executable,
+                    status,
+                    started: process.started,
+                    stderr_tail,
+                }
+                .into());
+            }
         };
+        self.process.as_mut().unwrap().started = true;
+        serde_json::from_str(&response)
+            .with_context(|| format!("Could not parse response from provider {}", executable))
+    }
 
-        // Wait for the process to finish
-        process.wait()?;
+    pub fn create(
+        &mut self,
+        type_: &str,
+        inputs: &BTreeMap<String, Value>,
+    ) -> Result<BTreeMap<String, Value>> {
+        // Not wrapped in a Request, so that providers from before the other
+        // operations understand it
+        let response: CreateResourceResponse = self.send(
+            &CreateResourceRequest {
+                input_properties: inputs.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+                type_: type_.to_string(),
+            },
+            false,
+        )?;
 
         Ok(response
             .output_properties
@@ -79,4 +486,800 @@ impl ResourceProviderClient {
             .map(|(k, v)| (k.clone(), v.clone()))
             .collect())
     }
+
+    /// Ask the provider to check the inputs of a resource, without creating it.
+    pub fn validate(
+        &mut self,
+        type_: &str,
+        inputs: &BTreeMap<String, Value>,
+    ) -> Result<ValidateResourceResponse> {
+        self.require_operation("validate")?;
+        let response = self.request(&Request {
+            validate: Some(ValidateResourceRequest {
+                input_properties: inputs.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+                type_: type_.to_string(),
+            }),
+            capabilities: None,
+            schema: None,
+            cancel: None,
+        })?;
+        match response.validate {
+            Some(r) => Ok(r),
+            None => bail!(
+                "Provider {} did not respond to the validate request",
+                self.provider_config.provider_executable
+            ),
+        }
+    }
+
+    /// Ask the provider which operations and resource types it supports.
+    ///
+    /// The response is remembered, so only the first call sends a request.
+    ///
+    /// A provider that does not answer the request with its capabilities,
+    /// or exits instead, is assumed to predate the capabilities operation, and
+    /// to support only `create`.
+    pub fn capabilities(&mut self) -> Result<&CapabilitiesResponse> {
+        if self.capabilities.is_none() {
+            let response = self.request(&Request {
+                validate: None,
+                capabilities: Some(CapabilitiesRequest {}),
+                schema: None,
+                cancel: None,
+            });
+            let capabilities = match response {
+                Ok(response) => response.capabilities,
+                Err(e)
+                    if e.downcast_ref::<ProviderExitedError>().is_some()
+                        || e.downcast_ref::<serde_json::Error>().is_some() =>
+                {
+                    None
+                }
+                Err(e) => return Err(e),
+            };
+            self.capabilities = Some(capabilities.unwrap_or_else(create_only_capabilities));
+        }
+        Ok(self.capabilities.as_ref().unwrap())
+    }
+
+    /// Ask the provider for the JSON Schemas of its resource types' properties.
+    pub fn schema(&mut self) -> Result<SchemaResponse> {
+        self.require_operation("schema")?;
+        let response = self.request(&Request {
+            validate: None,
+            capabilities: None,
+            schema: Some(SchemaRequest {}),
+            cancel: None,
+        })?;
+        match response.schema {
+            Some(r) => Ok(r),
+            None => bail!(
+                "Provider {} did not respond to the schema request",
+                self.provider_config.provider_executable
+            ),
+        }
+    }
+
+    /// Check the inputs of a resource against the schema that the provider
+    /// reports for its type, without sending them to the provider.
+    ///
+    /// Returns no problems if the provider does not support the schema
+    /// operation, or does not describe the type.
+    pub fn check_inputs(
+        &mut self,
+        type_: &str,
+        inputs: &BTreeMap<String, Value>,
+    ) -> Result<Vec<ValidationProblem>> {
+        if !self.supports("schema")? {
+            return Ok(vec![]);
+        }
+        let schema = self.schema()?;
+        Ok(schema
+            .resource_types
+            .iter()
+            .find(|t| t.type_ == type_)
+            .map(|t| schema_problems(&t.inputs, inputs))
+            .unwrap_or_default())
+    }
+
+    /// Ask the provider to stop the operation it is performing, if the provider
+    /// is running and has advertised `cancel` in its capabilities.
+    ///
+    /// This is a notification; there is no response. Returns whether it was sent.
+    pub fn cancel(&mut self) -> Result<bool> {
+        let supported = self
+            .capabilities
+            .as_ref()
+            .is_some_and(|c| c.operations.iter().any(|o| o == "cancel"));
+        let Some(process) = self.process.as_mut().filter(|_| supported) else {
+            return Ok(false);
+        };
+        let request = Request {
+            validate: None,
+            capabilities: None,
+            schema: None,
+            cancel: Some(CancelNotification {}),
+        };
+        let line = serde_json::to_string(&request).unwrap() + "\n";
+        process
+            .stdin
+            .write_all(line.as_bytes())
+            .and_then(|_| process.stdin.flush())
+            .with_context(|| {
+                format!(
+                    "Could not send cancel notification to provider {}",
+                    self.provider_config.provider_executable
+                )
+            })?;
+        Ok(true)
+    }
+
+    /// Whether the provider supports `operation`, such as `"validate"`.
+    pub fn supports(&mut self, operation: &str) -> Result<bool> {
+        Ok(self
+            .capabilities()?
+            .operations
+            .iter()
+            .any(|o| o == operation))
+    }
+
+    fn require_operation(&mut self, operation: &str) -> Result<()> {
+        if !self.supports(operation)? {
+            bail!(
+                "Provider {} does not support the {} operation",
+                self.provider_config.provider_executable,
+                operation
+            );
+        }
+        Ok(())
+    }
+
+    /// Close the connection to the provider, and wait for the process to finish.
+    ///
+    /// This is a no-op if the provider process was never started.
+    pub fn close(&mut self) -> Result<()> {
+        if let Some(process) = self.process.take() {
+            let ProviderProcess {
+                mut child,
+                stdin,
+                responses,
+                started: _,
+                stderr_tail: _,
+                stderr_thread: _,
+            } = process;
+            // This closes stdin, signaling the provider to exit
+            drop(stdin);
+            drop(responses);
+            let status = child.wait()?;
+            if !status.success() {
+                bail!(
+                    "Provider {} exited with {}",
+                    self.provider_config.provider_executable,
+                    status
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ResourceProviderClient {
+    fn drop(&mut self) {
+        self.close().map_or_else(
+            |e| eprintln!("error while closing resource provider: {:?}", e),
+            |_| {},
+        );
+    }
+}
+
+/// Check that a provider executable is under one of the `allowed` path prefixes.
+///
+/// The executable must be an absolute path, so that it does not depend on `PATH`.
+/// It is checked after resolving symlinks and `..`, so that a symlink under an
+/// allowed prefix can not run an executable from elsewhere.
+///
+/// Returns the resolved path, which is what should be run.
+fn check_executable_allowed(executable: &str, allowed: &[String]) -> Result<PathBuf> {
+    let path = Path::new(executable);
+    if !path.is_absolute() {
+        bail!(
+            "provider executable not allowed: {}; it must be an absolute path when allowed providers are configured",
+            executable
+        );
+    }
+    let resolved = std::fs::canonicalize(path).with_context(|| {
+        format!(
+            "provider executable not allowed: {}; it could not be resolved",
+            executable
+        )
+    })?;
+    // A prefix may be reached through a symlink itself, like /tmp on macOS
+    let is_allowed = allowed.iter().any(|prefix| {
+        let prefix = std::fs::canonicalize(prefix).unwrap_or_else(|_| prefix.into());
+        resolved.starts_with(prefix)
+    });
+    if !is_allowed {
+        bail!(
+            "provider executable not allowed: {}; allowed: {}",
+            executable,
+            allowed.join(", ")
+        );
+    }
+    Ok(resolved)
+}
+
+/// Check input properties against the JSON Schema of a resource type.
+///
+/// This covers the parts of JSON Schema that help catch mistakes in a
+/// resource definition: unknown and missing properties, and the `type` of
+/// each property. Anything else is left to the provider's `validate`.
+pub fn schema_problems(schema: &Value, inputs: &BTreeMap<String, Value>) -> Vec<ValidationProblem> {
+    let mut problems = vec![];
+    let properties = schema.get("properties").and_then(Value::as_object);
+    if schema.get("additionalProperties") == Some(&Value::Bool(false)) {
+        let known: Vec<&str> = properties
+            .map(|p| p.keys().map(String::as_str).collect())
+            .unwrap_or_default();
+        for name in inputs.keys() {
+            if !known.contains(&name.as_str()) {
+                problems.push(ValidationProblem {
+                    property: Some(name.clone()),
+                    message: format!(
+                        "unknown input property; known properties: {}",
+                        known.join(", ")
+                    ),
+                });
+            }
+        }
+    }
+    if let Some(required) = schema.get("required").and_then(Value::as_array) {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !inputs.contains_key(name) {
+                problems.push(ValidationProblem {
+                    property: Some(name.to_string()),
+                    message: "missing required input property".to_string(),
+                });
+            }
+        }
+    }
+    for (name, value) in inputs {
+        let expected = properties
+            .and_then(|p| p.get(name))
+            .and_then(|p| p.get("type"))
+            .and_then(Value::as_str);
+        if let Some(expected) = expected {
+            if !has_json_type(value, expected) {
+                problems.push(ValidationProblem {
+                    property: Some(name.clone()),
+                    message: format!("expected type {}", expected),
+                });
+            }
+        }
+    }
+    problems
+}
+
+/// Whether `value` is of the JSON Schema `type`. Unknown types are accepted.
+fn has_json_type(value: &Value, type_: &str) -> bool {
+    match type_ {
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_startup_timeout() {
+        // A provider that never gets around to responding
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), "sleep 60".to_string()],
+            startup_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let e = client.create("file", &BTreeMap::new()).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert_eq!(
+            e.to_string(),
+            "Provider sh failed to start: it did not respond within 0.2 seconds; \
+             consider increasing the provider startup timeout"
+        );
+        // The process was killed and reaped; closing is a no-op
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_verbose_env() {
+        // A provider that reports the variable in its outputs
+        let script = r#"read request; printf '{"outputProperties":{"verbose":"%s"}}\n' "$NIXOPS4_PROVIDER_VERBOSE"; read eof; exit 0"#;
+        for (verbose, expected) in [(true, "1"), (false, "0")] {
+            let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+                provider_executable: "sh".to_string(),
+                provider_args: vec!["-c".to_string(), script.to_string()],
+                verbose,
+                ..Default::default()
+            });
+            let outputs = client.create("file", &BTreeMap::new()).unwrap();
+            assert_eq!(outputs.get("verbose"), Some(&Value::from(expected)));
+            client.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_exit_without_responding() {
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), "read request; exit 3".to_string()],
+            startup_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        });
+        let e = client.create("file", &BTreeMap::new()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Provider sh failed to start: it exited without responding (exit status: 3)"
+        );
+    }
+
+    fn file_schema() -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "contents": { "type": "string" },
+            },
+            "required": ["name"],
+            "additionalProperties": false,
+        })
+    }
+
+    #[test]
+    fn test_schema_problems() {
+        let inputs = BTreeMap::from([
+            ("name".to_string(), Value::from(1)),
+            ("conten".to_string(), Value::from("hi")),
+        ]);
+        assert_eq!(
+            schema_problems(&file_schema(), &inputs),
+            vec![
+                ValidationProblem {
+                    property: Some("conten".to_string()),
+                    message: "unknown input property; known properties: contents, name".to_string(),
+                },
+                ValidationProblem {
+                    property: Some("name".to_string()),
+                    message: "expected type string".to_string(),
+                },
+            ]
+        );
+
+        let inputs = BTreeMap::from([("contents".to_string(), Value::from("hi"))]);
+        assert_eq!(
+            schema_problems(&file_schema(), &inputs),
+            vec![ValidationProblem {
+                property: Some("name".to_string()),
+                message: "missing required input property".to_string(),
+            }]
+        );
+
+        let inputs = BTreeMap::from([("name".to_string(), Value::from("a.txt"))]);
+        assert_eq!(schema_problems(&file_schema(), &inputs), vec![]);
+    }
+
+    #[test]
+    fn test_check_inputs() {
+        // Answers the capabilities request, and then only schema requests
+        let script = format!(
+            r#"read request; printf '{{"capabilities":{{"protocolVersion":0,"operations":["create","schema"],"resourceTypes":["file"]}}}}\n'; while read request; do printf '%s\n' '{{"schema":{{"resourceTypes":[{{"type":"file","inputs":{},"outputs":{{}}}}]}}}}'; done"#,
+            file_schema()
+        );
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script],
+            ..Default::default()
+        });
+        let inputs = BTreeMap::from([
+            ("name".to_string(), Value::from("a.txt")),
+            ("conten".to_string(), Value::from("hi")),
+        ]);
+        let problems = client.check_inputs("file", &inputs).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].property.as_deref(), Some("conten"));
+        // Types that the provider doesn't describe are not checked
+        assert_eq!(client.check_inputs("other", &inputs).unwrap(), vec![]);
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_broken_provider() {
+        // A provider that can't even start, like a binary with missing libraries
+        let script = r#"for i in $(seq 1 30); do echo "noise $i" >&2; done
+echo "provider: error while loading shared libraries: libfoo.so.1" >&2
+exit 127"#;
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script.to_string()],
+            startup_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        });
+        let e = client.create("file", &BTreeMap::new()).unwrap_err();
+        let expected = std::iter::once(
+            "Provider sh failed to start: it exited without responding (exit status: 127)\n\
+             Its last output was:"
+                .to_string(),
+        )
+        .chain((12..=30).map(|i| format!("  noise {}", i)))
+        .chain(std::iter::once(
+            "  provider: error while loading shared libraries: libfoo.so.1".to_string(),
+        ))
+        .collect::<Vec<_>>()
+        .join("\n");
+        assert_eq!(e.to_string(), expected);
+    }
+
+    #[test]
+    fn test_stderr_not_utf8() {
+        // Relaying stderr must go on after such a line; otherwise the next
+        // write to stderr would kill the provider with SIGPIPE
+        let script = r#"while read request; do printf 'bad \377 byte\n' >&2; case "$request" in *capabilities*) printf '{"capabilities":{"protocolVersion":0,"operations":["create"],"resourceTypes":["file"]}}\n';; *) printf '{"outputProperties":{}}\n';; esac; done; exit 0"#;
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script.to_string()],
+            ..Default::default()
+        });
+        for _ in 0..3 {
+            client.create("file", &BTreeMap::new()).unwrap();
+        }
+        let tail = client.process.as_ref().unwrap().stderr_tail.clone();
+        client.close().unwrap();
+        // The relay has seen all output after the provider exits, but may still be relaying it
+        let start = Instant::now();
+        while tail.lock().unwrap().len() < 3 && start.elapsed() < Duration::from_secs(10) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            Vec::from(tail.lock().unwrap().clone()),
+            vec!["bad \u{FFFD} byte"; 3]
+        );
+    }
+
+    #[test]
+    fn test_deadline() {
+        // Each response comes well within the request timeout, but together
+        // they take longer than the deadline
+        let script = r#"while read request; do sleep 0.6; printf '{"capabilities":{"protocolVersion":0,"operations":["create"],"resourceTypes":["file"]}}\n'; done"#;
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script.to_string()],
+            request_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        });
+        client.set_deadline(Some(Instant::now() + Duration::from_secs(1)));
+        client.capabilities().unwrap();
+        client.capabilities = None;
+        let start = Instant::now();
+        let e = client.capabilities().unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert!(e.downcast_ref::<RequestTimeoutError>().is_some(), "{}", e);
+        // The process was killed and reaped; closing is a no-op
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_capabilities_exit() {
+        // A provider from before the capabilities operation, which only
+        // understands bare create requests, and exits on anything else
+        let script = r#"while read request; do case "$request" in '{"inputProperties":{"name":"a"},"type":"file"}') printf '{"outputProperties":{"created":true}}\n';; *) exit 1;; esac; done"#;
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script.to_string()],
+            ..Default::default()
+        });
+        assert!(!client.supports("validate").unwrap());
+        assert!(client.supports("create").unwrap());
+        let inputs = BTreeMap::from([("name".to_string(), Value::from("a"))]);
+        let outputs = client.create("file", &inputs).unwrap();
+        assert_eq!(outputs.get("created"), Some(&Value::from(true)));
+        // The same process handles more create requests
+        let outputs = client.create("file", &inputs).unwrap();
+        assert_eq!(outputs.get("created"), Some(&Value::from(true)));
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_one_request_per_process() {
+        // A provider that handles a single request, and exits after responding
+        let script = r#"read request; exec 0<&-; case "$request" in *capabilities*) printf '{"capabilities":{"protocolVersion":0,"operations":["create"],"resourceTypes":["file"]}}\n';; *) printf '{"outputProperties":{}}\n';; esac"#;
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script.to_string()],
+            ..Default::default()
+        });
+        assert!(client.supports("create").unwrap());
+        assert!(client.create("file", &BTreeMap::new()).unwrap().is_empty());
+        assert!(client.create("file", &BTreeMap::new()).unwrap().is_empty());
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_capabilities_unknown_response() {
+        // A provider that answers every request as if it were a create request
+        let script = r#"while read request; do printf '{"outputProperties":{}}\n'; done"#;
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script.to_string()],
+            ..Default::default()
+        });
+        assert_eq!(client.capabilities().unwrap(), &create_only_capabilities());
+        assert!(client.create("file", &BTreeMap::new()).unwrap().is_empty());
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_startup_timeout_handshake() {
+        // Starts quickly, but takes longer than the startup timeout to create
+        let script = r#"read request; printf '{"capabilities":{"protocolVersion":0,"operations":["create"],"resourceTypes":["file"]}}\n'; read request; sleep 1; printf '{"outputProperties":{}}\n'; read eof; exit 0"#;
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script.to_string()],
+            startup_timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        });
+        assert!(client.create("file", &BTreeMap::new()).unwrap().is_empty());
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_exit_after_start() {
+        // Once a provider has responded, an exit is not a startup problem
+        let script = r#"read request; printf '{"capabilities":{"protocolVersion":0,"operations":["create"],"resourceTypes":[]}}\n'; read request; echo "out of memory" >&2; exit 3"#;
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script.to_string()],
+            ..Default::default()
+        });
+        assert!(client.supports("create").unwrap());
+        let e = client.create("file", &BTreeMap::new()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Provider sh exited without responding (exit status: 3)"
+        );
+    }
+
+    #[test]
+    fn test_provider_env() {
+        // Reports $GREETING and whether $HOME is set, as its resource types
+        let script = r#"read request; printf '{"capabilities":{"protocolVersion":0,"operations":["create"],"resourceTypes":["%s","%s"]}}\n' "$GREETING" "${HOME+home}""#;
+        let env_types = |clear_env: bool| {
+            let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+                provider_executable: "/bin/sh".to_string(),
+                provider_args: vec!["-c".to_string(), script.to_string()],
+                env: BTreeMap::from([("GREETING".to_string(), "hello".to_string())]),
+                clear_env,
+                ..Default::default()
+            });
+            let types = client.capabilities().unwrap().resource_types.clone();
+            client.close().unwrap();
+            types
+        };
+        assert!(std::env::var_os("HOME").is_some());
+        assert_eq!(env_types(false), vec!["hello", "home"]);
+        assert_eq!(env_types(true), vec!["hello", ""]);
+    }
+
+    /// A directory with a `provider` copy of `sh`, to use as an allowed prefix.
+    fn allowed_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nixops4-resource-runner-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(
+            std::fs::canonicalize("/bin/sh").unwrap(),
+            dir.join("provider"),
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_allowed_executable() {
+        let dir = allowed_dir("allowed");
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: dir.join("provider").to_str().unwrap().to_string(),
+            provider_args: vec!["-c".to_string(), "read request; exit 3".to_string()],
+            startup_timeout: Some(Duration::from_secs(30)),
+            allowed_executables: Some(vec![dir.to_str().unwrap().to_string()]),
+            ..Default::default()
+        });
+        // It was started
+        let e = client.create("file", &BTreeMap::new()).unwrap_err();
+        assert!(e.to_string().contains("exited without responding"), "{}", e);
+
+        // A symlink to an allowed executable is allowed
+        let outside = dir.with_extension("link");
+        let _ = std::fs::remove_file(&outside);
+        std::os::unix::fs::symlink(dir.join("provider"), &outside).unwrap();
+        // The file it points to is what gets run
+        let resolved = check_executable_allowed(
+            outside.to_str().unwrap(),
+            &[dir.to_str().unwrap().to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            resolved,
+            std::fs::canonicalize(dir.join("provider")).unwrap()
+        );
+        std::fs::remove_file(&outside).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disallowed_executable() {
+        let dir = allowed_dir("disallowed");
+        let allowed = vec![dir.to_str().unwrap().to_string()];
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "/bin/sh".to_string(),
+            provider_args: vec!["-c".to_string(), "exit 3".to_string()],
+            startup_timeout: Some(Duration::from_secs(30)),
+            allowed_executables: Some(allowed.clone()),
+            ..Default::default()
+        });
+        let e = client.create("file", &BTreeMap::new()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "provider executable not allowed: /bin/sh; allowed: {}",
+                dir.display()
+            )
+        );
+
+        // Not fooled by `..`
+        let escape = format!("{}/../../../../../../bin/sh", dir.display());
+        let e = check_executable_allowed(&escape, &allowed).unwrap_err();
+        assert!(e.to_string().starts_with("provider executable not allowed"));
+
+        // Would be looked up in PATH
+        let e = check_executable_allowed("sh", &allowed).unwrap_err();
+        assert!(e.to_string().contains("must be an absolute path"));
+
+        // Not fooled by a symlink in the allowed directory
+        let link = dir.join("link");
+        std::os::unix::fs::symlink("/bin/sh", &link).unwrap();
+        let e = check_executable_allowed(link.to_str().unwrap(), &allowed).unwrap_err();
+        assert!(e.to_string().starts_with("provider executable not allowed"));
+
+        // Doesn't exist
+        let missing = dir.join("missing");
+        let e = check_executable_allowed(missing.to_str().unwrap(), &allowed).unwrap_err();
+        assert!(e.to_string().contains("could not be resolved"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        // A provider that starts, but then hangs
+        let script = r#"read request; printf '{"capabilities":{"protocolVersion":0,"operations":["create"],"resourceTypes":["file"]}}\n'; read request; exec sleep 60"#;
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script.to_string()],
+            request_timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        });
+        assert!(client.supports("create").unwrap());
+        let start = Instant::now();
+        let e = client.create("file", &BTreeMap::new()).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(30));
+        let timeout = e.downcast_ref::<RequestTimeoutError>().unwrap();
+        assert_eq!(timeout.timeout, Duration::from_millis(500));
+        assert_eq!(
+            e.to_string(),
+            "Provider sh did not respond within 0.5 seconds"
+        );
+        // The process was killed and reaped; closing is a no-op
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_interrupt() {
+        // A provider that takes a long time to create anything
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), "exec sleep 60".to_string()],
+            ..Default::default()
+        });
+        let interrupted = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        {
+            let interrupted = interrupted.clone();
+            client
+                .set_interrupt_check(move || interrupted.load(std::sync::atomic::Ordering::SeqCst));
+        }
+        let start = Instant::now();
+        let e = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(200));
+                interrupted.store(true, std::sync::atomic::Ordering::SeqCst);
+            });
+            client.create("file", &BTreeMap::new()).unwrap_err()
+        });
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert!(e.downcast_ref::<RequestInterruptedError>().is_some());
+        assert_eq!(
+            e.to_string(),
+            "Interrupted while waiting for provider sh; it was stopped, \
+             possibly leaving the resource partially created"
+        );
+        // The process was killed and reaped; closing is a no-op
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_interrupt_without_cancel() {
+        // A provider that does not support cancel, such as one built with the
+        // framework without overriding `cancel`, and records what it receives
+        let dir =
+            std::env::temp_dir().join(format!("nixops4-runner-no-cancel-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("log");
+        let script = format!(
+            r#"read request; printf '{{"capabilities":{{"protocolVersion":0,"operations":["capabilities","create","validate","schema"],"resourceTypes":["file"]}}}}\n'; read request; while read notification; do printf '%s' "$notification" >> {}; done; exec sleep 60"#,
+            log.display()
+        );
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script],
+            ..Default::default()
+        });
+        assert!(!client.supports("cancel").unwrap());
+        client.set_interrupt_check(|| true);
+        let start = Instant::now();
+        let e = client.create("file", &BTreeMap::new()).unwrap_err();
+        assert!(e.downcast_ref::<RequestInterruptedError>().is_some());
+        // Killed right away, without waiting for the grace period
+        assert!(start.elapsed() < CANCEL_GRACE_PERIOD);
+        assert!(!log.exists());
+        client.close().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interrupt_sends_cancel() {
+        // A provider that supports cancel, and records what it receives while creating
+        let dir =
+            std::env::temp_dir().join(format!("nixops4-runner-cancel-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("log");
+        let script = format!(
+            r#"read request; printf '{{"capabilities":{{"protocolVersion":0,"operations":["create","cancel"],"resourceTypes":["file"]}}}}\n'; read request; read notification; printf '%s' "$notification" > {}; exit 1"#,
+            log.display()
+        );
+        let mut client = ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: "sh".to_string(),
+            provider_args: vec!["-c".to_string(), script],
+            ..Default::default()
+        });
+        assert!(client.supports("cancel").unwrap());
+        client.set_interrupt_check(|| true);
+        let start = Instant::now();
+        let e = client.create("file", &BTreeMap::new()).unwrap_err();
+        assert!(e.downcast_ref::<RequestInterruptedError>().is_some());
+        // The provider exited by itself, well before the grace period ran out
+        assert!(start.elapsed() < CANCEL_GRACE_PERIOD);
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), r#"{"cancel":{}}"#);
+        client.close().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
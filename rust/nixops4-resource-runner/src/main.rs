@@ -5,6 +5,7 @@ use core::str;
 use nixops4_resource_runner::{ResourceProviderClient, ResourceProviderConfig};
 use serde_json::Value;
 use std::collections::BTreeMap;
+use std::time::Duration;
 
 /// The nixops4-resource-runner executable
 ///
@@ -15,69 +16,80 @@ fn main() -> Result<()> {
 
     match &args.command {
         Commands::Create {
-            provider_exe,
-            resource_type,
-            input_properties_json,
-            input_property_json,
-            input_property_str,
+            resource,
+            validate: _,
+            no_validate,
         } => {
-            // NOTE (loss of ordering):
-            //
-            // clap_derive appears incapable of preserving the order of flags,
-            // as it rejects a Vec of enums that would allow for this. This
-            // means that we can't tell which input property was specified last,
-            // and so we can't make later inputs override earlier ones, as we
-            // would like to do, mirroring Nix's `//` operator. Eventually this
-            // may be supported, so we carve out this possibility by rejecting
-            // duplicate inputs. Otherwise, this improvement would be a breaking
-            // change.
-
-            // Mutable map that is used for gathering all input properties.
-            let mut inputs = match input_properties_json {
-                Some(json_string) => {
-                    serde_json::from_str::<BTreeMap<String, Value>>(json_string.as_str())
-                        .with_context(|| "failed to parse value of --inputs-json")?
-                }
-                None => BTreeMap::new(),
-            };
-
-            for pair in input_property_json.chunks(2) {
-                assert!(pair.len() == 2);
-                let k = &pair[0];
-                let v = &pair[1];
-                if inputs.contains_key(k) {
-                    // No overriding; see note "loss of ordering"
-                    eprintln!("error: duplicate input: {}", k);
+            let inputs = resource.inputs()?;
+            let mut provider = resource.provider();
+
+            if !no_validate {
+                let problems = provider
+                    .check_inputs(&resource.resource_type, &inputs)
+                    .with_context(|| "failed to check resource inputs against the schema")?;
+                if !problems.is_empty() {
+                    eprintln!("error: the resource inputs do not match the provider's schema:");
+                    for p in &problems {
+                        match &p.property {
+                            Some(property) => eprintln!("  - {}: {}", property, p.message),
+                            None => eprintln!("  - {}", p.message),
+                        }
+                    }
+                    provider.close()?;
                     std::process::exit(1);
                 }
-                inputs.insert(
-                    k.clone(),
-                    serde_json::from_str(v.as_str())
-                        .with_context(|| format!("failed to parse JSON value for input: {}", k))?,
-                );
             }
-            for pair in input_property_str.chunks(2) {
-                assert!(pair.len() == 2);
-                let k = &pair[0];
-                let v = &pair[1];
-                if inputs.contains_key(k) {
-                    // No overriding; see note "loss of ordering"
-                    eprintln!("error: duplicate input: {}", k);
-                    std::process::exit(1);
-                }
-                inputs.insert(k.clone(), serde_json::Value::String(v.clone()));
+
+            let response = provider
+                .create(&resource.resource_type, &inputs)
+                .with_context(|| "failed to create resource")?;
+            provider.close()?;
+
+            println!("{}", serde_json::to_string_pretty(&response)?);
+        }
+        Commands::Validate { resource } => {
+            let inputs = resource.inputs()?;
+            let mut provider = resource.provider();
+
+            let response = provider
+                .validate(&resource.resource_type, &inputs)
+                .with_context(|| "failed to validate resource")?;
+            provider.close()?;
+
+            println!("{}", serde_json::to_string_pretty(&response)?);
+            if !response.problems.is_empty() {
+                eprintln!("error: the resource inputs are invalid");
+                std::process::exit(1);
             }
+        }
+        Commands::Capabilities { provider_exe } => {
+            let mut provider = ResourceProviderClient::new(ResourceProviderConfig {
+                provider_executable: provider_exe.clone(),
+                provider_args: vec![],
+                ..Default::default()
+            });
 
-            let provider = ResourceProviderClient::new(ResourceProviderConfig {
+            let capabilities = provider
+                .capabilities()
+                .with_context(|| "failed to query provider capabilities")?
+                .clone();
+            provider.close()?;
+
+            println!("{}", serde_json::to_string_pretty(&capabilities)?);
+        }
+        Commands::Schema { provider_exe } => {
+            let mut provider = ResourceProviderClient::new(ResourceProviderConfig {
                 provider_executable: provider_exe.clone(),
                 provider_args: vec![],
+                ..Default::default()
             });
 
-            let response = provider
-                .create(resource_type, &inputs)
-                .with_context(|| "failed to create resource")?;
+            let schema = provider
+                .schema()
+                .with_context(|| "failed to query provider schema")?;
+            provider.close()?;
 
-            println!("{}", serde_json::to_string_pretty(&response)?);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
         }
         Commands::GenerateMan => {
             let cmd = Args::command();
@@ -119,30 +131,39 @@ struct Args {
 enum Commands {
     /// Create a resource
     Create {
+        #[command(flatten)]
+        resource: ResourceArgs,
+
+        /// Check the inputs against the provider's schema before creating the resource; this is the default
+        #[arg(long, default_value_t = false)]
+        validate: bool,
+
+        /// Send the inputs to the provider without checking them against its schema
+        #[arg(long, default_value_t = false, conflicts_with = "validate")]
+        no_validate: bool,
+    },
+
+    /// Check the inputs of a resource, without creating it
+    ///
+    /// Prints the problems reported by the provider, and exits with a non-zero
+    /// status if there are any.
+    Validate {
+        #[command(flatten)]
+        resource: ResourceArgs,
+    },
+
+    /// Show the operations and resource types that a provider supports
+    Capabilities {
         /// The executable that implements the resource operations
         #[arg(long)]
         provider_exe: String,
+    },
 
-        /// The type of resource to create: an identifier recognized by the resource provider
-        #[arg(long("type"))]
-        resource_type: String,
-
-        /// The (whole) JSON input properties for the resource
-        ///
-        /// This is a JSON object with the values needed to create the resource.
-        /// The structure of this object is defined by the resource provider behavior.
-        #[arg(long("inputs-json"))]
-        input_properties_json: Option<String>,
-
-        /// An individual input property for the resource, in JSON format
-        #[arg(long("input-json"),short('j'),number_of_values = 2, value_names = &["NAME", "JSON"])]
-        input_property_json: Vec<String>,
-
-        /// An individual input property for the resource, as a raw string.
-        ///
-        /// This is equivalent to `--input-json NAME JSON` if JSON is the JSON string formatting of STR.
-        #[arg(long("input-str"),short('s'),number_of_values = 2, value_names = &["NAME", "STR"])]
-        input_property_str: Vec<String>,
+    /// Show the JSON Schemas of the inputs and outputs of a provider's resource types
+    Schema {
+        /// The executable that implements the resource operations
+        #[arg(long)]
+        provider_exe: String,
     },
 
     /// Generate markdown documentation for nixops4-resource-runner
@@ -161,3 +182,102 @@ enum Commands {
         shell: clap_complete::Shell,
     },
 }
+
+#[derive(clap::Args, Debug)]
+struct ResourceArgs {
+    /// The executable that implements the resource operations
+    #[arg(long)]
+    provider_exe: String,
+
+    /// Fail if the provider does not respond within SECONDS of starting
+    #[arg(long, value_name = "SECONDS")]
+    provider_startup_timeout: Option<u64>,
+
+    /// Ask the provider to log more details
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// The type of resource: an identifier recognized by the resource provider
+    #[arg(long("type"))]
+    resource_type: String,
+
+    /// The (whole) JSON input properties for the resource
+    ///
+    /// This is a JSON object with the values needed to create the resource.
+    /// The structure of this object is defined by the resource provider behavior.
+    #[arg(long("inputs-json"))]
+    input_properties_json: Option<String>,
+
+    /// An individual input property for the resource, in JSON format
+    #[arg(long("input-json"),short('j'),number_of_values = 2, value_names = &["NAME", "JSON"])]
+    input_property_json: Vec<String>,
+
+    /// An individual input property for the resource, as a raw string.
+    ///
+    /// This is equivalent to `--input-json NAME JSON` if JSON is the JSON string formatting of STR.
+    #[arg(long("input-str"),short('s'),number_of_values = 2, value_names = &["NAME", "STR"])]
+    input_property_str: Vec<String>,
+}
+
+impl ResourceArgs {
+    fn provider(&self) -> ResourceProviderClient {
+        ResourceProviderClient::new(ResourceProviderConfig {
+            provider_executable: self.provider_exe.clone(),
+            provider_args: vec![],
+            startup_timeout: self.provider_startup_timeout.map(Duration::from_secs),
+            verbose: self.verbose,
+            ..Default::default()
+        })
+    }
+
+    /// Gather the input properties from the command line.
+    fn inputs(&self) -> Result<BTreeMap<String, Value>> {
+        // NOTE (loss of ordering):
+        //
+        // clap_derive appears incapable of preserving the order of flags,
+        // as it rejects a Vec of enums that would allow for this. This
+        // means that we can't tell which input property was specified last,
+        // and so we can't make later inputs override earlier ones, as we
+        // would like to do, mirroring Nix's `//` operator. Eventually this
+        // may be supported, so we carve out this possibility by rejecting
+        // duplicate inputs. Otherwise, this improvement would be a breaking
+        // change.
+
+        // Mutable map that is used for gathering all input properties.
+        let mut inputs = match &self.input_properties_json {
+            Some(json_string) => {
+                serde_json::from_str::<BTreeMap<String, Value>>(json_string.as_str())
+                    .with_context(|| "failed to parse value of --inputs-json")?
+            }
+            None => BTreeMap::new(),
+        };
+
+        for pair in self.input_property_json.chunks(2) {
+            assert!(pair.len() == 2);
+            let k = &pair[0];
+            let v = &pair[1];
+            if inputs.contains_key(k) {
+                // No overriding; see note "loss of ordering"
+                eprintln!("error: duplicate input: {}", k);
+                std::process::exit(1);
+            }
+            inputs.insert(
+                k.clone(),
+                serde_json::from_str(v.as_str())
+                    .with_context(|| format!("failed to parse JSON value for input: {}", k))?,
+            );
+        }
+        for pair in self.input_property_str.chunks(2) {
+            assert!(pair.len() == 2);
+            let k = &pair[0];
+            let v = &pair[1];
+            if inputs.contains_key(k) {
+                // No overriding; see note "loss of ordering"
+                eprintln!("error: duplicate input: {}", k);
+                std::process::exit(1);
+            }
+            inputs.insert(k.clone(), serde_json::Value::String(v.clone()));
+        }
+        Ok(inputs)
+    }
+}
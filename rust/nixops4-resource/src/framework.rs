@@ -1,49 +1,275 @@
 use std::{
-    io::{BufRead, BufReader},
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
     os::fd::{AsRawFd, FromRawFd},
+    sync::{mpsc, Arc},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use nix::unistd::{dup, dup2};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
 
-use crate::schema::v0::{CreateResourceRequest, CreateResourceResponse};
+use crate::schema::v0::{
+    CapabilitiesResponse, CreateResourceRequest, CreateResourceResponse, Request,
+    ResourceTypeSchema, Response, SchemaResponse, ValidateResourceRequest,
+    ValidateResourceResponse,
+};
+
+/// The version of the resource provider protocol that this framework implements.
+pub const PROTOCOL_VERSION: i64 = 0;
+
+/// Environment variable that nixops4 sets to `1` when the provider should log
+/// more details, for instance because `--verbose` was passed.
+pub const VERBOSE_ENV: &str = "NIXOPS4_PROVIDER_VERBOSE";
+
+/// Whether the provider was asked to log more details; see [`VERBOSE_ENV`].
+pub fn is_verbose() -> bool {
+    std::env::var(VERBOSE_ENV).is_ok_and(|v| v == "1")
+}
+
+/// The operations that this framework handles, as advertised in the capabilities response.
+///
+/// `cancel` is added when the provider supports it; see [`ResourceProvider::supports_cancel`].
+const OPERATIONS: &[&str] = &["capabilities", "create", "validate", "schema"];
 
 pub trait ResourceProvider {
     fn create(&self, request: CreateResourceRequest) -> Result<CreateResourceResponse>;
+
+    /// Check the input properties of a resource, without performing any operation.
+    ///
+    /// The default implementation reports no problems.
+    fn validate(&self, _request: ValidateResourceRequest) -> Result<ValidateResourceResponse> {
+        Ok(ValidateResourceResponse { problems: vec![] })
+    }
+
+    /// The resource types that this provider implements, for the capabilities response.
+    ///
+    /// The default implementation does not advertise any types.
+    fn resource_types(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// The JSON Schemas of the input and output properties of the resource
+    /// types, for the schema response.
+    ///
+    /// The default implementation does not describe any types.
+    fn resource_type_schemas(&self) -> Vec<ResourceTypeSchema> {
+        vec![]
+    }
+
+    /// Called when nixops4 asks to stop the operation in progress, for instance
+    /// because the user pressed Ctrl+C.
+    ///
+    /// This is called from a separate thread, while another method may be
+    /// running. A long running operation may use it to stop early, typically
+    /// by returning an error. The default implementation does nothing, letting
+    /// the operation run to completion.
+    fn cancel(&self) {}
+
+    /// Whether the provider implements [`ResourceProvider::cancel`], so that
+    /// `cancel` is advertised in the capabilities response.
+    ///
+    /// Otherwise nixops4 stops the provider process right away when
+    /// interrupted, instead of waiting for the operation to stop.
+    fn supports_cancel(&self) -> bool {
+        false
+    }
     // TODO:
-    // fn check(&self) -> Result<()>;
     // fn destroy(&self) -> Result<()>;
     // fn update(&self) -> Result<()>;
 }
 
-pub fn run_main(provider: impl ResourceProvider) {
+pub fn run_main(provider: impl ResourceProvider + Send + Sync + 'static) {
     let pipe = {
         let pipe = init_stdio();
         pipe_fds_to_files(pipe)
     };
 
-    // Read the request from the input
+    serve(Arc::new(provider), BufReader::new(pipe.in_), pipe.out).unwrap_or_exit();
+}
 
-    let mut in_ = BufReader::new(pipe.in_);
+/// Serve requests until nixops4 closes our input. A single provider process
+/// may be asked to operate on many resources.
+///
+/// Input is read by a separate thread, so that a cancel notification can be
+/// delivered while a request is being handled.
+fn serve<P: ResourceProvider + Send + Sync + 'static>(
+    provider: Arc<P>,
+    mut in_: impl BufRead + Send + 'static,
+    mut out: impl Write,
+) -> Result<()> {
+    let verbose = is_verbose();
+    let (sender, requests) = mpsc::channel();
+    {
+        let provider = provider.clone();
+        std::thread::spawn(move || loop {
+            // Read the request from the input
+            let request: Result<Message> = {
+                let mut line = String::new();
+                match in_.read_line(&mut line) {
+                    // End of input; nixops4 is done with us
+                    Ok(0) => break,
+                    Ok(_) => parse_message(&line),
+                    Err(e) => Err(e).with_context(|| "Could not read line for request message"),
+                }
+            };
+            if let Ok(Message::Request(request)) = &request {
+                if is_cancel(request) {
+                    if verbose {
+                        eprintln!("Received cancel notification");
+                    }
+                    provider.cancel();
+                    continue;
+                }
+            }
+            let is_err = request.is_err();
+            if sender.send(request).is_err() || is_err {
+                break;
+            }
+        });
+    }
 
-    let request = {
-        let mut line = String::new();
-        in_.read_line(&mut line)
-            .with_context(|| "Could not read line for request message")
-            .unwrap_or_exit();
-        serde_json::from_str(&line)
+    for request in requests {
+        match request? {
+            Message::Create(create) => {
+                if verbose {
+                    eprintln!("Received request: create {}", create.type_);
+                }
+                let resp = provider
+                    .create(create)
+                    .with_context(|| "Could not create resource")?;
+                write_message(&mut out, &resp)?;
+            }
+            Message::Request(request) => {
+                if verbose {
+                    eprintln!("Received request: {}", describe_request(&request));
+                }
+                let resp = handle_request(provider.as_ref(), request)?;
+                write_message(&mut out, &resp)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A message from nixops4.
+enum Message {
+    /// Create requests are not wrapped in a [`Request`], as in the first
+    /// version of the protocol, so that providers written against it keep
+    /// working.
+    Create(CreateResourceRequest),
+    Request(Request),
+}
+
+/// Parse a line of input. Only a bare create request has a `type` property.
+fn parse_message(line: &str) -> Result<Message> {
+    let value: Value =
+        serde_json::from_str(line).with_context(|| "Could not parse request message")?;
+    if value.get("type").is_some() {
+        serde_json::from_value(value)
+            .map(Message::Create)
+            .with_context(|| "Could not parse create request")
+    } else {
+        serde_json::from_value(value)
+            .map(Message::Request)
             .with_context(|| "Could not parse request message")
-            .unwrap_or_exit()
+    }
+}
+
+fn write_message(mut out: impl Write, message: &impl Serialize) -> Result<()> {
+    serde_json::to_writer(&mut out, message)?;
+    out.write_all(b"\n")?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Whether the message is a cancel notification, which does not get a response.
+fn is_cancel(request: &Request) -> bool {
+    request.cancel.is_some()
+        && request.validate.is_none()
+        && request.capabilities.is_none()
+        && request.schema.is_none()
+}
+
+/// A short description of a request, for logging.
+fn describe_request(request: &Request) -> String {
+    if let Some(validate) = &request.validate {
+        format!("validate {}", validate.type_)
+    } else if request.capabilities.is_some() {
+        "capabilities".to_string()
+    } else if request.schema.is_some() {
+        "schema".to_string()
+    } else {
+        "unknown".to_string()
+    }
+}
+
+fn handle_request(provider: &impl ResourceProvider, request: Request) -> Result<Response> {
+    let operations = [
+        request.validate.is_some(),
+        request.capabilities.is_some(),
+        request.schema.is_some(),
+        request.cancel.is_some(),
+    ];
+    if operations.iter().filter(|x| **x).count() != 1 {
+        bail!("Request message must contain exactly one operation");
+    }
+    if request.cancel.is_some() {
+        bail!("A cancel notification does not have a response");
+    }
+    let mut response = Response {
+        validate: None,
+        capabilities: None,
+        schema: None,
     };
+    if let Some(validate) = request.validate {
+        response.validate = Some(
+            provider
+                .validate(validate)
+                .with_context(|| "Could not validate resource")?,
+        );
+    }
+    if request.capabilities.is_some() {
+        response.capabilities = Some(CapabilitiesResponse {
+            protocol_version: PROTOCOL_VERSION,
+            operations: OPERATIONS
+                .iter()
+                .copied()
+                .chain(provider.supports_cancel().then_some("cancel"))
+                .map(|s| s.to_string())
+                .collect(),
+            resource_types: provider.resource_types(),
+        });
+    }
+    if request.schema.is_some() {
+        response.schema = Some(SchemaResponse {
+            resource_types: provider.resource_type_schemas(),
+        });
+    }
+    Ok(response)
+}
 
-    // Call the provider
-    let resp = provider
-        .create(request)
-        .with_context(|| "Could not create resource")
-        .unwrap_or_exit();
+/// Deserialize the input properties of a request into a provider-defined type.
+pub fn parse_inputs<T: DeserializeOwned>(input_properties: &BTreeMap<String, Value>) -> Result<T> {
+    serde_json::from_value(Value::Object(
+        input_properties
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+    ))
+    .with_context(|| "Could not deserialize input properties")
+}
 
-    // Write the response to the output
-    serde_json::to_writer(pipe.out, &resp).unwrap();
+/// Serialize a provider-defined type into output properties.
+///
+/// The type must serialize to a JSON object.
+pub fn to_outputs<T: Serialize>(outputs: T) -> Result<BTreeMap<String, Value>> {
+    let value = serde_json::to_value(outputs).with_context(|| "Could not serialize outputs")?;
+    match value {
+        Value::Object(o) => Ok(o.into_iter().collect()),
+        _ => bail!("Expected object as output"),
+    }
 }
 
 /// A pair of `T` values: one for input and one for output.
@@ -114,3 +340,190 @@ impl<T> NixOps4MainError<Result<T>> for Result<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    struct Example {
+        name: String,
+        count: u32,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_parse_inputs_to_outputs_round_trip() {
+        let example = Example {
+            name: "hello".to_string(),
+            count: 3,
+            tags: vec!["a".to_string(), "b".to_string()],
+        };
+        let props = to_outputs(&example).unwrap();
+        assert_eq!(props.get("name"), Some(&Value::String("hello".to_string())));
+        let parsed: Example = parse_inputs(&props).unwrap();
+        assert_eq!(parsed, example);
+    }
+
+    #[test]
+    fn test_parse_inputs_missing_field() {
+        let props = BTreeMap::from([("name".to_string(), Value::String("hello".to_string()))]);
+        let r: Result<Example> = parse_inputs(&props);
+        let e = r.unwrap_err();
+        assert_eq!(e.to_string(), "Could not deserialize input properties");
+        assert!(format!("{:#}", e).contains("missing field `count`"));
+    }
+
+    struct ExampleProvider {}
+
+    impl ResourceProvider for ExampleProvider {
+        fn create(&self, request: CreateResourceRequest) -> Result<CreateResourceResponse> {
+            let example: Example = parse_inputs(&request.input_properties)?;
+            Ok(CreateResourceResponse {
+                output_properties: to_outputs(example)?,
+            })
+        }
+    }
+
+    #[test]
+    fn test_handle_request_validate_default() {
+        let request: Request = serde_json::from_str(
+            r#"{"validate": {"type": "example", "inputProperties": {"bogus": 1}}}"#,
+        )
+        .unwrap();
+        let response = handle_request(&ExampleProvider {}, request).unwrap();
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({"validate": {"problems": []}})
+        );
+    }
+
+    #[test]
+    fn test_handle_request_capabilities() {
+        let request: Request = serde_json::from_str(r#"{"capabilities": {}}"#).unwrap();
+        let response = handle_request(&ExampleProvider {}, request).unwrap();
+        let capabilities = response.capabilities.unwrap();
+        assert_eq!(capabilities.protocol_version, PROTOCOL_VERSION);
+        assert!(capabilities.operations.contains(&"create".to_string()));
+        assert!(capabilities.operations.contains(&"validate".to_string()));
+        assert!(!capabilities.operations.contains(&"cancel".to_string()));
+        assert_eq!(capabilities.resource_types, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_handle_request_schema_default() {
+        let request: Request = serde_json::from_str(r#"{"schema": {}}"#).unwrap();
+        let response = handle_request(&ExampleProvider {}, request).unwrap();
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({"schema": {"resourceTypes": []}})
+        );
+    }
+
+    #[test]
+    fn test_handle_request_no_operation() {
+        let request: Request = serde_json::from_str("{}").unwrap();
+        let e = handle_request(&ExampleProvider {}, request).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Request message must contain exactly one operation"
+        );
+    }
+
+    /// A provider whose create only finishes when it is cancelled.
+    struct CancellableProvider {
+        cancelled: std::sync::Mutex<bool>,
+        condvar: std::sync::Condvar,
+    }
+
+    impl ResourceProvider for CancellableProvider {
+        fn create(&self, _request: CreateResourceRequest) -> Result<CreateResourceResponse> {
+            let cancelled = self.cancelled.lock().unwrap();
+            let _cancelled = self.condvar.wait_while(cancelled, |c| !*c).unwrap();
+            bail!("cancelled")
+        }
+
+        fn cancel(&self) {
+            *self.cancelled.lock().unwrap() = true;
+            self.condvar.notify_all();
+        }
+
+        fn supports_cancel(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_serve_cancel() {
+        let provider = Arc::new(CancellableProvider {
+            cancelled: std::sync::Mutex::new(false),
+            condvar: std::sync::Condvar::new(),
+        });
+        let input = concat!(
+            r#"{"capabilities": {}}"#,
+            "\n",
+            r#"{"type": "example", "inputProperties": {}}"#,
+            "\n",
+            r#"{"cancel": {}}"#,
+            "\n",
+        );
+        let mut out = Vec::new();
+        let e = serve(
+            provider.clone(),
+            std::io::Cursor::new(input.as_bytes().to_vec()),
+            &mut out,
+        )
+        .unwrap_err();
+        assert_eq!(format!("{:#}", e), "Could not create resource: cancelled");
+        assert!(*provider.cancelled.lock().unwrap());
+        // Only the capabilities request got a response
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains(r#""cancel""#));
+    }
+
+    #[test]
+    fn test_serve_create_bare() {
+        let input = concat!(
+            r#"{"type": "example", "inputProperties": {"name": "a", "count": 1, "tags": []}}"#,
+            "\n",
+            r#"{"validate": {"type": "example", "inputProperties": {}}}"#,
+            "\n",
+        );
+        let mut out = Vec::new();
+        serve(
+            Arc::new(ExampleProvider {}),
+            std::io::Cursor::new(input.as_bytes().to_vec()),
+            &mut out,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<Value> = out
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({"outputProperties": {"name": "a", "count": 1, "tags": []}}),
+                serde_json::json!({"validate": {"problems": []}}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_handle_request_cancel() {
+        let request: Request = serde_json::from_str(r#"{"cancel": {}}"#).unwrap();
+        let e = handle_request(&ExampleProvider {}, request).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "A cancel notification does not have a response"
+        );
+    }
+
+    #[test]
+    fn test_to_outputs_not_an_object() {
+        let r = to_outputs("just a string");
+        assert_eq!(r.unwrap_err().to_string(), "Expected object as output");
+    }
+}
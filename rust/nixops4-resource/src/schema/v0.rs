@@ -50,6 +50,84 @@ mod tests {
         );
     }
 
+    #[test]
+    fn examples_v0_validate_resource_request() {
+        let json = include_str!("../../examples/v0/ValidateResourceRequest.json");
+        let _value: ValidateResourceRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            _value,
+            ValidateResourceRequest {
+                type_: "file".to_string(),
+                input_properties: BTreeMap::from_iter(vec![
+                    ("path".to_string(), Value::String("pubkey.txt".to_string())),
+                    ("content".to_string(), Value::from(42)),
+                ]),
+            }
+        );
+    }
+
+    #[test]
+    fn examples_v0_validate_resource_response() {
+        let json = include_str!("../../examples/v0/ValidateResourceResponse.json");
+        let _value: ValidateResourceResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            _value,
+            ValidateResourceResponse {
+                problems: vec![ValidationProblem {
+                    property: Some("content".to_string()),
+                    message: "expected a string".to_string(),
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn examples_v0_capabilities_response() {
+        let json = include_str!("../../examples/v0/CapabilitiesResponse.json");
+        let value: CapabilitiesResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            value,
+            CapabilitiesResponse {
+                protocol_version: 0,
+                operations: vec![
+                    "capabilities".to_string(),
+                    "create".to_string(),
+                    "validate".to_string()
+                ],
+                resource_types: vec!["file".to_string(), "exec".to_string()],
+            }
+        );
+        // Round trip
+        let json2 = serde_json::to_string(&value).unwrap();
+        let value2: CapabilitiesResponse = serde_json::from_str(&json2).unwrap();
+        assert_eq!(value, value2);
+    }
+
+    #[test]
+    fn examples_v0_schema_response() {
+        let json = include_str!("../../examples/v0/SchemaResponse.json");
+        let value: SchemaResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(value.resource_types.len(), 1);
+        let file = &value.resource_types[0];
+        assert_eq!(file.type_, "file");
+        assert_eq!(
+            file.inputs["required"],
+            serde_json::json!(["name", "contents"])
+        );
+        // Round trip
+        let json2 = serde_json::to_string(&value).unwrap();
+        let value2: SchemaResponse = serde_json::from_str(&json2).unwrap();
+        assert_eq!(value, value2);
+    }
+
+    #[test]
+    fn request_envelope() {
+        let json = r#"{"validate": {"type": "file", "inputProperties": {}}}"#;
+        let value: Request = serde_json::from_str(json).unwrap();
+        assert_eq!(value.capabilities, None);
+        assert_eq!(value.validate.unwrap().type_, "file");
+    }
+
     fn object_from_iter<T: IntoIterator<Item = (String, Value)>>(x: T) -> Value {
         Value::Object(serde_json::Map::from_iter(x))
     }
@@ -1,22 +1,62 @@
+use std::collections::BTreeMap;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Context, Result};
-use nixops4_resource::framework::run_main;
-use nixops4_resource::{schema::v0::CreateResourceRequest, schema::v0::CreateResourceResponse};
+use anyhow::{anyhow, bail, Context, Result};
+use nixops4_resource::framework::{parse_inputs, run_main, to_outputs};
+use nixops4_resource::schema::v0::{
+    CreateResourceRequest, CreateResourceResponse, ResourceTypeSchema, ValidateResourceRequest,
+    ValidateResourceResponse, ValidationProblem,
+};
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 
 struct LocalResourceProvider {}
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
 struct FileInProperties {
     name: String,
-    contents: String,
+    contents: Option<String>,
+    /// A file in the Nix store to copy the contents from, such as a
+    /// `pkgs.writeText` output
+    #[serde(rename = "sourceStorePath")]
+    source_store_path: Option<String>,
+    /// Octal permissions, e.g. `"0600"`
+    mode: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
 struct FileOutProperties {}
 
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+struct JsonFileInProperties {
+    name: String,
+    value: serde_json::Value,
+    pretty: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+struct JsonFileOutProperties {}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+struct EnvFileInProperties {
+    name: String,
+    variables: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+struct EnvFileOutProperties {}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+struct TemplateInProperties {
+    name: String,
+    template: String,
+    variables: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+struct TemplateOutProperties {}
+
 #[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
 struct ExecInProperties {
     command: String,
@@ -30,13 +70,43 @@ struct ExecOutProperties {
     stdout: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+struct AssertInProperties {
+    condition: bool,
+    message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+struct AssertOutProperties {}
+
 impl nixops4_resource::framework::ResourceProvider for LocalResourceProvider {
     fn create(&self, request: CreateResourceRequest) -> Result<CreateResourceResponse> {
         match request.type_.as_str() {
             "file" => do_create(request, |p: FileInProperties| {
-                std::fs::write(&p.name, &p.contents)?;
+                let mode = p.mode.as_deref().map(parse_mode).transpose()?;
+                let contents = file_contents(&p, &store_dir())?;
+                write_atomically(Path::new(&p.name), &contents, mode)?;
                 Ok(FileOutProperties {})
             }),
+            "json_file" => do_create(request, |p: JsonFileInProperties| {
+                let contents = if p.pretty.unwrap_or(false) {
+                    serde_json::to_string_pretty(&p.value)? + "\n"
+                } else {
+                    serde_json::to_string(&p.value)?
+                };
+                write_atomically(Path::new(&p.name), contents.as_bytes(), None)?;
+                Ok(JsonFileOutProperties {})
+            }),
+            "env_file" => do_create(request, |p: EnvFileInProperties| {
+                let contents = render_env_file(&p.variables)?;
+                write_atomically(Path::new(&p.name), contents.as_bytes(), None)?;
+                Ok(EnvFileOutProperties {})
+            }),
+            "template" => do_create(request, |p: TemplateInProperties| {
+                let contents = render_template(&p.template, &p.variables)?;
+                write_atomically(Path::new(&p.name), contents.as_bytes(), None)?;
+                Ok(TemplateOutProperties {})
+            }),
             "exec" => do_create(request, |p: ExecInProperties| {
                 let mut command = std::process::Command::new(&p.command);
                 command.args(&p.args);
@@ -70,22 +140,134 @@ impl nixops4_resource::framework::ResourceProvider for LocalResourceProvider {
 
                 Ok(ExecOutProperties { stdout })
             }),
+            "assert" => do_create(request, |p: AssertInProperties| {
+                if !p.condition {
+                    bail!("Assertion failed: {}", p.message);
+                }
+                Ok(AssertOutProperties {})
+            }),
             t => bail!(
                 "LocalResourceProvider::create: unknown resource type: {}",
                 t
             ),
         }
     }
+
+    fn resource_types(&self) -> Vec<String> {
+        vec![
+            "file".to_string(),
+            "json_file".to_string(),
+            "env_file".to_string(),
+            "template".to_string(),
+            "exec".to_string(),
+            "assert".to_string(),
+        ]
+    }
+
+    fn resource_type_schemas(&self) -> Vec<ResourceTypeSchema> {
+        let string = json!({ "type": "string" });
+        let string_map = json!({ "type": "object", "additionalProperties": string });
+        let no_outputs = object_schema(&[], &[]);
+        vec![
+            ResourceTypeSchema {
+                type_: "file".to_string(),
+                inputs: object_schema(
+                    &[("name", string.clone())],
+                    &[
+                        ("contents", string.clone()),
+                        ("sourceStorePath", string.clone()),
+                        ("mode", string.clone()),
+                    ],
+                ),
+                outputs: no_outputs.clone(),
+            },
+            ResourceTypeSchema {
+                type_: "json_file".to_string(),
+                inputs: object_schema(
+                    &[("name", string.clone()), ("value", json!({}))],
+                    &[("pretty", json!({ "type": "boolean" }))],
+                ),
+                outputs: no_outputs.clone(),
+            },
+            ResourceTypeSchema {
+                type_: "env_file".to_string(),
+                inputs: object_schema(
+                    &[("name", string.clone()), ("variables", string_map.clone())],
+                    &[],
+                ),
+                outputs: no_outputs.clone(),
+            },
+            ResourceTypeSchema {
+                type_: "template".to_string(),
+                inputs: object_schema(
+                    &[
+                        ("name", string.clone()),
+                        ("template", string.clone()),
+                        ("variables", string_map),
+                    ],
+                    &[],
+                ),
+                outputs: no_outputs.clone(),
+            },
+            ResourceTypeSchema {
+                type_: "exec".to_string(),
+                inputs: object_schema(
+                    &[
+                        ("command", string.clone()),
+                        ("args", json!({ "type": "array", "items": string })),
+                    ],
+                    &[("stdin", string.clone())],
+                ),
+                outputs: object_schema(&[("stdout", string.clone())], &[]),
+            },
+            ResourceTypeSchema {
+                type_: "assert".to_string(),
+                inputs: object_schema(
+                    &[
+                        ("condition", json!({ "type": "boolean" })),
+                        ("message", string),
+                    ],
+                    &[],
+                ),
+                outputs: no_outputs,
+            },
+        ]
+    }
+
+    fn validate(&self, request: ValidateResourceRequest) -> Result<ValidateResourceResponse> {
+        let r = match request.type_.as_str() {
+            "file" => parse_inputs::<FileInProperties>(&request.input_properties)
+                .and_then(|p| check_file_source(&p, &store_dir())),
+            "json_file" => {
+                parse_inputs::<JsonFileInProperties>(&request.input_properties).map(|_| ())
+            }
+            "env_file" => parse_inputs::<EnvFileInProperties>(&request.input_properties)
+                .and_then(|p| render_env_file(&p.variables))
+                .map(|_| ()),
+            "template" => parse_inputs::<TemplateInProperties>(&request.input_properties)
+                .and_then(|p| render_template(&p.template, &p.variables))
+                .map(|_| ()),
+            "exec" => parse_inputs::<ExecInProperties>(&request.input_properties).map(|_| ()),
+            "assert" => parse_inputs::<AssertInProperties>(&request.input_properties).map(|_| ()),
+            t => Err(anyhow!("unknown resource type: {}", t)),
+        };
+        Ok(ValidateResourceResponse {
+            problems: match r {
+                Ok(()) => vec![],
+                Err(e) => vec![ValidationProblem {
+                    property: None,
+                    message: format!("{:#}", e),
+                }],
+            },
+        })
+    }
 }
 
 fn do_create<In: for<'de> Deserialize<'de>, Out: serde::Serialize>(
     request: CreateResourceRequest,
     f: impl Fn(In) -> Result<Out>,
 ) -> std::prelude::v1::Result<CreateResourceResponse, anyhow::Error> {
-    let parsed_properties: In = serde_json::from_value(Value::Object(
-        request.input_properties.into_iter().collect(),
-    ))
-    .with_context(|| {
+    let parsed_properties: In = parse_inputs(&request.input_properties).with_context(|| {
         format!(
             "Could not deserialize input properties for {} resource",
             request.type_
@@ -94,20 +276,601 @@ fn do_create<In: for<'de> Deserialize<'de>, Out: serde::Serialize>(
 
     let out = f(parsed_properties)?;
 
-    let out_value = serde_json::to_value(out)?;
+    Ok(CreateResourceResponse {
+        output_properties: to_outputs(out)?,
+    })
+}
 
-    let out_object = match out_value {
-        Value::Object(o) => o,
-        _ => bail!("Expected object as output"),
-    };
+/// A JSON Schema for an object with the given properties, and no others.
+fn object_schema(required: &[(&str, Value)], optional: &[(&str, Value)]) -> Value {
+    let properties: serde_json::Map<String, Value> = required
+        .iter()
+        .chain(optional)
+        .map(|(name, schema)| (name.to_string(), schema.clone()))
+        .collect();
+    let required: Vec<&str> = required.iter().map(|(name, _)| *name).collect();
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
 
-    let out_properties = out_object.into_iter().collect();
+/// The directory of the Nix store, as configured by `NIX_STORE_DIR`.
+fn store_dir() -> PathBuf {
+    std::env::var_os("NIX_STORE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/nix/store"))
+}
 
-    Ok(CreateResourceResponse {
-        output_properties: out_properties,
-    })
+/// Check that exactly one of `contents` and `sourceStorePath` is set, and
+/// that `sourceStorePath` is in `store_dir`.
+fn check_file_source(p: &FileInProperties, store_dir: &Path) -> Result<()> {
+    match (&p.contents, &p.source_store_path) {
+        (Some(_), None) => Ok(()),
+        (None, Some(path)) => {
+            if !Path::new(path).starts_with(store_dir) {
+                bail!(
+                    "`sourceStorePath` must be in the Nix store {}: {}",
+                    store_dir.display(),
+                    path
+                );
+            }
+            Ok(())
+        }
+        (None, None) => bail!("Either `contents` or `sourceStorePath` must be set"),
+        (Some(_), Some(_)) => {
+            bail!("Only one of `contents` and `sourceStorePath` can be set")
+        }
+    }
+}
+
+/// The bytes to write for a file resource.
+fn file_contents(p: &FileInProperties, store_dir: &Path) -> Result<Vec<u8>> {
+    check_file_source(p, store_dir)?;
+    match (&p.contents, &p.source_store_path) {
+        (Some(contents), _) => Ok(contents.as_bytes().to_vec()),
+        (None, Some(path)) => {
+            // Resolve `..` and symlinks, which could lead out of the store
+            let resolved = std::fs::canonicalize(path)
+                .with_context(|| format!("Could not read store path: {}", path))?;
+            let resolved_store = std::fs::canonicalize(store_dir).with_context(|| {
+                format!("Could not resolve the Nix store: {}", store_dir.display())
+            })?;
+            if !resolved.starts_with(&resolved_store) {
+                bail!(
+                    "`sourceStorePath` must be in the Nix store {}: {}",
+                    store_dir.display(),
+                    path
+                );
+            }
+            std::fs::read(&resolved).with_context(|| format!("Could not read store path: {}", path))
+        }
+        (None, None) => unreachable!(),
+    }
+}
+
+/// Parse an octal file mode, such as `"0644"`.
+fn parse_mode(mode: &str) -> Result<u32> {
+    let m = u32::from_str_radix(mode, 8)
+        .with_context(|| format!("Invalid file mode, expected an octal number: {}", mode))?;
+    if m > 0o7777 {
+        bail!("Invalid file mode, expected at most 7777: {}", mode);
+    }
+    Ok(m)
+}
+
+/// Render `KEY=VALUE` lines for a dotenv file.
+///
+/// Values that consist of only safe characters are written as is. Others are
+/// double quoted, with `\`, `"`, `$` and `` ` `` escaped by a backslash, and
+/// newlines written as `\n`.
+fn render_env_file(variables: &BTreeMap<String, String>) -> Result<String> {
+    let mut contents = String::new();
+    for (key, value) in variables {
+        if !is_env_var_name(key) {
+            bail!("Invalid environment variable name: {:?}", key);
+        }
+        contents.push_str(key);
+        contents.push('=');
+        if value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.,/:@%+".contains(c))
+        {
+            contents.push_str(value);
+        } else {
+            contents.push('"');
+            for c in value.chars() {
+                match c {
+                    '\\' | '"' | '$' | '`' => {
+                        contents.push('\\');
+                        contents.push(c);
+                    }
+                    '\n' => contents.push_str("\\n"),
+                    c => contents.push(c),
+                }
+            }
+            contents.push('"');
+        }
+        contents.push('\n');
+    }
+    Ok(contents)
+}
+
+/// Substitute `${name}` in `template` by the value of variable `name`.
+///
+/// `$${` is written as a literal `${`. Any other `$` is kept as is.
+fn render_template(template: &str, variables: &BTreeMap<String, String>) -> Result<String> {
+    let mut result = String::new();
+    let mut missing = std::collections::BTreeSet::new();
+    let mut rest = template;
+    while let Some(ix) = rest.find('$') {
+        result.push_str(&rest[..ix]);
+        rest = &rest[ix..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let Some(end) = after.find('}') else {
+                bail!("Unterminated ${{ in template");
+            };
+            let name = &after[..end];
+            match variables.get(name) {
+                Some(value) => result.push_str(value),
+                None => {
+                    missing.insert(name);
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    if !missing.is_empty() {
+        bail!(
+            "Undefined variables in template: {}",
+            missing.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+    Ok(result)
+}
+
+/// Whether `name` is a valid environment variable name, such as `PATH` or `_x1`.
+fn is_env_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Write a file by writing a temporary file in the same directory and
+/// renaming it, so that readers see either the old or the new contents.
+///
+/// Without `mode`, the permissions of an existing file are kept, and a new
+/// file gets the default permissions, as with `std::fs::write`. The temporary
+/// file is never more permissive than the resulting file.
+///
+/// If `path` is a symlink, the file it points to is replaced, so that the link
+/// is kept. Because the file is replaced, it is owned by the user that runs
+/// the provider, even if an existing file had another owner.
+fn write_atomically(path: &Path, contents: &[u8], mode: Option<u32>) -> Result<()> {
+    let r = (|| -> Result<()> {
+        let path = resolve_symlinks(path)?;
+        let existing = std::fs::metadata(&path).ok();
+        let create_mode = mode.or(existing.as_ref().map(file_mode)).unwrap_or(0o666);
+        let (mut file, tmp_path) = create_temp_file(&path, create_mode)?;
+        let r = (|| -> Result<()> {
+            file.write_all(contents)?;
+            // Set the exact permissions, which the umask may have reduced
+            match (mode, &existing) {
+                (Some(mode), _) => set_mode(&file, mode)?,
+                (None, Some(existing)) => file.set_permissions(existing.permissions())?,
+                (None, None) => {}
+            }
+            file.sync_all()?;
+            drop(file);
+            std::fs::rename(&tmp_path, &path)?;
+            Ok(())
+        })();
+        if r.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        r
+    })();
+    r.with_context(|| format!("Could not write file: {}", path.display()))
+}
+
+/// Follow `path` while it is a symlink, to the path of the file it refers to,
+/// which need not exist yet.
+fn resolve_symlinks(path: &Path) -> Result<std::path::PathBuf> {
+    let mut path = path.to_path_buf();
+    // Like the usual SYMLOOP_MAX
+    for _ in 0..40 {
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = std::fs::read_link(&path)?;
+                path = match path.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target,
+                };
+            }
+            _ => return Ok(path),
+        }
+    }
+    bail!("Too many levels of symbolic links: {}", path.display())
+}
+
+/// Create a new, empty temporary file next to `path`, with at most the
+/// permissions in `mode`.
+///
+/// The file must not exist yet, so that an existing file or symlink with the
+/// same name is never written to.
+fn create_temp_file(path: &Path, mode: u32) -> Result<(std::fs::File, std::path::PathBuf)> {
+    static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Not a file path: {}", path.display()))?;
+    loop {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let tmp_path = path.with_file_name(format!(
+            ".{}.{}.{}.{:08x}.tmp",
+            file_name.to_string_lossy(),
+            std::process::id(),
+            COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            nanos
+        ));
+        match create_new(&tmp_path, mode) {
+            Ok(file) => return Ok((file, tmp_path)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Could not create temporary file: {}", tmp_path.display())
+                })
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn create_new(path: &Path, mode: u32) -> std::io::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_new(path: &Path, _mode: u32) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata) -> u32 {
+    0o666
+}
+
+#[cfg(unix)]
+fn set_mode(file: &std::fs::File, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_file: &std::fs::File, _mode: u32) -> Result<()> {
+    bail!("Setting the file mode is only supported on Unix")
 }
 
 fn main() {
     run_main(LocalResourceProvider {})
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nixops4_resource::framework::ResourceProvider;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nixops4-resources-local-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_resource_type_schemas() {
+        let provider = LocalResourceProvider {};
+        let schemas = provider.resource_type_schemas();
+        let types: Vec<String> = schemas.iter().map(|s| s.type_.clone()).collect();
+        assert_eq!(types, provider.resource_types());
+
+        let file = schemas.iter().find(|s| s.type_ == "file").unwrap();
+        assert_eq!(
+            file.inputs["properties"]["name"],
+            json!({ "type": "string" })
+        );
+        assert_eq!(
+            file.inputs["properties"]["contents"],
+            json!({ "type": "string" })
+        );
+        assert_eq!(file.inputs["required"], json!(["name"]));
+        assert_eq!(file.inputs["additionalProperties"], json!(false));
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0600").unwrap(), 0o600);
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert!(parse_mode("0800").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert!(parse_mode("rw-r--r--").is_err());
+    }
+
+    fn file_inputs(contents: Option<&str>, source_store_path: Option<&Path>) -> FileInProperties {
+        FileInProperties {
+            name: "out.txt".to_string(),
+            contents: contents.map(|s| s.to_string()),
+            source_store_path: source_store_path.map(|p| p.to_string_lossy().into_owned()),
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn test_file_contents() {
+        // A store of our own, with a path like `pkgs.writeText` produces
+        let store = temp_dir("store");
+        let source = store.join("0c8dhz8bw7xldsvfy5sdlnrvgyqgmvy6-source.bin");
+        std::fs::write(&source, b"\x00\xffbinary").unwrap();
+        assert_eq!(
+            file_contents(&file_inputs(None, Some(&source)), &store).unwrap(),
+            b"\x00\xffbinary"
+        );
+        assert_eq!(
+            file_contents(&file_inputs(Some("hi"), None), &store).unwrap(),
+            b"hi"
+        );
+        std::fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[test]
+    fn test_file_contents_outside_store() {
+        let store = temp_dir("store-outside");
+        let outside = temp_dir("outside");
+        let secret = outside.join("secret");
+        std::fs::write(&secret, "not for copying").unwrap();
+        let expected = format!(
+            "`sourceStorePath` must be in the Nix store {}: ",
+            store.display()
+        );
+        let e = file_contents(&file_inputs(None, Some(&secret)), &store).unwrap_err();
+        assert_eq!(e.to_string(), format!("{}{}", expected, secret.display()));
+        // Also when it only looks like a store path
+        let dotdot = store
+            .join("..")
+            .join(outside.file_name().unwrap())
+            .join("secret");
+        let e = file_contents(&file_inputs(None, Some(&dotdot)), &store).unwrap_err();
+        assert_eq!(e.to_string(), format!("{}{}", expected, dotdot.display()));
+        let link = store.join("0c8dhz8bw7xldsvfy5sdlnrvgyqgmvy6-link");
+        std::os::unix::fs::symlink(&secret, &link).unwrap();
+        let e = file_contents(&file_inputs(None, Some(&link)), &store).unwrap_err();
+        assert_eq!(e.to_string(), format!("{}{}", expected, link.display()));
+        std::fs::remove_dir_all(&store).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_file_contents_exclusive() {
+        let store = Path::new("/nix/store");
+        let e = file_contents(&file_inputs(None, None), store).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Either `contents` or `sourceStorePath` must be set"
+        );
+        let e = file_contents(&file_inputs(Some("hi"), Some(Path::new("/x"))), store).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Only one of `contents` and `sourceStorePath` can be set"
+        );
+    }
+
+    #[test]
+    fn test_render_env_file() {
+        let variables = BTreeMap::from([
+            ("PLAIN".to_string(), "/var/lib/app".to_string()),
+            ("SPACES".to_string(), "hello world".to_string()),
+            ("NEWLINES".to_string(), "line 1\nline 2".to_string()),
+            ("EQUALS".to_string(), "a=b".to_string()),
+            ("SPECIAL".to_string(), r#"say "$HOME" \ `x`"#.to_string()),
+            ("EMPTY".to_string(), "".to_string()),
+        ]);
+        assert_eq!(
+            render_env_file(&variables).unwrap(),
+            r#"EMPTY=
+EQUALS="a=b"
+NEWLINES="line 1\nline 2"
+PLAIN=/var/lib/app
+SPACES="hello world"
+SPECIAL="say \"\$HOME\" \\ \`x\`"
+"#
+        );
+    }
+
+    #[test]
+    fn test_render_env_file_invalid_key() {
+        for key in ["1abc", "", "A-B", "A B", "A=B"] {
+            let variables = BTreeMap::from([(key.to_string(), "x".to_string())]);
+            let e = render_env_file(&variables).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("Invalid environment variable name: {:?}", key)
+            );
+        }
+        assert!(is_env_var_name("_x1"));
+    }
+
+    #[test]
+    fn test_render_template() {
+        let variables = BTreeMap::from([
+            ("host".to_string(), "example.com".to_string()),
+            ("port".to_string(), "443".to_string()),
+        ]);
+        assert_eq!(
+            render_template("url = https://${host}:${port}/\n", &variables).unwrap(),
+            "url = https://example.com:443/\n"
+        );
+        assert_eq!(
+            render_template("${host}${host}", &variables).unwrap(),
+            "example.comexample.com"
+        );
+        assert_eq!(render_template("", &variables).unwrap(), "");
+    }
+
+    #[test]
+    fn test_render_template_escape() {
+        let variables = BTreeMap::from([("host".to_string(), "example.com".to_string())]);
+        assert_eq!(
+            render_template("$${host} is ${host}; $HOME costs $5$", &variables).unwrap(),
+            "${host} is example.com; $HOME costs $5$"
+        );
+        assert_eq!(
+            render_template("$$${host}", &variables).unwrap(),
+            "$${host}"
+        );
+    }
+
+    #[test]
+    fn test_render_template_missing_variables() {
+        let variables = BTreeMap::from([("host".to_string(), "example.com".to_string())]);
+        let e = render_template("${port} ${host} ${user} ${port}", &variables).unwrap_err();
+        assert_eq!(e.to_string(), "Undefined variables in template: port, user");
+        let e = render_template("${host", &variables).unwrap_err();
+        assert_eq!(e.to_string(), "Unterminated ${ in template");
+    }
+
+    #[test]
+    fn test_write_atomically_no_partial_reads() {
+        let dir = temp_dir("atomic");
+        let path = dir.join("file.txt");
+        let a = vec![b'a'; 100_000];
+        let b = vec![b'b'; 200_000];
+        write_atomically(&path, &a, None).unwrap();
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let reader = scope.spawn(|| {
+                let mut reads = 0;
+                while !done.load(std::sync::atomic::Ordering::SeqCst) || reads == 0 {
+                    let contents = std::fs::read(&path).unwrap();
+                    assert!(contents == a || contents == b, "partial read");
+                    reads += 1;
+                }
+            });
+            for i in 0..50 {
+                write_atomically(&path, if i % 2 == 0 { &b } else { &a }, None).unwrap();
+            }
+            done.store(true, std::sync::atomic::Ordering::SeqCst);
+            reader.join().unwrap();
+        });
+
+        // No temporary files are left behind
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_temp_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("temp-mode");
+        let path = dir.join("secret.txt");
+        for mode in [0o600, 0o400, 0o640] {
+            let (file, tmp_path) = create_temp_file(&path, mode).unwrap();
+            // Before anything is written, the file is no more permissive than asked
+            let actual = file.metadata().unwrap().permissions().mode() & 0o7777;
+            assert_eq!(
+                actual & !mode,
+                0,
+                "{:o} is more permissive than {:o}",
+                actual,
+                mode
+            );
+            assert_eq!(std::fs::read(&tmp_path).unwrap(), b"");
+            std::fs::remove_file(&tmp_path).unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_new_existing_symlink() {
+        let dir = temp_dir("create-new-symlink");
+        let victim = dir.join("victim.txt");
+        let tmp_path = dir.join(".file.txt.tmp");
+        std::fs::write(&victim, b"keep").unwrap();
+        std::os::unix::fs::symlink(&victim, &tmp_path).unwrap();
+        let e = create_new(&tmp_path, 0o600).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&victim).unwrap(), b"keep");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomically_through_symlink() {
+        let dir = temp_dir("through-symlink");
+        let target = dir.join("target.txt");
+        let link = dir.join("link.txt");
+        std::fs::write(&target, b"old").unwrap();
+        std::os::unix::fs::symlink("target.txt", &link).unwrap();
+        write_atomically(&link, b"new", None).unwrap();
+        assert!(std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomically_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("mode");
+        let path = dir.join("secret.txt");
+        write_atomically(&path, b"secret", Some(0o600)).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
+
+        // Without a mode, the existing permissions are kept
+        write_atomically(&path, b"still secret", None).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
+        assert_eq!(std::fs::read(&path).unwrap(), b"still secret");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
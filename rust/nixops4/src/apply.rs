@@ -1,23 +1,132 @@
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, BTreeSet, HashSet},
     sync::Mutex,
+    time::{Duration, Instant},
 };
 
+use crate::{
+    determine_interactive,
+    eval_client::{EvalClient, REDACTED},
+    logging::Frontend,
+    with_flake, Options,
+};
 use crate::{interrupt::InterruptState, provider};
-use crate::{with_flake, Options};
 use anyhow::{bail, Result};
 use nixops4_core::eval_api::{
-    AssignRequest, DeploymentRequest, EvalRequest, EvalResponse, Id, NamedProperty, Property,
-    QueryRequest, QueryResponseValue, ResourceInputState, ResourceRequest, ResourceType,
+    AssignRequest, DeploymentArg, DeploymentRequest, DeploymentType, EvalRequest, EvalResponse,
+    FlakeMetadata, Id, NamedProperty, Property, QueryRequest, QueryResponseValue,
+    ResourceInputDependency, ResourceInputState, ResourceProviderInfo, ResourceRequest,
+    ResourceType,
+};
+use nixops4_resource::schema::v0::ValidationProblem;
+use nixops4_resource_runner::{
+    RequestTimeoutError, ResourceProviderClient, ResourceProviderConfig,
 };
-use nixops4_resource_runner::{ResourceProviderClient, ResourceProviderConfig};
+use serde::Serialize;
 use serde_json::Value;
 use tracing::info_span;
 
 #[derive(clap::Parser, Debug)]
 pub(crate) struct Args {
-    #[arg(default_value = "default")]
-    deployment: String,
+    /// The deployment to apply, from the flake's `nixops4Deployments`; defaults to `default`
+    #[arg(value_name = "DEPLOYMENT")]
+    deployment_positional: Option<String>,
+
+    /// The deployment to apply; same as the positional argument
+    #[arg(
+        long("deployment"),
+        value_name = "NAME",
+        conflicts_with = "deployment_positional"
+    )]
+    deployment: Option<String>,
+
+    #[command(flatten)]
+    deployment_args: DeploymentArgs,
+
+    /// Fail if a resource provider does not respond within SECONDS of starting
+    #[arg(long, value_name = "SECONDS")]
+    provider_startup_timeout: Option<u64>,
+
+    /// Fail a resource if its provider takes longer than SECONDS to apply it; the provider is stopped. A resource's `timeoutSeconds` takes precedence.
+    #[arg(long, value_name = "SECONDS")]
+    resource_timeout: Option<u64>,
+
+    /// Only run provider executables under PREFIX, e.g. `/nix/store`, after resolving symlinks; may be repeated. By default, any provider may run.
+    #[arg(long, value_name = "PREFIX")]
+    allow_provider: Vec<String>,
+
+    /// Only apply resource NAME and the resources it depends on; may be repeated
+    #[arg(long("resource"), visible_alias = "target", value_name = "NAME")]
+    resource: Vec<String>,
+
+    /// Do not apply resource NAME; may be repeated
+    #[arg(long("exclude"), value_name = "NAME")]
+    exclude: Vec<String>,
+
+    /// Show which resources would be applied, without running any resource providers
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Print the plan as JSON to stdout, without running any resource providers
+    #[arg(long, default_value_t = false, conflicts_with = "dry_run")]
+    plan_json: bool,
+
+    /// Apply without asking for confirmation in interactive mode
+    #[arg(long, short = 'y', visible_alias = "confirm", default_value_t = false)]
+    yes: bool,
+
+    /// Print how long each resource took to apply
+    #[arg(long, default_value_t = false)]
+    timings: bool,
+
+    /// Keep applying the resources that don't depend on a resource that failed
+    #[arg(long, default_value_t = false)]
+    keep_going: bool,
+
+    /// Fail instead of warning when the flake has uncommitted changes
+    #[arg(long, visible_alias = "no-dirty", default_value_t = false)]
+    require_clean: bool,
+}
+
+impl Args {
+    fn deployment_name(&self) -> &str {
+        self.deployment
+            .as_deref()
+            .or(self.deployment_positional.as_deref())
+            .unwrap_or("default")
+    }
+}
+
+/// The arguments for a deployment that is a function.
+#[derive(clap::Args, Debug, Default)]
+pub(crate) struct DeploymentArgs {
+    /// Pass the value of the Nix expression EXPR as argument NAME, if the deployment is a function
+    #[arg(long("arg"), number_of_values = 2, value_names = &["NAME", "EXPR"])]
+    arg: Vec<String>,
+
+    /// Pass the string STRING as argument NAME, if the deployment is a function
+    #[arg(long("argstr"), number_of_values = 2, value_names = &["NAME", "STRING"])]
+    argstr: Vec<String>,
+}
+
+impl DeploymentArgs {
+    pub(crate) fn to_map(&self) -> Result<BTreeMap<String, DeploymentArg>> {
+        let mut args = BTreeMap::new();
+        let exprs = self
+            .arg
+            .chunks(2)
+            .map(|pair| (&pair[0], DeploymentArg::Expr(pair[1].clone())));
+        let strs = self
+            .argstr
+            .chunks(2)
+            .map(|pair| (&pair[0], DeploymentArg::Str(pair[1].clone())));
+        for (name, value) in exprs.chain(strs) {
+            if args.insert(name.clone(), value).is_some() {
+                bail!("argument {} was specified more than once", name);
+            }
+        }
+        Ok(args)
+    }
 }
 
 /// Run the `apply` command.
@@ -25,14 +134,31 @@ pub(crate) fn apply(
     interrupt_state: &InterruptState,
     options: &Options, /* global options; apply options tbd, extra param */
     args: &Args,
+    frontend: &mut dyn Frontend,
 ) -> Result<()> {
+    let deployment_args = args.deployment_args.to_map()?;
+    let deployment_name = args.deployment_name();
     with_flake(options, |c, flake_id| {
+        let metadata_id = c.query(EvalRequest::GetFlakeMetadata, flake_id)?;
+        let deployments_id = c.query(EvalRequest::ListDeployments, flake_id)?;
+        let (metadata, deployments) = c.receive_until(|client, _resp| {
+            client.check_error(flake_id)?;
+            client.check_error(metadata_id)?;
+            client.check_error(deployments_id)?;
+            Ok(client
+                .get_flake_metadata(flake_id)
+                .cloned()
+                .zip(client.get_deployments(flake_id).cloned()))
+        })?;
+        check_flake_clean(&metadata, args.require_clean)?;
+        check_deployment_exists(&deployments, deployment_name)?;
         let deployment_id = c.next_id();
         c.send(&EvalRequest::LoadDeployment(AssignRequest {
             assign_to: deployment_id,
             payload: DeploymentRequest {
                 flake: flake_id,
-                name: args.deployment.to_string(),
+                name: deployment_name.to_string(),
+                args: deployment_args.clone(),
             },
         }))?;
         let resources_list_id = c.query(EvalRequest::ListResources, deployment_id)?;
@@ -42,11 +168,20 @@ pub(crate) fn apply(
             client.check_error(resources_list_id)?;
             Ok(client.get_resources(deployment_id).cloned())
         })?;
+        let selected = select_resources(&resources, &args.resource, &args.exclude)?;
+        let is_partial = selected.len() != resources.len();
         if resources.is_empty() {
             eprintln!("Deployment contains no resources; nothing to apply.");
+        } else if selected.is_empty() {
+            eprintln!("No resources are selected; nothing to apply.");
         } else {
+            if is_partial {
+                eprintln!(
+                    "Partial apply: only the selected resources and the resources they depend on will be applied."
+                );
+            }
             eprintln!("The following resources will be checked, created and/or updated:");
-            for r in &resources {
+            for r in &selected {
                 eprintln!("  - {}", r);
             }
         }
@@ -54,33 +189,134 @@ pub(crate) fn apply(
             .iter()
             .map(|name| (name.clone(), c.next_id()))
             .collect();
-        for (r, id) in resource_ids.iter() {
-            c.send(&EvalRequest::LoadResource(AssignRequest {
-                assign_to: *id,
-                payload: ResourceRequest {
-                    deployment: deployment_id,
-                    name: r.clone(),
-                },
-            }))?;
-            // TODO: check for errors on this id
-            c.query(EvalRequest::GetResource, *id)?;
-            // TODO: check for errors on this id
-            c.query(EvalRequest::ListResourceInputs, *id)?;
+        let load_selected = |c: &mut EvalClient, ids: &BTreeMap<String, Id<ResourceType>>| {
+            let mut loaded = BTreeSet::new();
+            for r in selected.iter() {
+                let id = *ids.get(r).unwrap();
+                load_resource(c, deployment_id, r, id)?;
+                loaded.insert(id);
+            }
+            anyhow::Ok(loaded)
+        };
+        if args.dry_run || args.plan_json {
+            // Like applying, previewing nothing would wait forever
+            let resources = if selected.is_empty() {
+                BTreeMap::new()
+            } else {
+                let loaded_resources = load_selected(c, &resource_ids)?;
+                preview(
+                    c,
+                    deployment_id,
+                    &resource_ids,
+                    loaded_resources,
+                    &args.exclude,
+                )?
+            };
+            if args.plan_json {
+                let plan = plan_json(deployment_name, &resources);
+                println!("{}", serde_json::to_string_pretty(&plan)?);
+            } else {
+                print_preview(&resources);
+            }
+            return Ok(());
         }
+        // Nothing would be outstanding, so waiting for the evaluator would never end
+        if selected.is_empty() {
+            return Ok(());
+        }
+        // Evaluate what can be evaluated before applying, so that invalid
+        // inputs are reported before any resource is created. Applying
+        // continues from this evaluation, rather than evaluating again.
+        let selected_loaded = load_selected(c, &resource_ids)?;
+        let plan = evaluate_plan(
+            c,
+            deployment_id,
+            &resource_ids,
+            selected_loaded,
+            &args.exclude,
+        )?;
+        confirm_apply(frontend, args.yes, determine_interactive(options))?;
+        // Provider processes are kept alive for the duration of the apply, so
+        // that resources of the same provider don't each spawn a new process.
+        // Validating starts them, so that only happens once the user agrees.
+        let providers: ProviderPool = Mutex::new(BTreeMap::new());
+        let providers_ref = &providers;
+        let validated =
+            validate_resources(&providers, &plan, args, options.verbose, interrupt_state)?;
+        // Resources that we have asked the evaluator to load: those of the plan,
+        // and dependencies that are discovered while applying.
+        let loaded_resources: Mutex<BTreeSet<Id<ResourceType>>> = Mutex::new(
+            plan.keys()
+                .map(|name| *resource_ids.get(name).unwrap())
+                .collect(),
+        );
         let resource_ids_to_names: BTreeMap<Id<ResourceType>, String> =
             resource_ids.iter().map(|(k, v)| (*v, k.clone())).collect();
-        let resource_ids_clone = resource_ids.clone();
+        // Outputs that inputs are waiting for, for detecting cycles
+        let dependency_edges: Mutex<Vec<DependencyEdge>> = Mutex::new(Vec::new());
         // key: blocking property, value: blocked properties
         let resources_blocked: Mutex<BTreeMap<Property, BTreeSet<Property>>> =
             Mutex::new(BTreeMap::new());
         let resources_outputs: Mutex<BTreeMap<Id<ResourceType>, BTreeMap<String, Value>>> =
             Mutex::new(BTreeMap::new());
-        let resource_inputs = Mutex::new(BTreeMap::new());
+        // Outputs that the evaluator has recorded. Only after that is it
+        // useful to ask for the inputs that depend on them.
+        let acknowledged_outputs: Mutex<HashSet<NamedProperty>> = Mutex::new(HashSet::new());
+        // The plan has already listed the inputs and providers of its resources
+        let resource_inputs: Mutex<BTreeMap<Id<ResourceType>, Vec<String>>> = Mutex::new(
+            plan.iter()
+                .map(|(name, resource)| {
+                    let id = *resource_ids.get(name).unwrap();
+                    (id, resource.inputs.keys().cloned().collect())
+                })
+                .collect(),
+        );
         let resource_input_values = Mutex::new(BTreeMap::new());
-        let resource_provider_info = Mutex::new(BTreeMap::new());
+        let resource_provider_info: Mutex<BTreeMap<Id<ResourceType>, ResourceProviderInfo>> =
+            Mutex::new(
+                plan.iter()
+                    .map(|(name, resource)| {
+                        (*resource_ids.get(name).unwrap(), resource.info.clone())
+                    })
+                    .collect(),
+            );
+        // Also needed after evaluation, to redact the summary
+        let resource_provider_info = &resource_provider_info;
+        // The outputs that inputs are taken from, so that an input taken from a
+        // secret output is redacted too. Known from the plan, because once the
+        // output is known, the evaluator only reports the input's value.
+        let input_sources: Mutex<BTreeMap<Property, NamedProperty>> = Mutex::new(
+            plan.iter()
+                .filter_map(|(name, resource)| Some((resource_ids.get(name)?, resource)))
+                .flat_map(|(id, resource)| {
+                    resource
+                        .inputs
+                        .iter()
+                        .filter_map(|(input, value)| match value {
+                            PreviewInput::Dependency(dependency) => Some((
+                                Property {
+                                    resource: *id,
+                                    name: input.clone(),
+                                },
+                                dependency.clone(),
+                            )),
+                            _ => None,
+                        })
+                })
+                .collect(),
+        );
+        let input_sources = &input_sources;
+        // With --keep-going: the resources that could not be created, and why
+        let failed_resources: Mutex<BTreeMap<Id<ResourceType>, String>> =
+            Mutex::new(BTreeMap::new());
+        // Time spent by the provider on each resource
+        let timings: Mutex<BTreeMap<String, Duration>> = Mutex::new(BTreeMap::new());
+        let timings_ref = &timings;
 
-        let (resource_inputs, resource_outputs, resource_input_values) = {
-            c.receive_until(move |client, resp| {
+        let (resource_inputs, resource_outputs, resource_input_values, failed, skipped) = {
+            // Also used for replaying the plan below
+            let (plan, resource_ids) = (&plan, &resource_ids);
+            let handle = move |client: &mut EvalClient, resp: &EvalResponse| {
                 // TODO: stop asynchronously
                 // TODO: when concurrent track critical tasks and wait for them
                 interrupt_state.check_interrupted()?;
@@ -109,6 +345,8 @@ pub(crate) fn apply(
                             }
                         }
                         QueryResponseValue::ListDeployments(_) => {}
+                        QueryResponseValue::FlakeMetadata(_) => {}
+                        QueryResponseValue::DeploymentOptions(_) => {}
                         QueryResponseValue::ListResources(_) => todo!(),
                         QueryResponseValue::ResourceProviderInfo(info) => {
                             resource_provider_info
@@ -151,8 +389,17 @@ pub(crate) fn apply(
                                         });
 
                                     if options.verbose {
+                                        let secrets = secret_properties(
+                                            &resource_provider_info.lock().unwrap(),
+                                            &input_sources.lock().unwrap(),
+                                            resource_ids,
+                                            prop.resource,
+                                        );
                                         eprintln!("Resource complete: {}", is_complete);
-                                        eprintln!("Resource inputs: {:?}", inputs);
+                                        eprintln!(
+                                            "Resource inputs: {:?}",
+                                            redact_secrets(&inputs, &secrets)
+                                        );
                                     }
 
                                     if is_complete {
@@ -161,6 +408,10 @@ pub(crate) fn apply(
                                             .unwrap()
                                             .get(&prop.resource)
                                             .is_none()
+                                            && !failed_resources
+                                                .lock()
+                                                .unwrap()
+                                                .contains_key(&prop.resource)
                                         {
                                             let provider_info = {
                                                 let resource_provider_info =
@@ -184,90 +435,106 @@ pub(crate) fn apply(
                                             );
 
                                             if options.verbose {
+                                                let provider_info = ResourceProviderInfo {
+                                                    provider: provider::redact_env(
+                                                        &provider_info.provider,
+                                                    ),
+                                                    ..provider_info.clone()
+                                                };
                                                 eprintln!(
                                                     "Resource details for {}: {:?}",
                                                     resource_name, provider_info
                                                 );
                                             }
 
-                                            let provider_argv =
-                                                provider::parse_provider(&provider_info.provider)?;
-                                            // Run the provider
-                                            let provider = ResourceProviderClient::new(
-                                                ResourceProviderConfig {
-                                                    provider_executable: provider_argv.command,
-                                                    provider_args: provider_argv.args,
-                                                },
-                                            );
-                                            let outputs = provider.create(
-                                                provider_info.resource_type.as_str(),
+                                            let start = Instant::now();
+                                            let result = create_resource(
+                                                providers_ref,
+                                                &provider_info,
+                                                &resource_name,
                                                 &inputs,
-                                            )?;
-
+                                                !validated.contains(&resource_name),
+                                                args,
+                                                options.verbose,
+                                                interrupt_state,
+                                            );
                                             drop(span);
-
-                                            if options.verbose {
-                                                eprintln!("Resource outputs: {:?}", outputs);
-                                            }
-
-                                            resources_outputs
+                                            timings_ref
                                                 .lock()
                                                 .unwrap()
-                                                .insert(prop.resource, outputs.clone());
+                                                .insert(resource_name.clone(), start.elapsed());
 
-                                            // Push the outputs to the evaluator
-                                            for (output_name, output_value) in outputs.iter() {
-                                                let resource_name = {
-                                                    resource_ids_to_names
-                                                        .get(&prop.resource)
+                                            match result {
+                                                Ok(outputs) => {
+                                                    if options.verbose {
+                                                        eprintln!(
+                                                            "Resource outputs: {:?}",
+                                                            redact_secrets(
+                                                                &outputs,
+                                                                &provider_info.secret_properties
+                                                            )
+                                                        );
+                                                    }
+
+                                                    resources_outputs
+                                                        .lock()
                                                         .unwrap()
-                                                        .clone()
-                                                };
-                                                let output_prop = NamedProperty {
-                                                    resource: resource_name,
-                                                    name: output_name.clone(),
-                                                };
-                                                client.send(&EvalRequest::PutResourceOutput(
-                                                    output_prop,
-                                                    output_value.clone(),
-                                                ))?;
-                                            }
+                                                        .insert(prop.resource, outputs.clone());
 
-                                            // Trigger dependents
-                                            {
-                                                let dependents: BTreeSet<Property> = {
-                                                    let resources_blocked =
-                                                        resources_blocked.lock().unwrap();
-                                                    let blocker_resource = prop.resource;
-                                                    outputs
-                                                        .keys()
-                                                        .flat_map(|k| {
-                                                            let blocker_property = Property {
-                                                                resource: blocker_resource,
-                                                                name: k.clone(),
-                                                            };
-                                                            resources_blocked
-                                                                .get(&blocker_property)
-                                                                .unwrap_or(&BTreeSet::new())
-                                                                .clone()
-                                                        })
-                                                        .collect()
-                                                };
-                                                for dependent_property in dependents.iter() {
-                                                    let req_id = client.next_id();
-                                                    client.send(&EvalRequest::GetResourceInput(
-                                                        QueryRequest::new(
-                                                            req_id,
-                                                            dependent_property.clone(),
-                                                        ),
-                                                    ))?;
+                                                    // Push the outputs to the evaluator
+                                                    for (output_name, output_value) in
+                                                        outputs.iter()
+                                                    {
+                                                        let output_prop = NamedProperty {
+                                                            resource: resource_name.clone(),
+                                                            name: output_name.clone(),
+                                                        };
+                                                        client.send(
+                                                            &EvalRequest::PutResourceOutput(
+                                                                output_prop,
+                                                                output_value.clone(),
+                                                            ),
+                                                        )?;
+                                                    }
+                                                    // Dependents are triggered when the evaluator
+                                                    // acknowledges the outputs.
+                                                }
+                                                // An interrupt stops the whole apply
+                                                Err(e)
+                                                    if args.keep_going
+                                                        && !interrupt_state.is_interrupted() =>
+                                                {
+                                                    eprintln!(
+                                                        "Resource {} failed: {:#}",
+                                                        resource_name, e
+                                                    );
+                                                    failed_resources
+                                                        .lock()
+                                                        .unwrap()
+                                                        .insert(prop.resource, format!("{:#}", e));
                                                 }
+                                                Err(e) => return Err(e),
                                             }
                                         }
                                     }
                                 }
                             }
                             ResourceInputState::ResourceInputDependency(dep) => {
+                                input_sources
+                                    .lock()
+                                    .unwrap()
+                                    .insert(dep.dependent.clone(), dep.dependency.clone());
+                                // In a partial apply, the dependency may not be loaded yet.
+                                // The plan has announced the dependencies that it found
+                                load_dependency(
+                                    client,
+                                    deployment_id,
+                                    dep,
+                                    resource_ids,
+                                    &mut loaded_resources.lock().unwrap(),
+                                    &args.exclude,
+                                    !plan.contains_key(&dep.dependency.resource),
+                                )?;
                                 // We might have learned the value after we've asked to evaluate this,
                                 // so we need to check if we have the value now.
                                 let resource_output_opt = {
@@ -276,9 +543,13 @@ pub(crate) fn apply(
                                         resource_ids.get(&dep.dependency.resource).unwrap();
                                     resources_outputs.get(resource_id).cloned()
                                 };
+                                let acknowledged = acknowledged_outputs
+                                    .lock()
+                                    .unwrap()
+                                    .contains(&dep.dependency);
                                 match resource_output_opt {
-                                    Some(_) => {
-                                        // Have have already sent PutResourceOutput for this,
+                                    Some(_) if acknowledged => {
+                                        // The evaluator already has the output,
                                         // so all that's missing is the request to recompute the dependents
 
                                         // Trigger the dependent (TODO dedup?)
@@ -291,7 +562,32 @@ pub(crate) fn apply(
                                             },
                                         )?;
                                     }
-                                    None => {
+                                    _ => {
+                                        // Would waiting for the dependency wait forever?
+                                        // Not if its outputs are already known.
+                                        if resource_output_opt.is_none() {
+                                            let edge = DependencyEdge {
+                                                output: dep.dependency.clone(),
+                                                input: NamedProperty {
+                                                    resource: resource_ids_to_names
+                                                        .get(&dep.dependent.resource)
+                                                        .unwrap()
+                                                        .clone(),
+                                                    name: dep.dependent.name.clone(),
+                                                },
+                                            };
+                                            let mut dependency_edges =
+                                                dependency_edges.lock().unwrap();
+                                            if let Some(cycle) =
+                                                find_cycle(&dependency_edges, &edge)
+                                            {
+                                                bail!(
+                                                    "Resources depend on each other in a cycle: {}",
+                                                    render_cycle(&cycle)
+                                                );
+                                            }
+                                            dependency_edges.push(edge);
+                                        }
                                         let mut resources_blocked =
                                             resources_blocked.lock().unwrap();
                                         let dependency =
@@ -308,6 +604,22 @@ pub(crate) fn apply(
                             }
                         },
                     },
+                    EvalResponse::ResourceOutputAck(output) => {
+                        acknowledged_outputs.lock().unwrap().insert(output.clone());
+                        // Trigger dependents
+                        let blocker_property = Property {
+                            resource: *resource_ids.get(&output.resource).unwrap(),
+                            name: output.name.clone(),
+                        };
+                        let dependents = resources_blocked
+                            .lock()
+                            .unwrap()
+                            .remove(&blocker_property)
+                            .unwrap_or_default();
+                        for dependent_property in dependents {
+                            client.query(EvalRequest::GetResourceInput, dependent_property)?;
+                        }
+                    }
                     EvalResponse::TracingEvent(_) => {
                         // already handled in EvalClient
                     }
@@ -318,51 +630,1544 @@ pub(crate) fn apply(
 
                 // Are we done?
                 {
-                    if resources.len() == resources_outputs.lock().unwrap().len() {
+                    let failed = failed_resources.lock().unwrap();
+                    let skipped = skipped_resources(
+                        &failed.keys().cloned().collect(),
+                        &resources_blocked.lock().unwrap(),
+                    );
+                    if loaded_resources.lock().unwrap().len()
+                        == resources_outputs.lock().unwrap().len() + failed.len() + skipped.len()
+                    {
                         let resources_inputs = resource_inputs.lock().unwrap();
                         let resources_outputs = resources_outputs.lock().unwrap();
                         Ok(Some((
                             resources_inputs.clone(),
                             resources_outputs.clone(),
                             resource_input_values.lock().unwrap().clone(),
+                            failed.clone(),
+                            skipped,
                         )))
                     } else {
                         Ok(None)
                     }
                 }
-            })?
+            };
+            // Handle the input states of the plan as if the evaluator reported
+            // them again, so that the resources whose inputs are known are
+            // created, and the others wait for their dependencies.
+            let mut done = None;
+            for (name, resource) in plan.iter() {
+                let id = *resource_ids.get(name).unwrap();
+                for (input, state) in resource.inputs.iter() {
+                    let property = Property {
+                        resource: id,
+                        name: input.clone(),
+                    };
+                    let state = match state {
+                        PreviewInput::Value(value) => ResourceInputState::ResourceInputValue((
+                            property.clone(),
+                            value.clone(),
+                        )),
+                        PreviewInput::Dependency(dependency) => {
+                            ResourceInputState::ResourceInputDependency(ResourceInputDependency {
+                                dependent: property.clone(),
+                                dependency: dependency.clone(),
+                            })
+                        }
+                        PreviewInput::Secret => unreachable!("the plan is not redacted"),
+                    };
+                    let resp = EvalResponse::QueryResponse(
+                        c.next_id(),
+                        QueryResponseValue::ResourceInputState((property, state)),
+                    );
+                    if done.is_none() {
+                        done = handle(c, &resp)?;
+                    }
+                }
+            }
+            match done {
+                Some(done) => done,
+                None => c.receive_until(&handle)?,
+            }
         };
 
+        for (_, idle) in providers.into_inner().unwrap() {
+            for mut provider in idle {
+                provider.close()?;
+            }
+        }
+
         if options.verbose {
             eprintln!();
             eprintln!("Done!");
         }
         eprintln!("The following resources were created:");
-        for (resource_name, resource_id) in resource_ids_clone {
+        let resource_provider_info = resource_provider_info.lock().unwrap();
+        for (resource_name, resource_id) in resource_ids.iter() {
+            if !resource_outputs.contains_key(resource_id) {
+                // Not selected
+                continue;
+            }
+            let secrets = secret_properties(
+                &resource_provider_info,
+                &input_sources.lock().unwrap(),
+                &resource_ids,
+                *resource_id,
+            );
             eprintln!("Resource {}:", resource_name);
             {
-                let inputs = resource_inputs.get(&resource_id).unwrap();
+                let inputs = resource_inputs.get(resource_id).unwrap();
                 for input in inputs.iter() {
                     let property = Property {
-                        resource: resource_id,
+                        resource: *resource_id,
                         name: input.clone(),
                     };
                     let input_value = resource_input_values.get(&property).unwrap();
-                    eprintln!("  - input {}: {}", input, indented_json(input_value));
+                    eprintln!(
+                        "  - input {}: {}",
+                        input,
+                        redacted_json(input, input_value, &secrets)
+                    );
                 }
             }
             {
-                let outputs = resource_outputs.get(&resource_id).unwrap();
+                let outputs = resource_outputs.get(resource_id).unwrap();
                 for (k, v) in outputs.iter() {
-                    eprintln!("  - output {}: {}", k, indented_json(v));
+                    eprintln!("  - output {}: {}", k, redacted_json(k, v, &secrets));
+                }
+            }
+        }
+        if args.timings || options.verbose {
+            eprintln!("Time spent per resource:");
+            for line in format_timings(&timings.into_inner().unwrap()) {
+                eprintln!("  {}", line);
+            }
+        }
+        if args.keep_going {
+            eprintln!(
+                "Summary: {} succeeded, {} failed, {} skipped",
+                resource_outputs.len(),
+                failed.len(),
+                skipped.len()
+            );
+            for (resource_name, resource_id) in resource_ids.iter() {
+                if let Some(e) = failed.get(resource_id) {
+                    eprintln!("  - failed: {}: {}", resource_name, e);
+                } else if skipped.contains(resource_id) {
+                    eprintln!("  - skipped: {}", resource_name);
                 }
             }
+            if !failed.is_empty() {
+                bail!(
+                    "{} of {} resources failed to apply",
+                    failed.len(),
+                    resource_outputs.len() + failed.len() + skipped.len()
+                );
+            }
         }
         Ok(())
     })
 }
 
+/// How to run the provider of a resource, as declared in its `provider` attribute.
+fn provider_config(
+    provider_info: &ResourceProviderInfo,
+    args: &Args,
+    verbose: bool,
+) -> Result<ResourceProviderConfig> {
+    let provider_argv = provider::parse_provider(&provider_info.provider)?;
+    Ok(ResourceProviderConfig {
+        provider_executable: provider_argv.command,
+        provider_args: provider_argv.args,
+        env: provider_argv.env,
+        clear_env: provider_argv.clear_env,
+        startup_timeout: args.provider_startup_timeout.map(Duration::from_secs),
+        verbose,
+        allowed_executables: if args.allow_provider.is_empty() {
+            None
+        } else {
+            Some(args.allow_provider.clone())
+        },
+        // The resource timeout is a deadline for all requests of a resource; see create_resource
+        request_timeout: None,
+    })
+}
+
+/// The provider clients that are not in use, by configuration.
+///
+/// A client is taken out while it works on a resource, so that the lock is not
+/// held during requests, and resources of the same provider can be created at
+/// the same time, each by its own process.
+type ProviderPool = Mutex<BTreeMap<ResourceProviderConfig, Vec<ResourceProviderClient>>>;
+
+/// Run `f` with a client for the provider of a resource, taken from the pool.
+///
+/// The requests that `f` makes must be answered within the timeout of the
+/// resource.
+fn with_provider<T>(
+    providers: &ProviderPool,
+    provider_info: &ResourceProviderInfo,
+    resource_name: &str,
+    args: &Args,
+    verbose: bool,
+    interrupt_state: &InterruptState,
+    f: impl FnOnce(&mut ResourceProviderClient) -> Result<T>,
+) -> Result<T> {
+    let provider_config = provider_config(provider_info, args, verbose)?;
+    let timeout = provider_info
+        .timeout_seconds
+        .or(args.resource_timeout)
+        .map(Duration::from_secs);
+    let idle = providers
+        .lock()
+        .unwrap()
+        .get_mut(&provider_config)
+        .and_then(|idle| idle.pop());
+    let mut provider = idle.unwrap_or_else(|| {
+        let mut client = ResourceProviderClient::new(provider_config.clone());
+        let interrupt_state = interrupt_state.clone();
+        client.set_interrupt_check(move || interrupt_state.is_interrupted());
+        client
+    });
+    provider.set_deadline(timeout.map(|timeout| Instant::now() + timeout));
+    let r = f(&mut provider);
+    provider.set_deadline(None);
+    // After a failure, the client starts a new process when it is used again
+    providers
+        .lock()
+        .unwrap()
+        .entry(provider_config)
+        .or_default()
+        .push(provider);
+    r.map_err(
+        |e| match (e.downcast_ref::<RequestTimeoutError>(), timeout) {
+            (Some(_), Some(timeout)) => anyhow::anyhow!(
+                "resource {} timed out after {} s",
+                resource_name,
+                timeout.as_secs()
+            ),
+            _ => e,
+        },
+    )
+}
+
+/// Ask the provider whether the inputs of a resource are valid, if it supports that.
+fn validate_inputs(
+    provider: &mut ResourceProviderClient,
+    provider_info: &ResourceProviderInfo,
+    resource_name: &str,
+    inputs: &BTreeMap<String, Value>,
+) -> Result<()> {
+    if provider.supports("validate")? {
+        let validation = provider.validate(provider_info.resource_type.as_str(), inputs)?;
+        if !validation.problems.is_empty() {
+            bail!(
+                "Invalid inputs for resource {}:\n{}",
+                resource_name,
+                format_problems(&validation.problems)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Validate the inputs of all planned resources whose inputs are known, and
+/// report all that are invalid together, so that nothing is applied yet.
+///
+/// Returns the names of the resources that were validated. Resources with
+/// inputs from other resources' outputs are validated before they are created.
+fn validate_resources(
+    providers: &ProviderPool,
+    plan: &BTreeMap<String, PlannedResource>,
+    args: &Args,
+    verbose: bool,
+    interrupt_state: &InterruptState,
+) -> Result<BTreeSet<String>> {
+    let mut validated = BTreeSet::new();
+    let mut failures = Vec::new();
+    for (resource_name, resource) in plan {
+        let Some(inputs) = resource.known_inputs() else {
+            continue;
+        };
+        let r = with_provider(
+            providers,
+            &resource.info,
+            resource_name,
+            args,
+            verbose,
+            interrupt_state,
+            |provider| validate_inputs(provider, &resource.info, resource_name, &inputs),
+        );
+        interrupt_state.check_interrupted()?;
+        if let Err(e) = r {
+            failures.push(format!("{:#}", e));
+        }
+        validated.insert(resource_name.clone());
+    }
+    if !failures.is_empty() {
+        bail!(
+            "{} of {} resources failed validation; nothing was applied:\n{}",
+            failures.len(),
+            plan.len(),
+            failures.join("\n")
+        );
+    }
+    Ok(validated)
+}
+
+/// Run the provider of a resource to create it, returning its outputs.
+///
+/// With `validate`, the inputs are validated first.
+#[allow(clippy::too_many_arguments)]
+fn create_resource(
+    providers: &ProviderPool,
+    provider_info: &ResourceProviderInfo,
+    resource_name: &str,
+    inputs: &BTreeMap<String, Value>,
+    validate: bool,
+    args: &Args,
+    verbose: bool,
+    interrupt_state: &InterruptState,
+) -> Result<BTreeMap<String, Value>> {
+    with_provider(
+        providers,
+        provider_info,
+        resource_name,
+        args,
+        verbose,
+        interrupt_state,
+        |provider| {
+            if validate {
+                validate_inputs(provider, provider_info, resource_name, inputs)?;
+            }
+            provider.create(provider_info.resource_type.as_str(), inputs)
+        },
+    )
+}
+
+/// The resources that can not be applied, because they depend, directly or
+/// indirectly, on a resource that failed.
+///
+/// `blocked` maps the outputs that have not been produced yet to the inputs
+/// that are waiting for them.
+fn skipped_resources(
+    failed: &BTreeSet<Id<ResourceType>>,
+    blocked: &BTreeMap<Property, BTreeSet<Property>>,
+) -> BTreeSet<Id<ResourceType>> {
+    let mut skipped = BTreeSet::new();
+    loop {
+        let mut changed = false;
+        for (blocker, dependents) in blocked.iter() {
+            if !failed.contains(&blocker.resource) && !skipped.contains(&blocker.resource) {
+                continue;
+            }
+            for dependent in dependents.iter() {
+                if !failed.contains(&dependent.resource) {
+                    changed |= skipped.insert(dependent.resource);
+                }
+            }
+        }
+        if !changed {
+            return skipped;
+        }
+    }
+}
+
+/// Ask the evaluator for a resource's provider and inputs.
+pub(crate) fn load_resource(
+    c: &mut EvalClient,
+    deployment_id: Id<DeploymentType>,
+    name: &str,
+    id: Id<ResourceType>,
+) -> Result<()> {
+    c.send(&EvalRequest::LoadResource(AssignRequest {
+        assign_to: id,
+        payload: ResourceRequest {
+            deployment: deployment_id,
+            name: name.to_string(),
+        },
+    }))?;
+    // TODO: check for errors on this id
+    c.query(EvalRequest::GetResource, id)?;
+    // TODO: check for errors on this id
+    c.query(EvalRequest::ListResourceInputs, id)?;
+    Ok(())
+}
+
+/// Make sure that the resource that `dep` refers to is loaded, so that it will be applied.
+fn load_dependency(
+    c: &mut EvalClient,
+    deployment_id: Id<DeploymentType>,
+    dep: &ResourceInputDependency,
+    resource_ids: &BTreeMap<String, Id<ResourceType>>,
+    loaded_resources: &mut BTreeSet<Id<ResourceType>>,
+    exclude: &[String],
+    announce: bool,
+) -> Result<()> {
+    let dependency_id = *resource_ids.get(&dep.dependency.resource).unwrap();
+    if loaded_resources.contains(&dependency_id) {
+        return Ok(());
+    }
+    let dependent_name = resource_ids
+        .iter()
+        .find(|(_, id)| **id == dep.dependent.resource)
+        .map(|(name, _)| name.as_str())
+        .unwrap();
+    if exclude.contains(&dep.dependency.resource) {
+        bail!(
+            "Resource {} depends on resource {}, which was excluded",
+            dependent_name,
+            dep.dependency.resource
+        );
+    }
+    if announce {
+        eprintln!(
+            "Also applying resource {}, because resource {} depends on it",
+            dep.dependency.resource, dependent_name
+        );
+    }
+    load_resource(c, deployment_id, &dep.dependency.resource, dependency_id)?;
+    loaded_resources.insert(dependency_id);
+    Ok(())
+}
+
+/// The secret properties of a resource: those declared in its `secretProperties`,
+/// and the inputs that are taken from a secret output of another resource.
+fn secret_properties(
+    provider_info: &BTreeMap<Id<ResourceType>, ResourceProviderInfo>,
+    input_sources: &BTreeMap<Property, NamedProperty>,
+    resource_ids: &BTreeMap<String, Id<ResourceType>>,
+    resource: Id<ResourceType>,
+) -> BTreeSet<String> {
+    let declared = |resource: &Id<ResourceType>| {
+        provider_info
+            .get(resource)
+            .map(|info| info.secret_properties.clone())
+            .unwrap_or_default()
+    };
+    let mut secrets = declared(&resource);
+    for (input, source) in input_sources {
+        let source_is_secret = resource_ids
+            .get(&source.resource)
+            .is_some_and(|id| declared(id).contains(&source.name));
+        if input.resource == resource && source_is_secret {
+            secrets.insert(input.name.clone());
+        }
+    }
+    secrets
+}
+
+/// `values`, with the values of the secret properties replaced, for printing.
+fn redact_secrets(
+    values: &BTreeMap<String, Value>,
+    secrets: &BTreeSet<String>,
+) -> BTreeMap<String, Value> {
+    values
+        .iter()
+        .map(|(name, value)| {
+            if secrets.contains(name) {
+                (name.clone(), Value::String(REDACTED.to_string()))
+            } else {
+                (name.clone(), value.clone())
+            }
+        })
+        .collect()
+}
+
+/// Like [`indented_json`], but shows a secret property as redacted.
+fn redacted_json(name: &str, value: &Value, secrets: &BTreeSet<String>) -> String {
+    if secrets.contains(name) {
+        REDACTED.to_string()
+    } else {
+        indented_json(value)
+    }
+}
+
+/// What is known about a resource input without applying anything.
+pub(crate) enum PreviewInput {
+    Value(Value),
+    /// The input is determined by an output of another resource.
+    Dependency(NamedProperty),
+    /// The input has a value, but it is secret.
+    Secret,
+}
+
+impl std::fmt::Display for PreviewInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreviewInput::Value(v) => write!(f, "{}", indented_json(v)),
+            PreviewInput::Secret => write!(f, "{}", REDACTED),
+            PreviewInput::Dependency(p) => {
+                write!(f, "(output {} of resource {})", p.name, p.resource)
+            }
+        }
+    }
+}
+
+/// What is known about a resource without applying anything.
+pub(crate) struct PreviewResource {
+    pub(crate) resource_type: String,
+    pub(crate) provider: Value,
+    pub(crate) inputs: BTreeMap<String, PreviewInput>,
+}
+
+/// A resource as far as it can be evaluated without applying anything.
+///
+/// Unlike [`PreviewResource`], this includes secrets, so it must not be shown.
+struct PlannedResource {
+    info: ResourceProviderInfo,
+    /// Only [`PreviewInput::Value`] and [`PreviewInput::Dependency`]
+    inputs: BTreeMap<String, PreviewInput>,
+}
+
+impl PlannedResource {
+    /// The input values, if none of them depend on other resources.
+    fn known_inputs(&self) -> Option<BTreeMap<String, Value>> {
+        self.inputs
+            .iter()
+            .map(|(name, input)| match input {
+                PreviewInput::Value(v) => Some((name.clone(), v.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Evaluate the resources and their inputs as far as possible without running
+/// any resource providers.
+pub(crate) fn preview(
+    c: &mut EvalClient,
+    deployment_id: Id<DeploymentType>,
+    resource_ids: &BTreeMap<String, Id<ResourceType>>,
+    loaded_resources: BTreeSet<Id<ResourceType>>,
+    exclude: &[String],
+) -> Result<BTreeMap<String, PreviewResource>> {
+    let plan = evaluate_plan(c, deployment_id, resource_ids, loaded_resources, exclude)?;
+    Ok(plan
+        .into_iter()
+        .map(|(resource_name, PlannedResource { info, inputs })| {
+            let inputs = inputs
+                .into_iter()
+                .map(|(input, state)| {
+                    let state = match state {
+                        PreviewInput::Value(_) if info.secret_properties.contains(&input) => {
+                            PreviewInput::Secret
+                        }
+                        state => state,
+                    };
+                    (input, state)
+                })
+                .collect();
+            let resource = PreviewResource {
+                resource_type: info.resource_type,
+                provider: provider::redact_env(&info.provider),
+                inputs,
+            };
+            (resource_name, resource)
+        })
+        .collect())
+}
+
+/// Like [`preview`], but without redacting anything.
+fn evaluate_plan(
+    c: &mut EvalClient,
+    deployment_id: Id<DeploymentType>,
+    resource_ids: &BTreeMap<String, Id<ResourceType>>,
+    loaded_resources: BTreeSet<Id<ResourceType>>,
+    exclude: &[String],
+) -> Result<BTreeMap<String, PlannedResource>> {
+    let loaded_resources = Mutex::new(loaded_resources);
+    let provider_info: Mutex<BTreeMap<Id<ResourceType>, ResourceProviderInfo>> =
+        Mutex::new(BTreeMap::new());
+    let resource_inputs: Mutex<BTreeMap<Id<ResourceType>, Vec<String>>> =
+        Mutex::new(BTreeMap::new());
+    let input_states: Mutex<BTreeMap<Property, PreviewInput>> = Mutex::new(BTreeMap::new());
+
+    c.receive_until(|client, resp| {
+        match resp {
+            EvalResponse::Error(_id, e) => {
+                bail!("Error during evaluation: {}", e);
+            }
+            EvalResponse::QueryResponse(_id, payload) => match payload {
+                QueryResponseValue::ListResourceInputs((res, input_names)) => {
+                    resource_inputs
+                        .lock()
+                        .unwrap()
+                        .insert(*res, input_names.clone());
+                    for input_name in input_names {
+                        client.query(
+                            EvalRequest::GetResourceInput,
+                            Property {
+                                resource: *res,
+                                name: input_name.clone(),
+                            },
+                        )?;
+                    }
+                }
+                QueryResponseValue::ResourceProviderInfo(info) => {
+                    provider_info.lock().unwrap().insert(info.id, info.clone());
+                }
+                QueryResponseValue::ResourceInputState((property, st)) => match st {
+                    ResourceInputState::ResourceInputValue((_, value)) => {
+                        input_states
+                            .lock()
+                            .unwrap()
+                            .insert(property.clone(), PreviewInput::Value(value.clone()));
+                    }
+                    ResourceInputState::ResourceInputDependency(dep) => {
+                        load_dependency(
+                            client,
+                            deployment_id,
+                            dep,
+                            resource_ids,
+                            &mut loaded_resources.lock().unwrap(),
+                            exclude,
+                            true,
+                        )?;
+                        input_states.lock().unwrap().insert(
+                            property.clone(),
+                            PreviewInput::Dependency(dep.dependency.clone()),
+                        );
+                    }
+                },
+                QueryResponseValue::ListDeployments(_)
+                | QueryResponseValue::FlakeMetadata(_)
+                | QueryResponseValue::DeploymentOptions(_)
+                | QueryResponseValue::ListResources(_) => {}
+            },
+            EvalResponse::ResourceOutputAck(_) | EvalResponse::TracingEvent(_) => {
+                // already handled in EvalClient
+            }
+        }
+
+        // Are we done?
+        let loaded_resources = loaded_resources.lock().unwrap();
+        let provider_info = provider_info.lock().unwrap();
+        let resource_inputs = resource_inputs.lock().unwrap();
+        let input_states = input_states.lock().unwrap();
+        let is_complete = loaded_resources.iter().all(|id| {
+            provider_info.contains_key(id)
+                && resource_inputs.get(id).is_some_and(|inputs| {
+                    inputs.iter().all(|name| {
+                        input_states.contains_key(&Property {
+                            resource: *id,
+                            name: name.clone(),
+                        })
+                    })
+                })
+        });
+        Ok(if is_complete { Some(()) } else { None })
+    })?;
+
+    let loaded_resources = loaded_resources.into_inner().unwrap();
+    let mut provider_info = provider_info.into_inner().unwrap();
+    let mut resource_inputs = resource_inputs.into_inner().unwrap();
+    let mut input_states = input_states.into_inner().unwrap();
+    let mut resources = BTreeMap::new();
+    for (resource_name, resource_id) in resource_ids {
+        if !loaded_resources.contains(resource_id) {
+            continue;
+        }
+        let info = provider_info.remove(resource_id).unwrap();
+        let inputs = resource_inputs
+            .remove(resource_id)
+            .unwrap()
+            .into_iter()
+            .map(|input| {
+                let state = input_states
+                    .remove(&Property {
+                        resource: *resource_id,
+                        name: input.clone(),
+                    })
+                    .unwrap();
+                (input, state)
+            })
+            .collect();
+        resources.insert(resource_name.clone(), PlannedResource { info, inputs });
+    }
+    Ok(resources)
+}
+
+fn print_preview(resources: &BTreeMap<String, PreviewResource>) {
+    eprintln!("Dry run; no resources were changed. The following resources would be applied:");
+    for (resource_name, resource) in resources {
+        eprintln!(
+            "Resource {} (type {}):",
+            resource_name, resource.resource_type
+        );
+        for (input, state) in &resource.inputs {
+            eprintln!("  - input {}: {}", input, state);
+        }
+    }
+}
+
+/// The `--plan-json` output.
+///
+/// Tools may rely on this format. Fields may be added, but changing or
+/// removing a field requires a new `version`.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct PlanJson {
+    version: u32,
+    deployment: String,
+    resources: BTreeMap<String, PlanResource>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct PlanResource {
+    action: PlanAction,
+    #[serde(rename = "type")]
+    resource_type: String,
+    provider: Value,
+    inputs: BTreeMap<String, PlanInput>,
+    /// Resources whose outputs this resource's inputs refer to
+    depends_on: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum PlanAction {
+    /// Create the resource, or update it if it exists. Without state, the
+    /// plan can't tell which; `create`, `update` and `delete` are reserved
+    /// for when it can.
+    Apply,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum PlanInput {
+    Value(Value),
+    /// The input is determined by an output of another resource.
+    Dependency {
+        resource: String,
+        output: String,
+    },
+    /// The input has a value, but it is listed in the resource's `secretProperties`.
+    Secret,
+}
+
+const PLAN_JSON_VERSION: u32 = 0;
+
+fn plan_json(deployment: &str, resources: &BTreeMap<String, PreviewResource>) -> PlanJson {
+    let resources = resources
+        .iter()
+        .map(|(name, resource)| {
+            let inputs: BTreeMap<String, PlanInput> = resource
+                .inputs
+                .iter()
+                .map(|(input, state)| {
+                    let input_json = match state {
+                        PreviewInput::Value(v) => PlanInput::Value(v.clone()),
+                        PreviewInput::Secret => PlanInput::Secret,
+                        PreviewInput::Dependency(p) => PlanInput::Dependency {
+                            resource: p.resource.clone(),
+                            output: p.name.clone(),
+                        },
+                    };
+                    (input.clone(), input_json)
+                })
+                .collect();
+            let depends_on = resource
+                .inputs
+                .values()
+                .filter_map(|state| match state {
+                    PreviewInput::Value(_) | PreviewInput::Secret => None,
+                    PreviewInput::Dependency(p) => Some(p.resource.clone()),
+                })
+                .collect();
+            let plan_resource = PlanResource {
+                action: PlanAction::Apply,
+                resource_type: resource.resource_type.clone(),
+                provider: resource.provider.clone(),
+                inputs,
+                depends_on,
+            };
+            (name.clone(), plan_resource)
+        })
+        .collect();
+    PlanJson {
+        version: PLAN_JSON_VERSION,
+        deployment: deployment.to_string(),
+        resources,
+    }
+}
+
+pub(crate) fn check_deployment_exists(deployments: &[String], name: &str) -> Result<()> {
+    if !deployments.iter().any(|d| d == name) {
+        if deployments.is_empty() {
+            bail!(
+                "No such deployment: {}; the flake does not define any deployments in nixops4Deployments",
+                name
+            );
+        }
+        bail!(
+            "No such deployment: {}; available deployments: {}",
+            name,
+            deployments.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Ask before applying, unless `yes` is set.
+///
+/// Without a terminal to ask on, applying proceeds.
+fn confirm_apply(frontend: &mut dyn Frontend, yes: bool, interactive: bool) -> Result<()> {
+    if yes {
+        return Ok(());
+    }
+    if interactive && !frontend.confirm("Apply these changes?")? {
+        bail!("Apply aborted; no changes were made");
+    }
+    Ok(())
+}
+
+/// Warn about, or with `require_clean`, refuse to apply from a flake with
+/// uncommitted changes, as the result can not be traced back to a revision.
+fn check_flake_clean(metadata: &FlakeMetadata, require_clean: bool) -> Result<()> {
+    if let Some(dirty_rev) = &metadata.dirty_rev {
+        if require_clean {
+            bail!(
+                "Refusing to apply from a dirty flake (uncommitted changes on top of {})",
+                dirty_rev.trim_end_matches("-dirty")
+            );
+        }
+        eprintln!(
+            "warning: applying from a dirty flake (uncommitted changes); result may not be reproducible"
+        );
+    }
+    Ok(())
+}
+
+/// Determine which resources to start applying, from the `--resource` and
+/// `--exclude` options. Dependencies are not included; they are only known
+/// during evaluation.
+fn select_resources(
+    resources: &[String],
+    include: &[String],
+    exclude: &[String],
+) -> Result<BTreeSet<String>> {
+    for name in include.iter().chain(exclude.iter()) {
+        if !resources.contains(name) {
+            bail!("Deployment does not contain a resource named {}", name);
+        }
+    }
+    Ok(resources
+        .iter()
+        .filter(|r| include.is_empty() || include.contains(r))
+        .filter(|r| !exclude.contains(r))
+        .cloned()
+        .collect())
+}
+
+/// A resource input that is waiting for an output of another resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DependencyEdge {
+    output: NamedProperty,
+    input: NamedProperty,
+}
+
+/// If adding `new` to `edges` creates a cycle, return the cycle, starting with `new`.
+fn find_cycle(edges: &[DependencyEdge], new: &DependencyEdge) -> Option<Vec<DependencyEdge>> {
+    // Search for a path from the resource that is waiting back to the
+    // resource it is waiting for, in breadth first order to find a short cycle.
+    let target = &new.output.resource;
+    let mut reached_by: BTreeMap<&str, Option<&DependencyEdge>> = BTreeMap::new();
+    reached_by.insert(new.input.resource.as_str(), None);
+    let mut queue = std::collections::VecDeque::from([new.input.resource.as_str()]);
+    while let Some(resource) = queue.pop_front() {
+        if resource == target {
+            let mut path = Vec::new();
+            let mut current = resource;
+            while let Some(Some(edge)) = reached_by.get(current) {
+                path.push((*edge).clone());
+                current = edge.output.resource.as_str();
+            }
+            path.reverse();
+            let mut cycle = vec![new.clone()];
+            cycle.extend(path);
+            return Some(cycle);
+        }
+        // Resources that wait for an output of `resource`
+        for edge in edges.iter().filter(|e| e.output.resource == resource) {
+            let next = edge.input.resource.as_str();
+            if !reached_by.contains_key(next) {
+                reached_by.insert(next, Some(edge));
+                queue.push_back(next);
+            }
+        }
+    }
+    None
+}
+
+/// Render a cycle as `a.out -> b.in -> b.out -> a.in -> a.out`.
+fn render_cycle(cycle: &[DependencyEdge]) -> String {
+    let mut parts = Vec::new();
+    for edge in cycle {
+        parts.push(format!("{}.{}", edge.output.resource, edge.output.name));
+        parts.push(format!("{}.{}", edge.input.resource, edge.input.name));
+    }
+    if let Some(first) = cycle.first() {
+        parts.push(format!("{}.{}", first.output.resource, first.output.name));
+    }
+    parts.join(" -> ")
+}
+
+/// Render resource durations, slowest first.
+fn format_timings(timings: &BTreeMap<String, Duration>) -> Vec<String> {
+    let mut timings: Vec<(&String, &Duration)> = timings.iter().collect();
+    timings.sort_by(|a, b| b.1.cmp(a.1));
+    timings
+        .into_iter()
+        .map(|(name, duration)| format!("{:>8.3}s  {}", duration.as_secs_f64(), name))
+        .collect()
+}
+
 fn indented_json(v: &Value) -> String {
     let s = serde_json::to_string_pretty(v).unwrap();
     s.replace("\n", "\n            ")
 }
+
+/// Render the problems reported by a provider, one per line.
+fn format_problems(problems: &[ValidationProblem]) -> String {
+    problems
+        .iter()
+        .map(|p| match &p.property {
+            Some(property) => format!("  - {}: {}", property, p.message),
+            None => format!("  - {}", p.message),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(xs: &[&str]) -> Vec<String> {
+        xs.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn parse_args(args: &[&str]) -> Result<Args, clap::Error> {
+        use clap::Parser as _;
+        Args::try_parse_from(std::iter::once("apply").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_deployment_name() {
+        assert_eq!(parse_args(&[]).unwrap().deployment_name(), "default");
+        assert_eq!(
+            parse_args(&["staging"]).unwrap().deployment_name(),
+            "staging"
+        );
+        assert_eq!(
+            parse_args(&["--deployment", "prod"])
+                .unwrap()
+                .deployment_name(),
+            "prod"
+        );
+        assert!(parse_args(&["staging", "--deployment", "prod"]).is_err());
+    }
+
+    #[test]
+    fn test_check_deployment_exists() {
+        let deployments = names(&["prod", "staging"]);
+        check_deployment_exists(&deployments, "prod").unwrap();
+        let e = check_deployment_exists(&deployments, "default").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "No such deployment: default; available deployments: prod, staging"
+        );
+        let e = check_deployment_exists(&[], "default").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "No such deployment: default; the flake does not define any deployments in nixops4Deployments"
+        );
+    }
+
+    fn flake_metadata(dirty_rev: Option<&str>) -> FlakeMetadata {
+        FlakeMetadata {
+            id: nixops4_core::eval_api::Ids::new().next(),
+            rev: None,
+            last_modified: Some(1700000000),
+            nar_hash: None,
+            dirty_rev: dirty_rev.map(|s| s.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_check_flake_clean() {
+        check_flake_clean(&flake_metadata(None), false).unwrap();
+        check_flake_clean(&flake_metadata(None), true).unwrap();
+        check_flake_clean(&flake_metadata(Some("0123abc-dirty")), false).unwrap();
+        let e = check_flake_clean(&flake_metadata(Some("0123abc-dirty")), true).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Refusing to apply from a dirty flake (uncommitted changes on top of 0123abc)"
+        );
+    }
+
+    #[test]
+    fn test_skipped_resources() {
+        let mut ids = nixops4_core::eval_api::Ids::new();
+        let [a, b, c, d]: [Id<ResourceType>; 4] = [ids.next(), ids.next(), ids.next(), ids.next()];
+        let prop = |resource, name: &str| Property {
+            resource,
+            name: name.to_string(),
+        };
+        // b waits for an output of a, and c for an output of b
+        let blocked = BTreeMap::from([
+            (prop(a, "out"), BTreeSet::from([prop(b, "in")])),
+            (prop(b, "out"), BTreeSet::from([prop(c, "in")])),
+        ]);
+        assert_eq!(
+            skipped_resources(&BTreeSet::new(), &blocked),
+            BTreeSet::new()
+        );
+        assert_eq!(
+            skipped_resources(&BTreeSet::from([a]), &blocked),
+            BTreeSet::from([b, c])
+        );
+        assert_eq!(
+            skipped_resources(&BTreeSet::from([b]), &blocked),
+            BTreeSet::from([c])
+        );
+        assert_eq!(
+            skipped_resources(&BTreeSet::from([d]), &blocked),
+            BTreeSet::new()
+        );
+    }
+
+    #[test]
+    fn test_select_resources_all() {
+        let resources = names(&["a", "b", "c"]);
+        let selected = select_resources(&resources, &[], &[]).unwrap();
+        assert_eq!(selected, resources.into_iter().collect());
+    }
+
+    #[test]
+    fn test_select_resources_include() {
+        let resources = names(&["a", "b", "c"]);
+        let selected = select_resources(&resources, &names(&["b"]), &[]).unwrap();
+        assert_eq!(selected, BTreeSet::from(["b".to_string()]));
+    }
+
+    #[test]
+    fn test_select_resources_exclude() {
+        let resources = names(&["a", "b", "c"]);
+        let selected = select_resources(&resources, &names(&["a", "b"]), &names(&["a"])).unwrap();
+        assert_eq!(selected, BTreeSet::from(["b".to_string()]));
+        let selected = select_resources(&resources, &[], &names(&["c"])).unwrap();
+        assert_eq!(selected, BTreeSet::from(["a".to_string(), "b".to_string()]));
+    }
+
+    #[test]
+    fn test_preview_input_display() {
+        let dependency = PreviewInput::Dependency(NamedProperty {
+            resource: "b".to_string(),
+            name: "out".to_string(),
+        });
+        assert_eq!(dependency.to_string(), "(output out of resource b)");
+        let value = PreviewInput::Value(Value::String("hi".to_string()));
+        assert_eq!(value.to_string(), "\"hi\"");
+    }
+
+    #[test]
+    fn test_plan_json() {
+        let provider = serde_json::json!({ "type": "stdio", "command": "local", "args": [] });
+        let resources = BTreeMap::from([
+            (
+                "a".to_string(),
+                PreviewResource {
+                    resource_type: "file".to_string(),
+                    provider: provider.clone(),
+                    inputs: BTreeMap::from([
+                        (
+                            "name".to_string(),
+                            PreviewInput::Value(Value::String("a.txt".to_string())),
+                        ),
+                        (
+                            "contents".to_string(),
+                            PreviewInput::Dependency(NamedProperty {
+                                resource: "b".to_string(),
+                                name: "stdout".to_string(),
+                            }),
+                        ),
+                    ]),
+                },
+            ),
+            (
+                "b".to_string(),
+                PreviewResource {
+                    resource_type: "exec".to_string(),
+                    provider: provider.clone(),
+                    inputs: BTreeMap::new(),
+                },
+            ),
+        ]);
+        let plan = serde_json::to_value(plan_json("default", &resources)).unwrap();
+        assert_eq!(
+            plan,
+            serde_json::json!({
+                "version": 0,
+                "deployment": "default",
+                "resources": {
+                    "a": {
+                        "action": "apply",
+                        "type": "file",
+                        "provider": provider,
+                        "inputs": {
+                            "contents": { "dependency": { "resource": "b", "output": "stdout" } },
+                            "name": { "value": "a.txt" },
+                        },
+                        "dependsOn": ["b"],
+                    },
+                    "b": {
+                        "action": "apply",
+                        "type": "exec",
+                        "provider": provider,
+                        "inputs": {},
+                        "dependsOn": [],
+                    },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_secret_properties_from_secret_outputs() {
+        let mut ids = nixops4_core::eval_api::Ids::new();
+        let (db, app) = (ids.next(), ids.next());
+        let info = |id, secrets: &[&str]| ResourceProviderInfo {
+            id,
+            provider: Value::Null,
+            resource_type: "test".to_string(),
+            secret_properties: secrets.iter().map(|s| s.to_string()).collect(),
+            timeout_seconds: None,
+        };
+        let provider_info = BTreeMap::from([(db, info(db, &["password"])), (app, info(app, &[]))]);
+        let resource_ids = BTreeMap::from([("db".to_string(), db), ("app".to_string(), app)]);
+        let source = |name: &str| NamedProperty {
+            resource: "db".to_string(),
+            name: name.to_string(),
+        };
+        let input = |name: &str| Property {
+            resource: app,
+            name: name.to_string(),
+        };
+        let input_sources = BTreeMap::from([
+            (input("dbPassword"), source("password")),
+            (input("dbHost"), source("host")),
+        ]);
+        assert_eq!(
+            secret_properties(&provider_info, &input_sources, &resource_ids, app),
+            BTreeSet::from(["dbPassword".to_string()])
+        );
+        assert_eq!(
+            secret_properties(&provider_info, &input_sources, &resource_ids, db),
+            BTreeSet::from(["password".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_redact_secrets() {
+        let values = BTreeMap::from([
+            ("password".to_string(), Value::from("hunter2")),
+            ("user".to_string(), Value::from("admin")),
+        ]);
+        let secrets = BTreeSet::from(["password".to_string()]);
+        let redacted = redact_secrets(&values, &secrets);
+        assert_eq!(redacted["password"], Value::from(REDACTED));
+        assert_eq!(redacted["user"], Value::from("admin"));
+        assert_eq!(
+            redacted_json("password", &values["password"], &secrets),
+            REDACTED
+        );
+        assert_eq!(
+            redacted_json("user", &values["user"], &secrets),
+            "\"admin\""
+        );
+        assert_eq!(PreviewInput::Secret.to_string(), REDACTED);
+        let resources = BTreeMap::from([(
+            "db".to_string(),
+            PreviewResource {
+                resource_type: "database".to_string(),
+                provider: Value::Null,
+                inputs: BTreeMap::from([("password".to_string(), PreviewInput::Secret)]),
+            },
+        )]);
+        let plan = serde_json::to_value(plan_json("default", &resources)).unwrap();
+        assert_eq!(plan["resources"]["db"]["inputs"]["password"], "secret");
+    }
+
+    #[test]
+    fn test_plan_json_conflicts_with_dry_run() {
+        assert!(parse_args(&["--plan-json"]).unwrap().plan_json);
+        assert!(parse_args(&["--plan-json", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn test_format_timings() {
+        let timings = BTreeMap::from([
+            ("fast".to_string(), Duration::from_millis(20)),
+            ("slow".to_string(), Duration::from_millis(1500)),
+        ]);
+        assert_eq!(
+            format_timings(&timings),
+            vec!["   1.500s  slow".to_string(), "   0.020s  fast".to_string()]
+        );
+    }
+
+    fn edge(output: (&str, &str), input: (&str, &str)) -> DependencyEdge {
+        DependencyEdge {
+            output: NamedProperty {
+                resource: output.0.to_string(),
+                name: output.1.to_string(),
+            },
+            input: NamedProperty {
+                resource: input.0.to_string(),
+                name: input.1.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_find_cycle_two_resources() {
+        let edges = vec![edge(("b", "out"), ("a", "in"))];
+        let new = edge(("a", "out"), ("b", "in"));
+        let cycle = find_cycle(&edges, &new).unwrap();
+        assert_eq!(
+            render_cycle(&cycle),
+            "a.out -> b.in -> b.out -> a.in -> a.out"
+        );
+    }
+
+    #[test]
+    fn test_find_cycle_self() {
+        let new = edge(("a", "out"), ("a", "in"));
+        let cycle = find_cycle(&[], &new).unwrap();
+        assert_eq!(render_cycle(&cycle), "a.out -> a.in -> a.out");
+    }
+
+    #[test]
+    fn test_find_cycle_none() {
+        let edges = vec![
+            edge(("a", "out"), ("b", "in")),
+            edge(("b", "out"), ("c", "in")),
+        ];
+        assert_eq!(find_cycle(&edges, &edge(("a", "out"), ("c", "in2"))), None);
+    }
+
+    #[test]
+    fn test_find_cycle_three_resources() {
+        let edges = vec![
+            edge(("a", "out"), ("b", "in")),
+            edge(("b", "out"), ("c", "in")),
+            edge(("x", "out"), ("y", "in")),
+        ];
+        let cycle = find_cycle(&edges, &edge(("c", "out"), ("a", "in"))).unwrap();
+        assert_eq!(
+            render_cycle(&cycle),
+            "c.out -> a.in -> a.out -> b.in -> b.out -> c.in -> c.out"
+        );
+    }
+
+    #[test]
+    fn test_provider_config_args() {
+        use nixops4_core::eval_api::{eval_response_from_json, eval_response_to_json, Ids};
+        let mut ids = Ids::new();
+        let info = ResourceProviderInfo {
+            id: ids.next(),
+            provider: serde_json::json!({
+                "type": "stdio",
+                "command": "terraform-adapter",
+                "args": ["run", "--provider-exe", "terraform-provider-x"],
+            }),
+            resource_type: "thing".to_string(),
+            secret_properties: Default::default(),
+            timeout_seconds: None,
+        };
+        // As received from the evaluator
+        let resp =
+            EvalResponse::QueryResponse(ids.next(), QueryResponseValue::ResourceProviderInfo(info));
+        let resp = eval_response_from_json(&eval_response_to_json(&resp).unwrap()).unwrap();
+        let EvalResponse::QueryResponse(_, QueryResponseValue::ResourceProviderInfo(info)) = resp
+        else {
+            panic!("expected ResourceProviderInfo, got: {:?}", resp);
+        };
+        let config = provider_config(&info, &parse_args(&[]).unwrap(), false).unwrap();
+        assert_eq!(config.provider_executable, "terraform-adapter");
+        assert_eq!(
+            config.provider_args,
+            names(&["run", "--provider-exe", "terraform-provider-x"])
+        );
+    }
+
+    #[test]
+    fn test_provider_config_env() {
+        let info = |provider: Value| ResourceProviderInfo {
+            id: nixops4_core::eval_api::Ids::new().next(),
+            provider,
+            resource_type: "thing".to_string(),
+            secret_properties: Default::default(),
+            timeout_seconds: None,
+        };
+        let args = parse_args(&[]).unwrap();
+        let config = provider_config(
+            &info(serde_json::json!({
+                "type": "stdio",
+                "command": "cloud-provider",
+                "args": [],
+                "env": { "AWS_REGION": "eu-west-1" },
+                "clearEnv": true,
+            })),
+            &args,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            config.env,
+            BTreeMap::from([("AWS_REGION".to_string(), "eu-west-1".to_string())])
+        );
+        assert!(config.clear_env);
+
+        // Both are optional
+        let config = provider_config(
+            &info(serde_json::json!({ "type": "stdio", "command": "cloud-provider", "args": [] })),
+            &args,
+            false,
+        )
+        .unwrap();
+        assert_eq!(config.env, BTreeMap::new());
+        assert!(!config.clear_env);
+    }
+
+    /// A provider that takes `start_delay` to start and `create_delay` to create
+    /// a resource, and appends a line to `starts` whenever it is started.
+    /// A provider that appends to `starts` each time a process is started.
+    fn counting_provider(starts: &std::path::Path) -> ResourceProviderInfo {
+        let script = r#"echo started >>"$STARTS"; while read request; do case "$request" in *capabilities*) printf '{"capabilities":{"protocolVersion":0,"operations":["create"],"resourceTypes":["file"]}}\n';; *) printf '{"outputProperties":{}}\n';; esac; done"#;
+        ResourceProviderInfo {
+            id: nixops4_core::eval_api::Ids::new().next(),
+            provider: serde_json::json!({
+                "type": "stdio",
+                "command": "sh",
+                "args": ["-c", script],
+                "env": { "STARTS": starts.to_str().unwrap() },
+            }),
+            resource_type: "file".to_string(),
+            secret_properties: Default::default(),
+            timeout_seconds: None,
+        }
+    }
+
+    fn starts_file(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("nixops4-apply-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn close_providers(providers: ProviderPool) {
+        for (_, idle) in providers.into_inner().unwrap() {
+            for mut provider in idle {
+                provider.close().unwrap();
+            }
+        }
+    }
+
+    /// A provider that rejects every resource, and appends to `creates` when
+    /// asked to create one.
+    fn rejecting_provider(creates: &std::path::Path) -> ResourceProviderInfo {
+        let script = r#"while read request; do case "$request" in *capabilities*) printf '{"capabilities":{"protocolVersion":0,"operations":["create","validate"],"resourceTypes":["file"]}}\n';; *validate*) printf '{"validate":{"problems":[{"property":"name","message":"is wrong"}]}}\n';; *) echo created >>"$CREATES"; printf '{"outputProperties":{}}\n';; esac; done"#;
+        ResourceProviderInfo {
+            provider: serde_json::json!({
+                "type": "stdio",
+                "command": "sh",
+                "args": ["-c", script],
+                "env": { "CREATES": creates.to_str().unwrap() },
+            }),
+            ..counting_provider(creates)
+        }
+    }
+
+    #[test]
+    fn test_validate_resources_reports_all() {
+        let creates = starts_file("validate");
+        let info = rejecting_provider(&creates);
+        let planned = |name: &str| PlannedResource {
+            info: info.clone(),
+            inputs: BTreeMap::from([(
+                "name".to_string(),
+                PreviewInput::Value(Value::String(name.to_string())),
+            )]),
+        };
+        let plan = BTreeMap::from([
+            ("a".to_string(), planned("a")),
+            ("b".to_string(), planned("b")),
+        ]);
+        let args = parse_args(&[]).unwrap();
+        let providers: ProviderPool = Mutex::new(BTreeMap::new());
+        let e = validate_resources(&providers, &plan, &args, false, &InterruptState::new())
+            .unwrap_err();
+        close_providers(providers);
+        let message = format!("{:#}", e);
+        assert!(
+            message.contains("2 of 2 resources failed validation"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("Invalid inputs for resource a:\n  - name: is wrong"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("Invalid inputs for resource b:\n  - name: is wrong"),
+            "{}",
+            message
+        );
+        assert!(!creates.exists());
+    }
+
+    #[test]
+    fn test_provider_pool_reuses_process() {
+        let starts = starts_file("reuse");
+        let info = counting_provider(&starts);
+        let args = parse_args(&[]).unwrap();
+        let interrupt_state = InterruptState::new();
+        let providers: ProviderPool = Mutex::new(BTreeMap::new());
+        for i in 0..10 {
+            create_resource(
+                &providers,
+                &info,
+                &format!("file{}", i),
+                &BTreeMap::new(),
+                true,
+                &args,
+                false,
+                &interrupt_state,
+            )
+            .unwrap();
+        }
+        close_providers(providers);
+        assert_eq!(std::fs::read_to_string(&starts).unwrap(), "started\n");
+        std::fs::remove_file(&starts).unwrap();
+    }
+
+    #[test]
+    fn test_provider_pool_one_request_per_process() {
+        // A provider that exits after responding to a single request
+        let starts = starts_file("one-shot");
+        let script = r#"echo started >>"$STARTS"; read request; exec 0<&-; case "$request" in *capabilities*) printf '{"capabilities":{"protocolVersion":0,"operations":["create"],"resourceTypes":["file"]}}\n';; *) printf '{"outputProperties":{}}\n';; esac"#;
+        let info = ResourceProviderInfo {
+            provider: serde_json::json!({
+                "type": "stdio",
+                "command": "sh",
+                "args": ["-c", script],
+                "env": { "STARTS": starts.to_str().unwrap() },
+            }),
+            ..counting_provider(&starts)
+        };
+        let args = parse_args(&[]).unwrap();
+        let interrupt_state = InterruptState::new();
+        let providers: ProviderPool = Mutex::new(BTreeMap::new());
+        for i in 0..3 {
+            create_resource(
+                &providers,
+                &info,
+                &format!("file{}", i),
+                &BTreeMap::new(),
+                true,
+                &args,
+                false,
+                &interrupt_state,
+            )
+            .unwrap();
+        }
+        close_providers(providers);
+        // One for the capabilities, and one for each resource
+        assert_eq!(
+            std::fs::read_to_string(&starts).unwrap(),
+            "started\n".repeat(4)
+        );
+        std::fs::remove_file(&starts).unwrap();
+    }
+
+    #[test]
+    fn test_select_resources_unknown() {
+        let resources = names(&["a", "b", "c"]);
+        let e = select_resources(&resources, &names(&["d"]), &[]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Deployment does not contain a resource named d"
+        );
+    }
+
+    /// A frontend that gives the same answer to every question, and counts them.
+    struct FakeFrontend {
+        answer: bool,
+        questions: usize,
+    }
+
+    impl Frontend for FakeFrontend {
+        fn set_up(&mut self, _options: &crate::logging::Options) -> Result<()> {
+            Ok(())
+        }
+        fn tear_down(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn get_panic_handler(&self) -> Box<dyn Fn(&std::panic::PanicHookInfo<'_>) + Send + Sync> {
+            Box::new(|_| {})
+        }
+        fn confirm(&mut self, _question: &str) -> Result<bool> {
+            self.questions += 1;
+            Ok(self.answer)
+        }
+    }
+
+    #[test]
+    fn test_confirm_apply_non_interactive() {
+        let mut frontend = FakeFrontend {
+            answer: false,
+            questions: 0,
+        };
+        // Proceeds without --yes
+        confirm_apply(&mut frontend, false, false).unwrap();
+        confirm_apply(&mut frontend, true, false).unwrap();
+        // There is no one to ask
+        assert_eq!(frontend.questions, 0);
+    }
+
+    #[test]
+    fn test_confirm_apply_interactive() {
+        let mut frontend = FakeFrontend {
+            answer: false,
+            questions: 0,
+        };
+        let e = confirm_apply(&mut frontend, false, true).unwrap_err();
+        assert_eq!(e.to_string(), "Apply aborted; no changes were made");
+        assert_eq!(frontend.questions, 1);
+        frontend.answer = true;
+        confirm_apply(&mut frontend, false, true).unwrap();
+        assert_eq!(frontend.questions, 2);
+        // --yes skips the question
+        confirm_apply(&mut frontend, true, true).unwrap();
+        assert_eq!(frontend.questions, 2);
+    }
+
+    #[test]
+    fn test_target_alias() {
+        let args = parse_args(&["--target", "a", "--resource", "b"]).unwrap();
+        assert_eq!(args.resource, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn test_yes_flag() {
+        assert!(!parse_args(&[]).unwrap().yes);
+        for flag in ["--yes", "-y", "--confirm"] {
+            assert!(parse_args(&[flag]).unwrap().yes, "{}", flag);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{apply::DeploymentArgs, eval_client::EvalClient, with_flake, Options};
+use anyhow::{bail, Result};
+use nixops4_core::eval_api::{
+    AssignRequest, DeploymentArg, DeploymentRequest, DeploymentType, EvalRequest, EvalResponse,
+    FlakeType, Id, MessageType, QueryResponseValue, ResourceRequest, ResourceType,
+};
+
+/// Run the `check` command.
+pub(crate) fn check(options: &Options, deployment_args: &DeploymentArgs) -> Result<()> {
+    let deployment_args = deployment_args.to_map()?;
+    with_flake(options, |c, flake_id| {
+        let deployments_id = c.query(EvalRequest::ListDeployments, flake_id)?;
+        let deployments = c.receive_until(|client, _resp| {
+            client.check_error(flake_id)?;
+            client.check_error(deployments_id)?;
+            Ok(client.get_deployments(flake_id).cloned())
+        })?;
+
+        let results = check_deployments(c, flake_id, &deployments, &deployment_args)?;
+
+        let mut failed = Vec::new();
+        for (name, result) in deployments.iter().zip(results) {
+            match result {
+                Ok(resources) => {
+                    eprintln!("deployment {}: ok", name);
+                    for (resource_name, resource_type) in resources {
+                        eprintln!("  - {}: {}", resource_name, resource_type);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("deployment {}: error: {}", name, e);
+                    failed.push(name.as_str());
+                }
+            }
+        }
+        if !failed.is_empty() {
+            bail!(
+                "{} of {} deployments failed to evaluate: {}",
+                failed.len(),
+                deployments.len(),
+                failed.join(", ")
+            );
+        }
+        Ok(())
+    })
+}
+
+/// The outcome of checking a deployment: a map from resource name to
+/// resource type, or the evaluation error.
+type CheckResult = std::result::Result<BTreeMap<String, String>, String>;
+
+/// The type of a resource, or the evaluation error.
+type CheckResultItem = std::result::Result<String, String>;
+
+/// Evaluate the deployments far enough to know their resources and the types
+/// of those resources.
+///
+/// The evaluator processes requests one at a time, so rather than waiting
+/// for each deployment in turn, all requests of a phase are sent before
+/// receiving any responses. This way the evaluator never waits for us.
+///
+/// The same arguments are passed to every deployment; a deployment that is a
+/// function only receives the arguments that it accepts.
+fn check_deployments(
+    c: &mut EvalClient,
+    flake_id: Id<FlakeType>,
+    deployments: &[String],
+    deployment_args: &BTreeMap<String, DeploymentArg>,
+) -> Result<Vec<CheckResult>> {
+    // Phase 1: list the resources of all deployments
+    let mut deployment_ids: Vec<(Id<DeploymentType>, Id<MessageType>)> = Vec::new();
+    for name in deployments.iter() {
+        let deployment_id = c.next_id();
+        c.send(&EvalRequest::LoadDeployment(AssignRequest {
+            assign_to: deployment_id,
+            payload: DeploymentRequest {
+                flake: flake_id,
+                name: name.to_string(),
+                args: deployment_args.clone(),
+            },
+        }))?;
+        let resources_list_id = c.query(EvalRequest::ListResources, deployment_id)?;
+        deployment_ids.push((deployment_id, resources_list_id));
+    }
+    let resource_lists = if deployment_ids.is_empty() {
+        Vec::new()
+    } else {
+        c.receive_until(|client, _resp| {
+            let r = deployment_ids
+                .iter()
+                .map(|(deployment_id, resources_list_id)| {
+                    listed_resources(client, *deployment_id, *resources_list_id)
+                })
+                .collect::<Option<Vec<_>>>();
+            Ok(r)
+        })?
+    };
+
+    // Phase 2: get the types of the resources of the deployments that could be listed
+    let mut resource_ids: BTreeMap<Id<ResourceType>, (usize, String)> = BTreeMap::new();
+    let mut query_ids: Vec<(Id<MessageType>, Id<ResourceType>)> = Vec::new();
+    for ((deployment_id, _), (ix, resources)) in
+        deployment_ids.iter().zip(resource_lists.iter().enumerate())
+    {
+        let Ok(resources) = resources else {
+            continue;
+        };
+        for resource_name in resources.iter() {
+            let id = c.next_id();
+            c.send(&EvalRequest::LoadResource(AssignRequest {
+                assign_to: id,
+                payload: ResourceRequest {
+                    deployment: *deployment_id,
+                    name: resource_name.clone(),
+                },
+            }))?;
+            query_ids.push((c.query(EvalRequest::GetResource, id)?, id));
+            resource_ids.insert(id, (ix, resource_name.clone()));
+        }
+    }
+    let resource_types: Mutex<BTreeMap<Id<ResourceType>, CheckResultItem>> =
+        Mutex::new(BTreeMap::new());
+    if !resource_ids.is_empty() {
+        c.receive_until(|client, resp| {
+            let mut resource_types = resource_types.lock().unwrap();
+            for (query_id, id) in query_ids.iter() {
+                if let Some(e) = client.get_error(*id).or(client.get_error(*query_id)) {
+                    resource_types.insert(*id, Err(e.to_string()));
+                }
+            }
+            if let EvalResponse::QueryResponse(_, QueryResponseValue::ResourceProviderInfo(info)) =
+                resp
+            {
+                if resource_ids.contains_key(&info.id) {
+                    resource_types.insert(info.id, Ok(info.resource_type.clone()));
+                }
+            }
+            if resource_types.len() == resource_ids.len() {
+                Ok(Some(()))
+            } else {
+                Ok(None)
+            }
+        })?;
+    }
+
+    let mut results: Vec<CheckResult> = resource_lists
+        .into_iter()
+        .map(|r| r.map(|_| BTreeMap::new()))
+        .collect();
+    for (id, item) in resource_types.into_inner().unwrap() {
+        let (ix, resource_name) = resource_ids.remove(&id).unwrap();
+        match (&mut results[ix], item) {
+            (Ok(types), Ok(resource_type)) => {
+                types.insert(resource_name, resource_type);
+            }
+            (result @ Ok(_), Err(e)) => {
+                *result = Err(format!("resource {}: {}", resource_name, e));
+            }
+            // Report only the first error of a deployment
+            (Err(_), _) => {}
+        }
+    }
+    Ok(results)
+}
+
+/// The resource names of a deployment, its evaluation error, or `None` if
+/// the evaluator has not responded yet.
+fn listed_resources(
+    client: &EvalClient,
+    deployment_id: Id<DeploymentType>,
+    resources_list_id: Id<MessageType>,
+) -> Option<std::result::Result<Vec<String>, String>> {
+    if let Some(e) = client
+        .get_error(deployment_id)
+        .or(client.get_error(resources_list_id))
+    {
+        return Some(Err(e.to_string()));
+    }
+    client
+        .get_resources(deployment_id)
+        .map(|resources| Ok(resources.clone()))
+}
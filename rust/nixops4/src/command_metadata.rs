@@ -0,0 +1,192 @@
+//! Machine-readable description of the command line interface, for use by
+//! external tooling such as editor integrations.
+//!
+//! This is derived from the same clap [`Command`] that produces the manual
+//! and the shell completions.
+
+use clap::{Arg, Command};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CommandMetadata {
+    pub(crate) name: String,
+    pub(crate) about: Option<String>,
+    pub(crate) args: Vec<ArgMetadata>,
+    pub(crate) subcommands: Vec<CommandMetadata>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ArgKind {
+    /// A named argument that does not take a value, e.g. `--verbose`
+    Flag,
+    /// A named argument that takes one or more values, e.g. `--color always`
+    Option,
+    /// A positional argument
+    Positional,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ArgMetadata {
+    pub(crate) id: String,
+    pub(crate) kind: ArgKind,
+    pub(crate) long: Option<String>,
+    /// Other long names that are shown in the help, e.g. `target` for `--resource`
+    pub(crate) aliases: Vec<String>,
+    pub(crate) short: Option<char>,
+    pub(crate) help: Option<String>,
+    pub(crate) required: bool,
+    pub(crate) global: bool,
+    /// Names of the values, as shown in the help, e.g. `NAME` and `JSON`
+    pub(crate) value_names: Vec<String>,
+    /// If non-empty, the argument only accepts these values
+    pub(crate) possible_values: Vec<String>,
+    pub(crate) default_values: Vec<String>,
+}
+
+/// Describe `cmd` and its visible subcommands.
+pub(crate) fn command_metadata(cmd: &Command) -> CommandMetadata {
+    CommandMetadata {
+        name: cmd.get_name().to_string(),
+        about: cmd.get_about().map(|s| s.to_string()),
+        args: cmd
+            .get_arguments()
+            .filter(|a| !a.is_hide_set())
+            .map(arg_metadata)
+            .collect(),
+        subcommands: cmd
+            .get_subcommands()
+            .filter(|c| !c.is_hide_set())
+            .map(command_metadata)
+            .collect(),
+    }
+}
+
+fn arg_metadata(arg: &Arg) -> ArgMetadata {
+    let takes_values = arg.get_action().takes_values();
+    let kind = if arg.is_positional() {
+        ArgKind::Positional
+    } else if takes_values {
+        ArgKind::Option
+    } else {
+        ArgKind::Flag
+    };
+    // Flags don't have values to describe; clap reports a synthetic value
+    // name and "true"/"false" for them, which is noise.
+    let (value_names, possible_values, default_values) = if takes_values {
+        (
+            arg.get_value_names()
+                .map(|names| names.iter().map(|n| n.to_string()).collect())
+                .unwrap_or_default(),
+            arg.get_possible_values()
+                .iter()
+                .filter(|v| !v.is_hide_set())
+                .map(|v| v.get_name().to_string())
+                .collect(),
+            arg.get_default_values()
+                .iter()
+                .map(|v| v.to_string_lossy().to_string())
+                .collect(),
+        )
+    } else {
+        (vec![], vec![], vec![])
+    };
+    ArgMetadata {
+        id: arg.get_id().to_string(),
+        kind,
+        long: arg.get_long().map(|s| s.to_string()),
+        aliases: arg
+            .get_visible_aliases()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect(),
+        short: arg.get_short(),
+        help: arg.get_help().map(|s| s.to_string()),
+        required: arg.is_required_set(),
+        global: arg.is_global_set(),
+        value_names,
+        possible_values,
+        default_values,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory as _;
+
+    #[test]
+    fn test_command_metadata_apply() {
+        let metadata = command_metadata(&crate::Args::command());
+        assert_eq!(metadata.name, "nixops4");
+        let apply = metadata
+            .subcommands
+            .iter()
+            .find(|c| c.name == "apply")
+            .expect("apply command");
+        let positional = apply
+            .args
+            .iter()
+            .find(|a| a.id == "deployment_positional")
+            .expect("deployment argument");
+        assert!(matches!(positional.kind, ArgKind::Positional));
+        assert_eq!(positional.value_names, vec!["DEPLOYMENT".to_string()]);
+        let option = apply
+            .args
+            .iter()
+            .find(|a| a.id == "deployment")
+            .expect("--deployment option");
+        assert!(matches!(option.kind, ArgKind::Option));
+        assert_eq!(option.long.as_deref(), Some("deployment"));
+    }
+
+    #[test]
+    fn test_command_metadata_apply_target() {
+        let metadata = command_metadata(&crate::Args::command());
+        let json = serde_json::to_value(&metadata).unwrap();
+        let apply = json["subcommands"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["name"] == "apply")
+            .expect("apply command");
+        let target = apply["args"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["aliases"].as_array().unwrap().contains(&"target".into()))
+            .expect("--target option");
+        assert_eq!(target["kind"], "option");
+        assert_eq!(target["long"], "resource");
+        assert_eq!(target["value_names"], serde_json::json!(["NAME"]));
+    }
+
+    #[test]
+    fn test_command_metadata_hides_generators() {
+        let metadata = command_metadata(&crate::Args::command());
+        assert!(metadata
+            .subcommands
+            .iter()
+            .all(|c| !c.name.starts_with("generate-")));
+    }
+
+    #[test]
+    fn test_command_metadata_json() {
+        let metadata = command_metadata(&crate::Args::command());
+        let json = serde_json::to_value(&metadata).unwrap();
+        let color = json["args"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["id"] == "color")
+            .unwrap();
+        assert_eq!(color["kind"], "option");
+        assert_eq!(color["long"], "color");
+        assert_eq!(color["global"], true);
+        assert!(color["possible_values"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::Value::String("always".to_string())));
+    }
+}
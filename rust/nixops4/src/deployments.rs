@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    apply::{check_deployment_exists, load_resource, preview, DeploymentArgs, PreviewResource},
+    provider,
+    providers::get_provider_infos,
+    with_flake, Options,
+};
+use anyhow::Result;
+use nixops4_core::eval_api::{
+    AssignRequest, DeploymentRequest, DeploymentType, EvalRequest, Id, ResourceType,
+};
+
+/// Run the `deployments show` command.
+pub(crate) fn show(
+    options: &Options,
+    deployment_name: &str,
+    deployment_args: &DeploymentArgs,
+    inputs: bool,
+) -> Result<()> {
+    let deployment_args = deployment_args.to_map()?;
+    let resources = with_flake(options, |c, flake_id| {
+        let deployments_id = c.query(EvalRequest::ListDeployments, flake_id)?;
+        let deployments = c.receive_until(|client, _resp| {
+            client.check_error(flake_id)?;
+            client.check_error(deployments_id)?;
+            Ok(client.get_deployments(flake_id).cloned())
+        })?;
+        check_deployment_exists(&deployments, deployment_name)?;
+        if !inputs {
+            // Only the types and providers are shown, so leave the inputs unevaluated
+            let infos = get_provider_infos(c, flake_id, deployment_name, deployment_args)?;
+            return Ok(infos
+                .into_iter()
+                .map(|(name, info)| {
+                    let resource = PreviewResource {
+                        resource_type: info.resource_type,
+                        provider: provider::redact_env(&info.provider),
+                        inputs: BTreeMap::new(),
+                    };
+                    (name, resource)
+                })
+                .collect());
+        }
+        let deployment_id: Id<DeploymentType> = c.next_id();
+        c.send(&EvalRequest::LoadDeployment(AssignRequest {
+            assign_to: deployment_id,
+            payload: DeploymentRequest {
+                flake: flake_id,
+                name: deployment_name.to_string(),
+                args: deployment_args,
+            },
+        }))?;
+        let resources_list_id = c.query(EvalRequest::ListResources, deployment_id)?;
+        let resources = c.receive_until(|client, _resp| {
+            client.check_error(deployment_id)?;
+            client.check_error(resources_list_id)?;
+            Ok(client.get_resources(deployment_id).cloned())
+        })?;
+        let resource_ids: BTreeMap<String, Id<ResourceType>> = resources
+            .iter()
+            .map(|name| (name.clone(), c.next_id()))
+            .collect();
+        if resource_ids.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        let mut loaded_resources = BTreeSet::new();
+        for (name, id) in resource_ids.iter() {
+            load_resource(c, deployment_id, name, *id)?;
+            loaded_resources.insert(*id);
+        }
+        preview(c, deployment_id, &resource_ids, loaded_resources, &[])
+    })?;
+    print!("{}", format_show(deployment_name, &resources, inputs)?);
+    Ok(())
+}
+
+/// Render the resources of a deployment, and optionally their inputs.
+fn format_show(
+    deployment_name: &str,
+    resources: &BTreeMap<String, PreviewResource>,
+    inputs: bool,
+) -> Result<String> {
+    let mut out = format!("deployment {}\n", deployment_name);
+    if resources.is_empty() {
+        out.push_str("  (no resources)\n");
+    }
+    for (resource_name, resource) in resources {
+        let provider = provider::parse_provider(&resource.provider)?;
+        let mut argv = vec![provider.command];
+        argv.extend(provider.args);
+        out.push_str(&format!("resource {}\n", resource_name));
+        out.push_str(&format!("  type: {}\n", resource.resource_type));
+        out.push_str(&format!("  provider: {}\n", argv.join(" ")));
+        if inputs {
+            for (input, state) in &resource.inputs {
+                out.push_str(&format!("  input {}: {}\n", input, state));
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply::PreviewInput;
+    use nixops4_core::eval_api::NamedProperty;
+    use serde_json::{json, Value};
+
+    fn example() -> BTreeMap<String, PreviewResource> {
+        let local = json!({ "type": "stdio", "command": "nixops4-resources-local", "args": [] });
+        BTreeMap::from([
+            (
+                "hello".to_string(),
+                PreviewResource {
+                    resource_type: "exec".to_string(),
+                    provider: local.clone(),
+                    inputs: BTreeMap::from([(
+                        "executable".to_string(),
+                        PreviewInput::Value(Value::String("die".to_string())),
+                    )]),
+                },
+            ),
+            (
+                "greeting".to_string(),
+                PreviewResource {
+                    resource_type: "file".to_string(),
+                    provider: local,
+                    inputs: BTreeMap::from([(
+                        "contents".to_string(),
+                        PreviewInput::Dependency(NamedProperty {
+                            resource: "hello".to_string(),
+                            name: "stdout".to_string(),
+                        }),
+                    )]),
+                },
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_format_show() {
+        assert_eq!(
+            format_show("default", &example(), false).unwrap(),
+            "deployment default\n\
+             resource greeting\n  type: file\n  provider: nixops4-resources-local\n\
+             resource hello\n  type: exec\n  provider: nixops4-resources-local\n"
+        );
+    }
+
+    #[test]
+    fn test_format_show_inputs() {
+        assert_eq!(
+            format_show("default", &example(), true).unwrap(),
+            "deployment default\n\
+             resource greeting\n  type: file\n  provider: nixops4-resources-local\n  \
+             input contents: (output stdout of resource hello)\n\
+             resource hello\n  type: exec\n  provider: nixops4-resources-local\n  \
+             input executable: \"die\"\n"
+        );
+    }
+
+    #[test]
+    fn test_format_show_empty() {
+        assert_eq!(
+            format_show("default", &BTreeMap::new(), true).unwrap(),
+            "deployment default\n  (no resources)\n"
+        );
+    }
+}
@@ -1,18 +1,24 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     io::{BufRead, Write},
     process::ChildStdout,
 };
 
 use anyhow::{Context, Result};
 use nixops4_core::eval_api::{
-    self, DeploymentType, EvalRequest, EvalResponse, FlakeType, Id, IdNum, Ids, MessageType,
-    QueryRequest,
+    self, DeploymentName, DeploymentOptions, DeploymentType, EvalError, EvalRequest, EvalResponse,
+    FlakeMetadata, FlakeType, Id, IdNum, Ids, MessageType, NamedProperty, Property, QueryRequest,
+    QueryResponseValue, ResourceInputState, ResourceProviderInfo, ResourceType,
 };
+use serde_json::Value;
+
+use crate::provider;
 
 #[derive(Clone)]
 pub(crate) struct Options {
     pub(crate) verbose: bool,
+    pub(crate) show_trace: bool,
+    pub(crate) pure_eval: bool,
 }
 
 pub struct EvalClient<'a> {
@@ -25,16 +31,27 @@ pub struct EvalClient<'a> {
 
     ids: Ids,
     deployments: HashMap<Id<FlakeType>, Vec<String>>,
+    flake_metadata: HashMap<Id<FlakeType>, FlakeMetadata>,
+    deployment_options: HashMap<DeploymentName, DeploymentOptions>,
     resources: HashMap<Id<DeploymentType>, Vec<String>>,
-    errors: HashMap<IdNum, String>,
+    errors: HashMap<IdNum, EvalError>,
+    secrets: Secrets,
 }
 impl<'a> EvalClient<'a> {
     pub fn with<T>(options: &Options, f: impl FnOnce(EvalClient) -> Result<T>) -> Result<T> {
         let exe = std::env::var("_NIXOPS4_EVAL").unwrap_or("nixops4-eval".to_string());
-        let mut process = std::process::Command::new(exe)
+        let mut command = std::process::Command::new(exe);
+        command
             .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
-            .arg("<subprocess>")
+            .arg("<subprocess>");
+        if options.show_trace {
+            command.arg("--show-trace");
+        }
+        if options.pure_eval {
+            command.arg("--pure-eval");
+        }
+        let mut process = command
             .spawn()
             .context("while starting the nixops4 evaluator process")?;
 
@@ -60,8 +77,11 @@ impl<'a> EvalClient<'a> {
                 tracing_event_receiver: tracing_tunnel::TracingEventReceiver::default(),
                 ids: Ids::new(),
                 deployments: HashMap::new(),
+                flake_metadata: HashMap::new(),
+                deployment_options: HashMap::new(),
                 resources: HashMap::new(),
                 errors: HashMap::new(),
+                secrets: Secrets::default(),
             };
 
             r = f(c)
@@ -73,9 +93,16 @@ impl<'a> EvalClient<'a> {
         r
     }
     pub fn send(&mut self, request: &EvalRequest) -> Result<()> {
+        self.secrets.record_request(request);
         let json = eval_api::eval_request_to_json(request)?;
         if self.options.verbose {
-            eprintln!("\x1b[35msending: {}\x1b[0m", json);
+            match self.secrets.redact_request(request) {
+                Some(redacted) => eprintln!(
+                    "\x1b[35msending: {}\x1b[0m",
+                    eval_api::eval_request_to_json(&redacted)?
+                ),
+                None => eprintln!("\x1b[35msending: {}\x1b[0m", json),
+            }
         }
         self.command_handle.write_all(json.as_bytes())?;
         self.command_handle.write_all(b"\n")?;
@@ -103,11 +130,21 @@ impl<'a> EvalClient<'a> {
             }
             Ok(_) => {}
         }
+        let response = eval_api::eval_response_from_json(line.as_str());
         if self.options.verbose {
-            eprintln!("\x1b[32mreceived: {}\x1b[0m", line.trim_end());
+            let redacted = response
+                .as_ref()
+                .ok()
+                .and_then(|r| self.secrets.redact_response(r));
+            match redacted {
+                Some(redacted) => eprintln!(
+                    "\x1b[32mreceived: {}\x1b[0m",
+                    eval_api::eval_response_to_json(&redacted)?
+                ),
+                None => eprintln!("\x1b[32mreceived: {}\x1b[0m", line.trim_end()),
+            }
         }
-        let response = eval_api::eval_response_from_json(line.as_str())?;
-        Ok(response)
+        response
     }
     pub fn receive_until<T>(
         &mut self,
@@ -128,7 +165,7 @@ impl<'a> EvalClient<'a> {
         self.ids.next()
     }
 
-    pub fn get_error<T>(&self, id: Id<T>) -> Option<&String> {
+    pub fn get_error<T>(&self, id: Id<T>) -> Option<&EvalError> {
         self.errors.get(&id.num())
     }
 
@@ -144,11 +181,20 @@ impl<'a> EvalClient<'a> {
         self.deployments.get(&id)
     }
 
+    pub fn get_flake_metadata(&self, id: Id<FlakeType>) -> Option<&FlakeMetadata> {
+        self.flake_metadata.get(&id)
+    }
+
+    pub fn get_deployment_options(&self, name: &DeploymentName) -> Option<&DeploymentOptions> {
+        self.deployment_options.get(name)
+    }
+
     pub fn get_resources(&self, id: Id<DeploymentType>) -> Option<&Vec<String>> {
         self.resources.get(&id)
     }
 
     fn handle_response(&mut self, response: &eval_api::EvalResponse) -> Result<()> {
+        self.secrets.record_response(response);
         match response {
             eval_api::EvalResponse::Error(id, error) => {
                 self.errors.insert(id.num(), error.clone());
@@ -157,11 +203,19 @@ impl<'a> EvalClient<'a> {
                 eval_api::QueryResponseValue::ListDeployments((flake_id, deployments)) => {
                     self.deployments.insert(*flake_id, deployments.clone());
                 }
+                eval_api::QueryResponseValue::FlakeMetadata(metadata) => {
+                    self.flake_metadata.insert(metadata.id, metadata.clone());
+                }
+                eval_api::QueryResponseValue::DeploymentOptions(options) => {
+                    self.deployment_options
+                        .insert(options.deployment.clone(), options.clone());
+                }
                 eval_api::QueryResponseValue::ListResources((deployment_id, resources)) => {
                     self.resources.insert(*deployment_id, resources.clone());
                 }
                 _ => {}
             },
+            eval_api::EvalResponse::ResourceOutputAck(_) => {}
             eval_api::EvalResponse::TracingEvent(v) => {
                 let event =
                     serde_json::from_value(v.clone()).context("while parsing tracing event")?;
@@ -173,3 +227,194 @@ impl<'a> EvalClient<'a> {
         Ok(())
     }
 }
+
+/// Shown instead of the value of a secret property.
+pub(crate) const REDACTED: &str = "«secret»";
+
+/// The properties that resources declare in `secretProperties`, so that
+/// their values can be kept out of the protocol log.
+///
+/// The evaluator answers in order, so a resource's provider info, which
+/// lists its secret properties, arrives before any of its input values.
+#[derive(Default)]
+struct Secrets {
+    resource_names: HashMap<Id<ResourceType>, String>,
+    properties: HashMap<Id<ResourceType>, BTreeSet<String>>,
+}
+impl Secrets {
+    fn record_request(&mut self, request: &EvalRequest) {
+        if let EvalRequest::LoadResource(req) = request {
+            self.resource_names
+                .insert(req.assign_to, req.payload.name.clone());
+        }
+    }
+
+    fn record_response(&mut self, response: &EvalResponse) {
+        if let EvalResponse::QueryResponse(_, QueryResponseValue::ResourceProviderInfo(info)) =
+            response
+        {
+            self.properties
+                .insert(info.id, info.secret_properties.clone());
+        }
+    }
+
+    fn is_secret(&self, property: &Property) -> bool {
+        self.properties
+            .get(&property.resource)
+            .is_some_and(|names| names.contains(&property.name))
+    }
+
+    fn is_secret_output(&self, property: &NamedProperty) -> bool {
+        self.resource_names.iter().any(|(id, name)| {
+            *name == property.resource
+                && self.is_secret(&Property {
+                    resource: *id,
+                    name: property.name.clone(),
+                })
+        })
+    }
+
+    /// A copy of `request` without secret values, if it has any.
+    fn redact_request(&self, request: &EvalRequest) -> Option<EvalRequest> {
+        match request {
+            EvalRequest::PutResourceOutput(property, _) if self.is_secret_output(property) => {
+                Some(EvalRequest::PutResourceOutput(
+                    property.clone(),
+                    Value::String(REDACTED.to_string()),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// A copy of `response` without secret values, if it has any.
+    fn redact_response(&self, response: &EvalResponse) -> Option<EvalResponse> {
+        match response {
+            EvalResponse::QueryResponse(
+                id,
+                QueryResponseValue::ResourceInputState((
+                    property,
+                    ResourceInputState::ResourceInputValue((value_property, _)),
+                )),
+            ) if self.is_secret(property) => Some(EvalResponse::QueryResponse(
+                *id,
+                QueryResponseValue::ResourceInputState((
+                    property.clone(),
+                    ResourceInputState::ResourceInputValue((
+                        value_property.clone(),
+                        Value::String(REDACTED.to_string()),
+                    )),
+                )),
+            )),
+            EvalResponse::QueryResponse(id, QueryResponseValue::ResourceProviderInfo(info))
+                if info.provider.get("env").is_some() =>
+            {
+                Some(EvalResponse::QueryResponse(
+                    *id,
+                    QueryResponseValue::ResourceProviderInfo(ResourceProviderInfo {
+                        provider: provider::redact_env(&info.provider),
+                        ..info.clone()
+                    }),
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nixops4_core::eval_api::{AssignRequest, ResourceRequest};
+
+    #[test]
+    fn test_secrets_redact() {
+        let mut ids = Ids::new();
+        let deployment = ids.next();
+        let resource: Id<ResourceType> = ids.next();
+        let mut secrets = Secrets::default();
+        secrets.record_request(&EvalRequest::LoadResource(AssignRequest {
+            assign_to: resource,
+            payload: ResourceRequest {
+                deployment,
+                name: "db".to_string(),
+            },
+        }));
+        secrets.record_response(&EvalResponse::QueryResponse(
+            ids.next(),
+            QueryResponseValue::ResourceProviderInfo(ResourceProviderInfo {
+                id: resource,
+                provider: Value::Null,
+                resource_type: "database".to_string(),
+                secret_properties: BTreeSet::from(["password".to_string()]),
+                timeout_seconds: None,
+            }),
+        ));
+
+        let message_id = ids.next();
+        let input = |name: &str| {
+            let property = Property {
+                resource,
+                name: name.to_string(),
+            };
+            EvalResponse::QueryResponse(
+                message_id,
+                QueryResponseValue::ResourceInputState((
+                    property.clone(),
+                    ResourceInputState::ResourceInputValue((property, Value::from("hunter2"))),
+                )),
+            )
+        };
+        let redacted = secrets.redact_response(&input("password")).unwrap();
+        let json = eval_api::eval_response_to_json(&redacted).unwrap();
+        assert!(!json.contains("hunter2"), "{}", json);
+        assert!(json.contains(REDACTED), "{}", json);
+        assert_eq!(secrets.redact_response(&input("user")), None);
+
+        let output = |resource: &str, name: &str| {
+            EvalRequest::PutResourceOutput(
+                NamedProperty {
+                    resource: resource.to_string(),
+                    name: name.to_string(),
+                },
+                Value::from("hunter2"),
+            )
+        };
+        let redacted = secrets.redact_request(&output("db", "password")).unwrap();
+        let json = eval_api::eval_request_to_json(&redacted).unwrap();
+        assert!(!json.contains("hunter2"), "{}", json);
+        assert_eq!(secrets.redact_request(&output("db", "url")), None);
+        assert_eq!(secrets.redact_request(&output("other", "password")), None);
+    }
+
+    #[test]
+    fn test_secrets_redact_provider_env() {
+        let mut ids = Ids::new();
+        let secrets = Secrets::default();
+        let mut info = |provider: Value| {
+            EvalResponse::QueryResponse(
+                ids.next(),
+                QueryResponseValue::ResourceProviderInfo(ResourceProviderInfo {
+                    id: ids.next(),
+                    provider,
+                    resource_type: "bucket".to_string(),
+                    secret_properties: BTreeSet::new(),
+                    timeout_seconds: None,
+                }),
+            )
+        };
+        let with_env = info(serde_json::json!({
+            "type": "stdio",
+            "command": "cloud-provider",
+            "args": [],
+            "env": { "AWS_SECRET_ACCESS_KEY": "hunter2" },
+        }));
+        let redacted = secrets.redact_response(&with_env).unwrap();
+        let json = eval_api::eval_response_to_json(&redacted).unwrap();
+        assert!(!json.contains("hunter2"), "{}", json);
+        assert!(json.contains("AWS_SECRET_ACCESS_KEY"), "{}", json);
+        let without_env =
+            info(serde_json::json!({ "type": "stdio", "command": "local", "args": [] }));
+        assert_eq!(secrets.redact_response(&without_env), None);
+    }
+}
@@ -2,6 +2,7 @@ use crate::logging::level_filter::LevelFilter2;
 
 use super::Frontend;
 use anyhow::Result;
+use std::io::Write as _;
 use tracing_subscriber::{
     fmt::{format::FmtSpan, Layer as FmtLayer},
     layer::{Layered, SubscriberExt as _},
@@ -78,4 +79,13 @@ impl Frontend for HeadlessLogger {
             std::process::exit(101);
         })
     }
+
+    fn confirm(&mut self, question: &str) -> Result<bool> {
+        eprint!("{} [y/N] ", question);
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        // End of input counts as no
+        std::io::stdin().read_line(&mut answer)?;
+        Ok(super::is_yes(&answer))
+    }
 }
@@ -44,7 +44,19 @@ pub(crate) struct InteractiveLogger {
     active_spans: Arc<Mutex<BTreeSet<u64>>>,
     // Disable the TUI crudely, robustly, during panic
     crashing: Arc<AtomicBool>,
+    question_sender: Option<mpsc::Sender<Question>>,
 }
+
+/// A yes/no question for the TUI thread to ask, which owns the terminal.
+struct Question {
+    text: String,
+    answer: mpsc::Sender<bool>,
+}
+
+/// How long to wait for logs written before a question, so that they are
+/// printed before it.
+const QUESTION_LOG_DELAY: Duration = Duration::from_millis(200);
+
 impl InteractiveLogger {
     pub(crate) fn new(interrupt_state: InterruptState) -> Self {
         Self {
@@ -56,6 +68,7 @@ impl InteractiveLogger {
             orig_stdout: None,
             active_spans: Arc::new(Mutex::new(BTreeSet::new())),
             crashing: Arc::new(AtomicBool::new(false)),
+            question_sender: None,
         }
     }
 }
@@ -86,6 +99,8 @@ impl Frontend for InteractiveLogger {
         dup2(diag_write.as_raw_fd(), 2).context("dup2 stderr")?;
 
         let (diag_queue_sender, diag_queue_receiver) = mpsc::channel::<String>();
+        let (question_sender, question_receiver) = mpsc::channel::<Question>();
+        self.question_sender = Some(question_sender);
 
         let log_shovel_thread = thread::spawn(move || {
             let diag_read = std::fs::File::from(diag_read);
@@ -130,6 +145,7 @@ impl Frontend for InteractiveLogger {
                 .try_clone()
                 .expect("clone stderr"),
             diag_queue_receiver,
+            question_receiver,
             Arc::new(Box::new(move |frame: &mut Frame| {
                 let tui_area = frame.area();
                 let time = std::time::SystemTime::now();
@@ -296,6 +312,7 @@ impl Frontend for InteractiveLogger {
 
     fn tear_down(&mut self) -> Result<()> {
         self.headless_logger.tear_down()?;
+        self.question_sender = None;
 
         // Restore stdout and stderr for direct use
         if let Some(stderr) = self.orig_stderr.as_ref() {
@@ -368,11 +385,31 @@ impl Frontend for InteractiveLogger {
             std::process::exit(101);
         })
     }
+
+    fn confirm(&mut self, question: &str) -> Result<bool> {
+        let question_sender = self
+            .question_sender
+            .as_ref()
+            .context("confirm: the interactive logger is not set up")?;
+        let (answer_sender, answer_receiver) = mpsc::channel();
+        question_sender
+            .send(Question {
+                text: question.to_string(),
+                answer: answer_sender,
+            })
+            .context("confirm: the terminal UI has stopped")?;
+        answer_receiver
+            .recv()
+            .context("confirm: the terminal UI stopped before answering")
+    }
 }
 
 struct TuiState<W: Write> {
     terminal: Terminal<CrosstermBackend<io::BufWriter<W>>>,
     log_receiver: mpsc::Receiver<String>,
+    question_receiver: mpsc::Receiver<Question>,
+    /// A question that waits for the logs before it, and when it was received.
+    pending_question: Option<(Question, std::time::Instant)>,
     width: u16,
     height: u16,
     /// Number of lines in the drawn TUI area. 0 if not drawn yet.
@@ -383,6 +420,7 @@ struct TuiState<W: Write> {
 impl<W: Write> TuiState<W> {
     fn new(
         log_receiver: mpsc::Receiver<String>,
+        question_receiver: mpsc::Receiver<Question>,
         crashing: Arc<AtomicBool>,
         writer: W,
     ) -> Result<Self> {
@@ -403,6 +441,8 @@ impl<W: Write> TuiState<W> {
         .context("initializing ratatui Terminal")?;
         Ok(Self {
             log_receiver,
+            question_receiver,
+            pending_question: None,
             terminal,
             width,
             height,
@@ -508,6 +548,8 @@ impl<W: Write> TuiState<W> {
                 new_logs
             };
 
+            let had_logs = !new_logs.is_empty();
+
             // Handle log updates by reading from the log queue
             if !new_logs.is_empty() {
                 let tui_start = self.height - tui_height;
@@ -561,6 +603,26 @@ impl<W: Write> TuiState<W> {
                 // this iteration.
                 break;
             }
+
+            // Ask a question once the logs written before it have been printed
+            if self.pending_question.is_none() {
+                self.pending_question = self
+                    .question_receiver
+                    .try_recv()
+                    .ok()
+                    .map(|q| (q, std::time::Instant::now()));
+            }
+            if self
+                .pending_question
+                .as_ref()
+                .is_some_and(|(_, received)| !had_logs && received.elapsed() >= QUESTION_LOG_DELAY)
+            {
+                let (question, _) = self.pending_question.take().unwrap();
+                let answer = self.ask(&question.text, tui_height, &interrupt_state)?;
+                let _ = question.answer.send(answer);
+                continue;
+            }
+
             self.terminal
                 .backend_mut()
                 .execute(cursor::MoveTo(0, self.height - tui_height))?;
@@ -594,6 +656,56 @@ impl<W: Write> TuiState<W> {
         Ok(())
     }
 
+    /// Ask a yes/no question in place of the TUI, and wait for a key press.
+    fn ask(
+        &mut self,
+        question: &str,
+        tui_height: u16,
+        interrupt_state: &InterruptState,
+    ) -> Result<bool> {
+        let tui_start = self.height - self.rendered_height;
+        for i in 0..self.rendered_height {
+            self.terminal
+                .backend_mut()
+                .execute(cursor::MoveTo(0, tui_start + i))?;
+            self.terminal.backend_mut().execute(CLEAR_LINE)?;
+        }
+        self.terminal
+            .backend_mut()
+            .execute(cursor::MoveTo(0, tui_start))?;
+        self.terminal
+            .backend_mut()
+            .write_all(format!("{} [y/N] ", question).as_bytes())?;
+        self.terminal.backend_mut().flush()?;
+
+        let answer = loop {
+            if let event::Event::Key(key) = event::read()? {
+                match key.code {
+                    KeyCode::Char('y') | KeyCode::Char('Y') => break true,
+                    // Ctrl+C   (in raw mode, this is not a SIGINT)
+                    KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        interrupt_state.set_interrupted();
+                        break false;
+                    }
+                    KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Enter | KeyCode::Esc => {
+                        break false
+                    }
+                    _ => {}
+                }
+            }
+        };
+        let echo = if answer { "yes\r\n" } else { "no\r\n" };
+        self.terminal.backend_mut().write_all(echo.as_bytes())?;
+
+        // Make room for the TUI again
+        for _ in 1..tui_height {
+            self.terminal.backend_mut().write_all(b"\r\n")?;
+        }
+        self.terminal.backend_mut().flush()?;
+        self.terminal.clear()?;
+        Ok(answer)
+    }
+
     fn disable(&mut self) -> Result<()> {
         // We're done!
         // Clear the TUI area before exiting
@@ -628,9 +740,10 @@ fn spawn_log_ui<W: Write + Send + 'static>(
     crashing: Arc<AtomicBool>,
     writer: W,
     log_receiver: mpsc::Receiver<String>,
+    question_receiver: mpsc::Receiver<Question>,
     render_callback: Arc<Box<dyn Fn(&mut Frame) + Send + Sync>>,
 ) -> Result<thread::JoinHandle<Result<()>>, anyhow::Error> {
-    let mut tui_state = TuiState::new(log_receiver, crashing, writer)?;
+    let mut tui_state = TuiState::new(log_receiver, question_receiver, crashing, writer)?;
     Ok(thread::spawn(move || {
         tui_state.enable()?;
         tui_state.run(interrupt_state, render_callback)?;
@@ -16,6 +16,13 @@ pub(crate) trait Frontend {
     fn set_up(&mut self, options: &Options) -> Result<()>;
     fn tear_down(&mut self) -> Result<()>;
     fn get_panic_handler(&self) -> Box<dyn Fn(&std::panic::PanicHookInfo<'_>) + Send + Sync>;
+    /// Ask the user a yes/no question. The answer defaults to no.
+    fn confirm(&mut self, question: &str) -> Result<bool>;
+}
+
+/// Whether a typed answer to a yes/no question means yes.
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
 pub(crate) fn set_up(
@@ -32,3 +39,18 @@ pub(crate) fn set_up(
     std::panic::set_hook(logger.get_panic_handler());
     Ok(logger)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_yes() {
+        for answer in ["y", "Y", "yes", "YES", " yes\n", "y\n"] {
+            assert!(is_yes(answer), "{:?}", answer);
+        }
+        for answer in ["", "\n", "n", "no", "yep", "ja"] {
+            assert!(!is_yes(answer), "{:?}", answer);
+        }
+    }
+}
@@ -1,14 +1,20 @@
 mod apply;
+mod check;
+mod command_metadata;
+mod deployments;
 mod eval_client;
 mod interrupt;
 mod logging;
 mod provider;
+mod providers;
 
 use anyhow::Result;
 use clap::{ColorChoice, CommandFactory as _, Parser, Subcommand};
 use eval_client::EvalClient;
 use interrupt::{set_up_process_interrupt_handler, InterruptState};
-use nixops4_core::eval_api::{AssignRequest, EvalRequest, FlakeRequest, FlakeType, Id};
+use nixops4_core::eval_api::{
+    AssignRequest, DeploymentName, EvalRequest, FlakeRequest, FlakeType, Id,
+};
 use std::process::exit;
 
 fn main() {
@@ -21,23 +27,57 @@ fn run_args(interrupt_state: &InterruptState, args: Args) -> Result<()> {
     match &args.command {
         Commands::Apply(subargs) => {
             let mut logging = set_up_logging(interrupt_state, &args)?;
-            apply::apply(interrupt_state, &args.options, subargs)?;
+            apply::apply(interrupt_state, &args.options, subargs, logging.as_mut())?;
             logging.tear_down()?;
             Ok(())
         }
+        Commands::Check { deployment_args } => {
+            let mut logging = set_up_logging(interrupt_state, &args)?;
+            let r = check::check(&args.options, deployment_args);
+            logging.tear_down()?;
+            r
+        }
         Commands::Deployments(sub) => {
             match sub {
                 Deployments::List {} => {
                     let mut logging = set_up_logging(interrupt_state, &args)?;
                     let deployments = deployments_list(&args.options)?;
                     logging.tear_down()?;
-                    for d in deployments {
-                        println!("{}", d);
-                    }
+                    print_deployments(&deployments);
+                }
+                Deployments::Show {
+                    deployment,
+                    deployment_args,
+                    inputs,
+                } => {
+                    let mut logging = set_up_logging(interrupt_state, &args)?;
+                    let r = deployments::show(&args.options, deployment, deployment_args, *inputs);
+                    logging.tear_down()?;
+                    r?;
                 }
             };
             Ok(())
         }
+        Commands::Providers(sub) => match sub {
+            Providers::List {
+                deployment,
+                deployment_args,
+            } => {
+                let mut logging = set_up_logging(interrupt_state, &args)?;
+                let r = providers::list(&args.options, deployment, deployment_args);
+                logging.tear_down()?;
+                r
+            }
+        },
+        Commands::CommandList { json } => {
+            let metadata = command_metadata::command_metadata(&Args::command());
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&metadata)?);
+            } else {
+                print_commands(&metadata, "");
+            }
+            Ok(())
+        }
         Commands::GenerateMan => (|| {
             let cmd = Args::command();
             let man = clap_mangen::Man::new(cmd);
@@ -98,6 +138,8 @@ fn set_up_logging(
 fn to_eval_options(options: &Options) -> eval_client::Options {
     eval_client::Options {
         verbose: options.verbose,
+        show_trace: options.show_trace,
+        pure_eval: options.pure_eval,
     }
 }
 
@@ -121,7 +163,8 @@ fn with_flake<T>(
     })
 }
 
-fn deployments_list(options: &Options) -> Result<Vec<String>> {
+/// List the deployments in the flake, with their descriptions, if any.
+fn deployments_list(options: &Options) -> Result<Vec<(String, Option<String>)>> {
     with_flake(options, |c, flake_id| {
         let deployments_id = c.query(EvalRequest::ListDeployments, flake_id)?;
         let deployments = c.receive_until(|client, _resp| {
@@ -130,10 +173,65 @@ fn deployments_list(options: &Options) -> Result<Vec<String>> {
             let x = client.get_deployments(flake_id);
             Ok(x.cloned())
         })?;
-        Ok(deployments)
+        let mut option_ids = Vec::new();
+        for name in &deployments {
+            let deployment = DeploymentName {
+                flake: flake_id,
+                name: name.clone(),
+            };
+            let id = c.query(EvalRequest::GetDeploymentOptions, deployment.clone())?;
+            option_ids.push((deployment, id));
+        }
+        if option_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        // A deployment that fails to evaluate is still listed; `apply` will
+        // report the error when it's actually used.
+        c.receive_until(|client, _resp| {
+            let done = option_ids.iter().all(|(deployment, id)| {
+                client.get_error(*id).is_some()
+                    || client.get_deployment_options(deployment).is_some()
+            });
+            Ok(if done { Some(()) } else { None })
+        })?;
+        Ok(option_ids
+            .iter()
+            .map(|(deployment, _)| {
+                let description = c
+                    .get_deployment_options(deployment)
+                    .and_then(|o| o.description.clone());
+                (deployment.name.clone(), description)
+            })
+            .collect())
     })
 }
 
+fn print_deployments(deployments: &[(String, Option<String>)]) {
+    let width = deployments
+        .iter()
+        .filter(|(_, description)| description.is_some())
+        .map(|(name, _)| name.len())
+        .max();
+    for (name, description) in deployments {
+        match (width, description) {
+            (Some(width), Some(description)) => {
+                println!("{:width$}  {}", name, description, width = width)
+            }
+            _ => println!("{}", name),
+        }
+    }
+}
+
+fn print_commands(metadata: &command_metadata::CommandMetadata, prefix: &str) {
+    let path = format!("{}{}", prefix, metadata.name);
+    if metadata.subcommands.is_empty() {
+        println!("{}: {}", path, metadata.about.as_deref().unwrap_or(""));
+    }
+    for sub in &metadata.subcommands {
+        print_commands(sub, format!("{} ", path).as_str());
+    }
+}
+
 fn handle_result(r: Result<()>) {
     match r {
         Ok(()) => {}
@@ -160,6 +258,17 @@ struct Options {
     #[arg(short, long, global = true, default_value = "false")]
     verbose: bool,
 
+    /// Show the Nix trace of evaluation errors
+    #[arg(long, global = true, default_value_t = false)]
+    show_trace: bool,
+
+    /// Forbid impure evaluation, such as reading environment variables or files outside the flake
+    ///
+    /// This enables Nix's `pure-eval` and `restrict-eval` settings and disables import from derivation.
+    /// The flake itself is still locked as usual, so it may have uncommitted changes.
+    #[arg(long, global = true, default_value_t = false)]
+    pure_eval: bool,
+
     #[arg(long, global = true, default_value_t = ColorChoice::Auto)]
     color: ColorChoice,
 
@@ -179,6 +288,30 @@ struct Options {
 enum Deployments {
     /// List the deployments based on the expressions in the flake
     List {},
+    /// Show the resources of a deployment and their providers, without applying anything
+    Show {
+        /// The deployment, from the flake's `nixops4Deployments`
+        #[arg(value_name = "DEPLOYMENT", default_value = "default")]
+        deployment: String,
+        #[command(flatten)]
+        deployment_args: apply::DeploymentArgs,
+        /// Also show the inputs of each resource, as far as they are known before applying.
+        /// This evaluates the inputs, which may build derivations.
+        #[arg(long)]
+        inputs: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum Providers {
+    /// List the resource providers that the resources of a deployment use
+    List {
+        /// The deployment, from the flake's `nixops4Deployments`
+        #[arg(value_name = "DEPLOYMENT", default_value = "default")]
+        deployment: String,
+        #[command(flatten)]
+        deployment_args: apply::DeploymentArgs,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -187,10 +320,29 @@ enum Commands {
     #[command()]
     Apply(apply::Args),
 
-    /// Commands that operate on all deployments
+    /// Evaluate all deployments and report the ones that fail, without running any resource providers
+    #[command()]
+    Check {
+        #[command(flatten)]
+        deployment_args: apply::DeploymentArgs,
+    },
+
+    /// Commands that list and inspect deployments
     #[command(subcommand)]
     Deployments(Deployments),
 
+    /// Commands that inspect the resource providers of a deployment
+    #[command(subcommand)]
+    Providers(Providers),
+
+    /// List the commands and their arguments
+    #[command(name = "commands")]
+    CommandList {
+        /// Print the commands and their arguments as JSON, for use by other programs
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+
     /// Generate markdown documentation for nixops4-resource-runner
     #[command(hide = true)]
     GenerateMarkdown,
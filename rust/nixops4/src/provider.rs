@@ -1,13 +1,21 @@
 /// This module supplements the `nixops4-resource-runner` library with
 /// evaluation-layer logic.
+use crate::eval_client::REDACTED;
 use anyhow::{bail, Result};
 use serde_json::Value;
+use std::collections::BTreeMap;
 
 /// This type implements the parsing of `type: "stdio"` providers.
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub(crate) struct ProviderStdio {
     pub(crate) command: String,
     pub(crate) args: Vec<String>,
+    /// Environment variables to set for the provider process
+    #[serde(default)]
+    pub(crate) env: BTreeMap<String, String>,
+    /// Whether to start the provider without the environment of nixops4
+    #[serde(default, rename = "clearEnv")]
+    pub(crate) clear_env: bool,
 }
 
 pub(crate) fn parse_provider(provider_value: &Value) -> Result<ProviderStdio> {
@@ -27,3 +35,42 @@ pub(crate) fn parse_provider(provider_value: &Value) -> Result<ProviderStdio> {
         }
     }
 }
+
+/// A copy of a provider value with the values of its `env` replaced, for
+/// printing. Environment variables often carry credentials.
+pub(crate) fn redact_env(provider_value: &Value) -> Value {
+    let mut provider_value = provider_value.clone();
+    if let Some(env) = provider_value.get_mut("env").and_then(Value::as_object_mut) {
+        for value in env.values_mut() {
+            *value = Value::String(REDACTED.to_string());
+        }
+    }
+    provider_value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_env() {
+        let provider = json!({
+            "type": "stdio",
+            "command": "cloud-provider",
+            "args": ["--region", "eu-west-1"],
+            "env": { "AWS_SECRET_ACCESS_KEY": "hunter2" },
+        });
+        assert_eq!(
+            redact_env(&provider),
+            json!({
+                "type": "stdio",
+                "command": "cloud-provider",
+                "args": ["--region", "eu-west-1"],
+                "env": { "AWS_SECRET_ACCESS_KEY": REDACTED },
+            })
+        );
+        let plain = json!({ "type": "stdio", "command": "local", "args": [] });
+        assert_eq!(redact_env(&plain), plain);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    apply::{check_deployment_exists, DeploymentArgs},
+    eval_client::EvalClient,
+    provider, with_flake, Options,
+};
+use anyhow::Result;
+use nixops4_core::eval_api::{
+    AssignRequest, DeploymentArg, DeploymentRequest, DeploymentType, EvalRequest, EvalResponse,
+    FlakeType, Id, MessageType, QueryResponseValue, ResourceProviderInfo, ResourceRequest,
+    ResourceType,
+};
+
+/// Run the `providers list` command.
+pub(crate) fn list(
+    options: &Options,
+    deployment_name: &str,
+    deployment_args: &DeploymentArgs,
+) -> Result<()> {
+    let deployment_args = deployment_args.to_map()?;
+    let providers = with_flake(options, |c, flake_id| {
+        let deployments_id = c.query(EvalRequest::ListDeployments, flake_id)?;
+        let deployments = c.receive_until(|client, _resp| {
+            client.check_error(flake_id)?;
+            client.check_error(deployments_id)?;
+            Ok(client.get_deployments(flake_id).cloned())
+        })?;
+        check_deployment_exists(&deployments, deployment_name)?;
+        let infos = get_provider_infos(c, flake_id, deployment_name, deployment_args)?;
+        group_by_provider(infos)
+    })?;
+    for (argv, resources) in providers {
+        println!("{}", argv.join(" "));
+        println!("  resources: {}", resources.join(", "));
+    }
+    Ok(())
+}
+
+/// Evaluate the providers of all resources of a deployment, without applying anything.
+///
+/// Unlike [`crate::apply::preview`], this does not evaluate the resources' inputs.
+pub(crate) fn get_provider_infos(
+    c: &mut EvalClient,
+    flake_id: Id<FlakeType>,
+    deployment_name: &str,
+    deployment_args: BTreeMap<String, DeploymentArg>,
+) -> Result<BTreeMap<String, ResourceProviderInfo>> {
+    let deployment_id: Id<DeploymentType> = c.next_id();
+    c.send(&EvalRequest::LoadDeployment(AssignRequest {
+        assign_to: deployment_id,
+        payload: DeploymentRequest {
+            flake: flake_id,
+            name: deployment_name.to_string(),
+            args: deployment_args,
+        },
+    }))?;
+    let resources_list_id = c.query(EvalRequest::ListResources, deployment_id)?;
+    let resources = c.receive_until(|client, _resp| {
+        client.check_error(deployment_id)?;
+        client.check_error(resources_list_id)?;
+        Ok(client.get_resources(deployment_id).cloned())
+    })?;
+
+    // Send all requests before waiting, so that the evaluator doesn't wait for us
+    let mut resource_ids: BTreeMap<Id<ResourceType>, (String, Id<MessageType>)> = BTreeMap::new();
+    for name in resources.iter() {
+        let id = c.next_id();
+        c.send(&EvalRequest::LoadResource(AssignRequest {
+            assign_to: id,
+            payload: ResourceRequest {
+                deployment: deployment_id,
+                name: name.clone(),
+            },
+        }))?;
+        let query_id = c.query(EvalRequest::GetResource, id)?;
+        resource_ids.insert(id, (name.clone(), query_id));
+    }
+    let infos: Mutex<BTreeMap<String, ResourceProviderInfo>> = Mutex::new(BTreeMap::new());
+    if !resource_ids.is_empty() {
+        c.receive_until(|client, resp| {
+            for (id, (_, query_id)) in resource_ids.iter() {
+                client.check_error(*id)?;
+                client.check_error(*query_id)?;
+            }
+            let mut infos = infos.lock().unwrap();
+            if let EvalResponse::QueryResponse(_, QueryResponseValue::ResourceProviderInfo(info)) =
+                resp
+            {
+                if let Some((name, _)) = resource_ids.get(&info.id) {
+                    infos.insert(name.clone(), info.clone());
+                }
+            }
+            if infos.len() == resource_ids.len() {
+                Ok(Some(()))
+            } else {
+                Ok(None)
+            }
+        })?;
+    }
+    Ok(infos.into_inner().unwrap())
+}
+
+/// Map each distinct provider command line to the resources that use it.
+fn group_by_provider(
+    infos: BTreeMap<String, ResourceProviderInfo>,
+) -> Result<BTreeMap<Vec<String>, Vec<String>>> {
+    let mut providers: BTreeMap<Vec<String>, Vec<String>> = BTreeMap::new();
+    for (resource_name, info) in infos {
+        let provider = provider::parse_provider(&info.provider)?;
+        let mut argv = vec![provider.command];
+        argv.extend(provider.args);
+        providers.entry(argv).or_default().push(resource_name);
+    }
+    Ok(providers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nixops4_core::eval_api::Ids;
+    use serde_json::json;
+
+    #[test]
+    fn test_group_by_provider() {
+        let mut ids = Ids::new();
+        let mut info = |provider: serde_json::Value| ResourceProviderInfo {
+            id: ids.next(),
+            provider,
+            resource_type: "file".to_string(),
+            secret_properties: Default::default(),
+            timeout_seconds: None,
+        };
+        let local = json!({ "type": "stdio", "command": "local", "args": [] });
+        let other = json!({ "type": "stdio", "command": "other", "args": ["--flag"] });
+        let infos = BTreeMap::from([
+            ("a".to_string(), info(local.clone())),
+            ("b".to_string(), info(other)),
+            ("c".to_string(), info(local)),
+        ]);
+        let providers = group_by_provider(infos).unwrap();
+        assert_eq!(
+            providers,
+            BTreeMap::from([
+                (
+                    vec!["local".to_string()],
+                    vec!["a".to_string(), "c".to_string()]
+                ),
+                (
+                    vec!["other".to_string(), "--flag".to_string()],
+                    vec!["b".to_string()]
+                ),
+            ])
+        );
+    }
+}
@@ -10,6 +10,9 @@ runCommand "check-schemas"
       jv http://json-schema.org/draft-04/schema# ${../rust/nixops4-resource/resource-schema-v0.json}
       jv ${../rust/nixops4-resource/resource-schema-v0.json}#/definitions/CreateResourceRequest ${../rust/nixops4-resource/examples/v0/CreateResourceRequest.json}
       jv ${../rust/nixops4-resource/resource-schema-v0.json}#/definitions/CreateResourceResponse ${../rust/nixops4-resource/examples/v0/CreateResourceResponse.json}
+      jv ${../rust/nixops4-resource/resource-schema-v0.json}#/definitions/ValidateResourceRequest ${../rust/nixops4-resource/examples/v0/ValidateResourceRequest.json}
+      jv ${../rust/nixops4-resource/resource-schema-v0.json}#/definitions/ValidateResourceResponse ${../rust/nixops4-resource/examples/v0/ValidateResourceResponse.json}
+      jv ${../rust/nixops4-resource/resource-schema-v0.json}#/definitions/CapabilitiesResponse ${../rust/nixops4-resource/examples/v0/CapabilitiesResponse.json}
     )
     touch $out
   ''
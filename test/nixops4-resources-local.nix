@@ -3,6 +3,7 @@
 , nixops4-resource-runner
 , nixops4-resources-local
 , runCommand
+, writeText
 ,
 }:
 
@@ -33,6 +34,113 @@ runCommand
     echo -n hi > expected
     (set -x; diff expected test.txt)
 
+    nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type file \
+      --input-str name secret.txt \
+      --input-str contents hush \
+      --input-str mode 0600 \
+      > out.json
+    cat out.json
+
+    (set -x; [[ $(stat -c %a secret.txt) == 600 ]])
+    (set -x; [[ $(cat secret.txt) == hush ]])
+
+    nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type file \
+      --input-str name copied.txt \
+      --input-str sourceStorePath ${writeText "source.txt" "from the store"} \
+      > out.json
+    cat out.json
+
+    (set -x; jq -e '. == { }' out.json)
+    (set -x; [[ $(cat copied.txt) == "from the store" ]])
+
+    # Test "json_file" resource
+
+    nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type json_file \
+      --input-str name test.json \
+      --input-json value '{ "b": [ 1, 2.5, null ], "a": { "c": "hi" } }' \
+      --input-json pretty true \
+      > out.json
+    cat out.json test.json
+
+    (set -x; jq -e '. == { }' out.json)
+    (set -x; jq -e '. == { "a": { "c": "hi" }, "b": [ 1, 2.5, null ] }' test.json)
+
+    nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type json_file \
+      --input-str name compact.json \
+      --input-json value '{ "a": 1 }' \
+      > out.json
+
+    (set -x; [[ $(cat compact.json) == '{"a":1}' ]])
+
+    # Test "env_file" resource
+
+    nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type env_file \
+      --input-str name test.env \
+      --input-json variables '{ "GREETING": "hello world", "PORT": "8080" }' \
+      > out.json
+    cat out.json test.env
+
+    (set -x; jq -e '. == { }' out.json)
+    printf 'GREETING="hello world"\nPORT=8080\n' > expected.env
+    (set -x; diff expected.env test.env)
+
+    if nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type env_file \
+      --input-str name bad.env \
+      --input-json variables '{ "1abc": "x" }' \
+      > out.json 2> err.log; then
+      echo "env_file resource with an invalid key should have failed"
+      exit 1
+    fi
+    cat err.log
+
+    (set -x; grep -F 'Invalid environment variable name: "1abc"' err.log)
+    (set -x; [[ ! -e bad.env ]])
+
+    # Test "template" resource
+
+    cat > greeting.template <<'EOF'
+    ''${greeting}, ''${name}! (not $''${name})
+    EOF
+
+    nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type template \
+      --input-str name greeting.txt \
+      --input-str template "$(cat greeting.template)" \
+      --input-json variables '{ "greeting": "Hello", "name": "world" }' \
+      > out.json
+    cat out.json greeting.txt
+
+    (set -x; jq -e '. == { }' out.json)
+    (set -x; [[ $(cat greeting.txt) == 'Hello, world! (not ''${name})' ]])
+
+    if nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type template \
+      --input-str name bad.txt \
+      --input-str template "$(cat greeting.template)" \
+      --input-json variables '{ "greeting": "Hello" }' \
+      > out.json 2> err.log; then
+      echo "template resource with an undefined variable should have failed"
+      exit 1
+    fi
+    cat err.log
+
+    (set -x; grep -F 'Undefined variables in template: name' err.log)
+    (set -x; [[ ! -e bad.txt ]])
+
     # Test "exec" resource
 
     nixops4-resource-runner create \
@@ -45,5 +153,64 @@ runCommand
 
     (set -x; jq -e '. == { "stdout": "hi there\n" }' out.json)
 
+    # Test "assert" resource
+
+    nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type assert \
+      --input-json condition true \
+      --input-str message 'this should not fail' \
+      > out.json
+    cat out.json
+
+    (set -x; jq -e '. == { }' out.json)
+
+    if nixops4-resource-runner create \
+      --provider-exe nixops4-resources-local \
+      --type assert \
+      --input-json condition false \
+      --input-str message 'the key must be 32 bytes' \
+      > out.json 2> err.log; then
+      echo "assert resource with a false condition should have failed"
+      exit 1
+    fi
+    cat err.log
+
+    (set -x; grep -F 'Assertion failed: the key must be 32 bytes' err.log)
+
+    # Test capabilities
+
+    nixops4-resource-runner capabilities \
+      --provider-exe nixops4-resources-local \
+      > out.json
+    cat out.json
+
+    (set -x; jq -e '.resourceTypes == [ "file", "json_file", "env_file", "template", "exec", "assert" ]' out.json)
+    (set -x; jq -e '.operations | contains([ "create", "validate" ])' out.json)
+
+    # Test validation
+
+    nixops4-resource-runner validate \
+      --provider-exe nixops4-resources-local \
+      --type file \
+      --input-str name test.txt \
+      --input-str contents hi \
+      > out.json
+    cat out.json
+
+    (set -x; jq -e '. == { "problems": [] }' out.json)
+
+    if nixops4-resource-runner validate \
+      --provider-exe nixops4-resources-local \
+      --type file \
+      --input-str name test.txt \
+      > out.json; then
+      echo "validating a file resource without contents should have failed"
+      exit 1
+    fi
+    cat out.json
+
+    (set -x; jq -e '.problems[0].message | contains("Either `contents` or `sourceStorePath` must be set")' out.json)
+
     touch $out
   ''
@@ -5,7 +5,10 @@
         _file = "test/nixos/flake-module.nix#baseModule";
         nodes.deployer = { pkgs, ... }: {
           # System installation is not actually needed. Should we test without it?
-          environment.systemPackages = [ config.packages.nixops4 ];
+          environment.systemPackages = [
+            config.packages.nixops4
+            config.packages.nixops4-resources-local-release
+          ];
           nix.settings.experimental-features = "flakes";
         };
       };
@@ -3,10 +3,9 @@
   name = "nixops4";
 
   nodes.deployer = { pkgs, ... }: {
-    # Uncomment if needed
-    # environment.systemPackages = [
-    #   pkgs.git
-    # ];
+    environment.systemPackages = [
+      pkgs.git
+    ];
   };
 
   testScript = ''
@@ -34,6 +33,500 @@
       [[ $(wc -l <deployments) == 2 ]]
       rm deployments
       rm flake.nix
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }: {
+            nixops4Deployments = { };
+          };
+        }
+      ''} ./flake.nix
+      timeout 60 nixops4 deployments list > deployments
+      [[ $(wc -l <deployments) == 0 ]]
+      rm deployments
+      rm flake.nix
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "check" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir check
+      cd check
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }: {
+            nixops4Deployments = {
+              good = {
+                _type = "nixops4Deployment";
+                deploymentFunction = { resources, ... }: {
+                  resources.hello = {
+                    type = "file";
+                    provider = {
+                      executable = "/bin/false";
+                      args = [ ];
+                      types.file.outputs = { };
+                    };
+                    inputs = { };
+                  };
+                };
+              };
+              broken = {
+                _type = "nixops4Deployment";
+                deploymentFunction = args: throw "this deployment is broken";
+              };
+            };
+          };
+        }
+      ''} ./flake.nix
+      if nixops4 check 2>check.log; then
+        cat 1>&2 check.log
+        echo "nixops4 check should have failed" 1>&2
+        exit 1
+      fi
+      cat 1>&2 check.log
+      grep "deployment good: ok" check.log
+      grep "hello: file" check.log
+      grep "deployment broken: error" check.log
+      grep "this deployment is broken" check.log
+      grep "1 of 2 deployments failed to evaluate: broken" check.log
+      rm check.log
+      rm flake.nix
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }: {
+            nixops4Deployments = {
+              parameterized = { name }: {
+                _type = "nixops4Deployment";
+                deploymentFunction = { resources, ... }: {
+                  resources.''${name} = {
+                    type = "file";
+                    provider = {
+                      executable = "/bin/false";
+                      args = [ ];
+                      types.file.outputs = { };
+                    };
+                    inputs = { };
+                  };
+                };
+              };
+            };
+          };
+        }
+      ''} ./flake.nix
+      nixops4 check --argstr name greeting 2>check.log
+      cat 1>&2 check.log
+      grep "greeting: file" check.log
+      rm check.log
+      rm flake.nix
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "show-trace" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir show-trace
+      cd show-trace
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }: {
+            nixops4Deployments = {
+              nested = {
+                _type = "nixops4Deployment";
+                deploymentFunction = args:
+                  builtins.addErrorContext "nixops4 test frame 1" (
+                  builtins.addErrorContext "nixops4 test frame 2" (
+                  builtins.addErrorContext "nixops4 test frame 3" (
+                  builtins.addErrorContext "nixops4 test frame 4" (
+                  builtins.addErrorContext "nixops4 test frame 5" (
+                  builtins.addErrorContext "nixops4 test frame 6" (
+                    throw "nested failure"))))));
+              };
+            };
+          };
+        }
+      ''} ./flake.nix
+      if nixops4 check --show-trace 2>trace.log; then
+        cat 1>&2 trace.log
+        echo "nixops4 check should have failed" 1>&2
+        exit 1
+      fi
+      cat 1>&2 trace.log
+      grep "nested failure" trace.log
+      [[ $(grep -c "nixops4 test frame [1-6]" trace.log) == 6 ]]
+      if nixops4 check 2>notrace.log; then
+        cat 1>&2 notrace.log
+        echo "nixops4 check should have failed" 1>&2
+        exit 1
+      fi
+      cat 1>&2 notrace.log
+      grep "nested failure" notrace.log
+      [[ $(grep -c "nixops4 test frame [1-6]" notrace.log) -lt 6 ]]
+      rm trace.log notrace.log
+      rm flake.nix
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "pure-eval" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir pure-eval
+      cd pure-eval
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }: {
+            nixops4Deployments =
+              if builtins.getEnv "NIXOPS4_TEST_ENV" == "set"
+              then { from-env = throw "not implemented"; }
+              else { pure = throw "not implemented"; };
+          };
+        }
+      ''} ./flake.nix
+      NIXOPS4_TEST_ENV=set nixops4 deployments list > impure.txt
+      cat 1>&2 impure.txt
+      [[ $(cat impure.txt) == "from-env" ]]
+      NIXOPS4_TEST_ENV=set nixops4 deployments list --pure-eval > pure.txt
+      cat 1>&2 pure.txt
+      [[ $(cat pure.txt) == "pure" ]]
+      cd ..
+      rm -rf pure-eval
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "dirty" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir dirty
+      cd dirty
+      git init
+      git config user.name "Test"
+      git config user.email "test@example.com"
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }: {
+            nixops4Deployments.default = {
+              _type = "nixops4Deployment";
+              deploymentFunction = { resources, ... }: {
+                resources = { };
+              };
+            };
+          };
+        }
+      ''} ./flake.nix
+      git add flake.nix
+      git commit -m "Initial commit"
+      timeout 60 nixops4 apply --dry-run 2>clean.log
+      cat 1>&2 clean.log
+      if grep "dirty flake" clean.log; then
+        echo "a clean flake should not be reported as dirty" 1>&2
+        exit 1
+      fi
+      nixops4 apply --dry-run --require-clean
+      echo "# uncommitted change" >>flake.nix
+      nixops4 apply --dry-run 2>dirty.log
+      cat 1>&2 dirty.log
+      grep "warning: applying from a dirty flake (uncommitted changes); result may not be reproducible" dirty.log
+      if nixops4 apply --dry-run --require-clean 2>require-clean.log; then
+        cat 1>&2 require-clean.log
+        echo "nixops4 apply --require-clean should have failed" 1>&2
+        exit 1
+      fi
+      cat 1>&2 require-clean.log
+      grep "Refusing to apply from a dirty flake" require-clean.log
+      cd ..
+      rm -rf dirty
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "keep-going" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir keep-going
+      cd keep-going
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }:
+            let
+              provider = {
+                type = "stdio";
+                command = "nixops4-resources-local";
+                args = [ ];
+              };
+            in
+            {
+              nixops4Deployments.default = {
+                _type = "nixops4Deployment";
+                deploymentFunction = { resources, ... }: {
+                  resources = {
+                    broken = {
+                      type = "assert";
+                      inherit provider;
+                      inputs = {
+                        condition = false;
+                        message = "this resource is meant to fail";
+                      };
+                    };
+                    independent = {
+                      type = "file";
+                      inherit provider;
+                      inputs = {
+                        name = "independent.txt";
+                        contents = "created anyway";
+                      };
+                    };
+                  };
+                };
+              };
+            };
+        }
+      ''} ./flake.nix
+      if nixops4 apply --keep-going 2>apply.log; then
+        cat 1>&2 apply.log
+        echo "nixops4 apply should have failed" 1>&2
+        exit 1
+      fi
+      cat 1>&2 apply.log
+      grep "this resource is meant to fail" apply.log
+      grep "Summary: 1 succeeded, 1 failed, 0 skipped" apply.log
+      grep "failed: broken" apply.log
+      grep "1 of 2 resources failed to apply" apply.log
+      [[ $(cat independent.txt) == "created anyway" ]]
+      cd ..
+      rm -rf keep-going
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "select-resources" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir select-resources
+      cd select-resources
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }:
+            let
+              provider = {
+                type = "stdio";
+                command = "nixops4-resources-local";
+                args = [ ];
+              };
+              file = name: {
+                type = "file";
+                inherit provider;
+                inputs = {
+                  inherit name;
+                  contents = "created";
+                };
+              };
+            in
+            {
+              nixops4Deployments.default = {
+                _type = "nixops4Deployment";
+                deploymentFunction = { resources, ... }: {
+                  resources = {
+                    a = file "a.txt";
+                    b = file "b.txt";
+                    c = file "c.txt";
+                  };
+                };
+              };
+            };
+        }
+      ''} ./flake.nix
+      timeout 60 nixops4 apply --exclude a --exclude b --exclude c 2>exclude.log
+      cat 1>&2 exclude.log
+      grep "No resources are selected; nothing to apply." exclude.log
+      timeout 60 nixops4 apply --dry-run --exclude a --exclude b --exclude c
+      timeout 60 nixops4 apply --plan-json --exclude a --exclude b --exclude c >plan.json
+      cat 1>&2 plan.json
+      [[ ! -e a.txt && ! -e b.txt && ! -e c.txt ]]
+      nixops4 apply --resource b 2>apply.log
+      cat 1>&2 apply.log
+      [[ $(cat b.txt) == "created" ]]
+      [[ ! -e a.txt && ! -e c.txt ]]
+      cd ..
+      rm -rf select-resources
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "secret-properties" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir secret-properties
+      cd secret-properties
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }: {
+            nixops4Deployments.default = {
+              _type = "nixops4Deployment";
+              deploymentFunction = { resources, ... }: {
+                resources.credentials = {
+                  type = "file";
+                  provider = {
+                    type = "stdio";
+                    command = "nixops4-resources-local";
+                    args = [ ];
+                  };
+                  secretProperties = [ "contents" ];
+                  inputs = {
+                    name = "credentials.txt";
+                    contents = "hunter2";
+                  };
+                };
+              };
+            };
+          };
+        }
+      ''} ./flake.nix
+      nixops4 apply --dry-run 2>dry-run.log
+      nixops4 apply --plan-json >plan.json 2>plan.log
+      nixops4 apply --verbose >apply.log 2>&1
+      cat 1>&2 dry-run.log plan.json apply.log
+      [[ $(cat credentials.txt) == "hunter2" ]]
+      if grep hunter2 dry-run.log plan.json plan.log apply.log; then
+        echo "the secret input should have been redacted" 1>&2
+        exit 1
+      fi
+      grep "input contents: «secret»" apply.log
+      cd ..
+      rm -rf secret-properties
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "providers" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir providers
+      cd providers
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }:
+            let
+              local = {
+                type = "stdio";
+                command = "nixops4-resources-local";
+                args = [ ];
+              };
+              other = {
+                type = "stdio";
+                command = "other-provider";
+                args = [ "--some-flag" ];
+              };
+            in
+            {
+              nixops4Deployments.default = {
+                _type = "nixops4Deployment";
+                deploymentFunction = { resources, ... }: {
+                  resources = {
+                    a = { type = "file"; provider = local; inputs = { }; };
+                    b = { type = "file"; provider = local; inputs = { }; };
+                    c = { type = "thing"; provider = other; inputs = { }; };
+                  };
+                };
+              };
+            };
+        }
+      ''} ./flake.nix
+      nixops4 providers list > providers.txt
+      cat 1>&2 providers.txt
+      [[ $(grep -c "^nixops4-resources-local$" providers.txt) == 1 ]]
+      [[ $(grep -c "^other-provider --some-flag$" providers.txt) == 1 ]]
+      grep "resources: a, b" providers.txt
+      grep "resources: c" providers.txt
+      [[ $(wc -l <providers.txt) == 4 ]]
+      nixops4 deployments show > show.txt
+      cat 1>&2 show.txt
+      grep "^deployment default$" show.txt
+      grep -A2 "^resource a$" show.txt | grep "provider: nixops4-resources-local"
+      grep -A2 "^resource c$" show.txt | grep "type: thing"
+      grep -A2 "^resource c$" show.txt | grep "provider: other-provider --some-flag"
+      [[ $(grep -c "^resource " show.txt) == 3 ]]
+      cd ..
+      rm -rf providers
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "deployments-show" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir deployments-show
+      cd deployments-show
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }: {
+            nixops4Deployments = {
+              empty = {
+                _type = "nixops4Deployment";
+                deploymentFunction = { resources, ... }: {
+                  resources = { };
+                };
+              };
+              parameterized = { name }: {
+                _type = "nixops4Deployment";
+                deploymentFunction = { resources, ... }: {
+                  resources.''${name} = {
+                    type = "file";
+                    provider = {
+                      type = "stdio";
+                      command = "nixops4-resources-local";
+                      args = [ ];
+                    };
+                    inputs = { };
+                  };
+                };
+              };
+            };
+          };
+        }
+      ''} ./flake.nix
+      timeout 60 nixops4 deployments show empty > empty.txt
+      cat 1>&2 empty.txt
+      grep "(no resources)" empty.txt
+      nixops4 deployments show parameterized --argstr name greeting > parameterized.txt
+      cat 1>&2 parameterized.txt
+      grep "^resource greeting$" parameterized.txt
+      nixops4 providers list parameterized --argstr name greeting > providers.txt
+      cat 1>&2 providers.txt
+      grep "resources: greeting" providers.txt
+      cd ..
+      rm -rf deployments-show
+    ''}");
+
+    deployer.succeed("${config.node.pkgs.writeScript "resource-timeout" ''
+      #!${config.node.pkgs.runtimeShell}
+      set -euxo pipefail
+      mkdir resource-timeout
+      cd resource-timeout
+      cp ${builtins.toFile "flake.nix" ''
+        {
+          outputs = { ... }: {
+            nixops4Deployments.default = {
+              _type = "nixops4Deployment";
+              deploymentFunction = { resources, ... }: {
+                resources = {
+                  hanging = {
+                    type = "file";
+                    provider = {
+                      type = "stdio";
+                      command = "sh";
+                      args = [ "-c" "exec sleep 600" ];
+                    };
+                    inputs = { };
+                  };
+                };
+              };
+            };
+          };
+        }
+      ''} ./flake.nix
+      if nixops4 apply --resource-timeout 2 2>apply.log; then
+        cat 1>&2 apply.log
+        echo "nixops4 apply should have failed" 1>&2
+        exit 1
+      fi
+      cat 1>&2 apply.log
+      grep "resource hanging timed out after 2 s" apply.log
+      # A resource's timeoutSeconds overrides --resource-timeout
+      sed -i 's/inputs = { };/inputs = { }; timeoutSeconds = 1;/' flake.nix
+      if timeout 300 nixops4 apply --resource-timeout 600 2>apply.log; then
+        cat 1>&2 apply.log
+        echo "nixops4 apply should have failed" 1>&2
+        exit 1
+      fi
+      cat 1>&2 apply.log
+      grep "resource hanging timed out after 1 s" apply.log
+      cd ..
+      rm -rf resource-timeout
     ''}");
   '';
 }